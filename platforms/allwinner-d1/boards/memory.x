@@ -1,6 +1,7 @@
 MEMORY {
-    RAM   : ORIGIN = 0x40000000, LENGTH = 128M
-    AHEAP : ORIGIN = 0x48000000, LENGTH = 384M
+    RAM     : ORIGIN = 0x40000000, LENGTH = 128M - 64K
+    BOOTCFG : ORIGIN = 0x47FF0000, LENGTH = 64K
+    AHEAP   : ORIGIN = 0x48000000, LENGTH = 384M
 }
 
 SECTIONS
@@ -11,6 +12,13 @@ SECTIONS
         KEEP(*(.aheap .aheap.*));
         . = ALIGN(8);
     } > AHEAP
+
+    .bootcfg (NOLOAD) : ALIGN(8)
+    {
+        *(.bootcfg .bootcfg.*);
+        KEEP(*(.bootcfg .bootcfg.*));
+        . = ALIGN(8);
+    } > BOOTCFG
 }
 
 REGION_ALIAS("REGION_TEXT", RAM)
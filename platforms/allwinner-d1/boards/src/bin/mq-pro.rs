@@ -4,6 +4,7 @@
 extern crate alloc;
 
 use core::time::Duration;
+use kernel::tasks::Priority;
 use mnemos_d1_core::{
     dmac::Dmac,
     drivers::{spim::kernel_spim1, uart::kernel_uart},
@@ -32,7 +33,9 @@ fn main() -> ! {
     let dmac = Dmac::new(p.DMAC, &mut p.CCU);
     let plic = Plic::new(p.PLIC);
 
-    let d1 = D1::initialize(timers, uart, spim, dmac, plic).unwrap();
+    let config = mnemos_d1::boot_config();
+    let d1 = D1::initialize(timers, uart, spim, dmac, plic, config).unwrap();
+    d1.kernel.set_heap_stats(mnemos_d1::heap_stats).unwrap();
 
     p.GPIO.pd_cfg2.modify(|_r, w| {
         w.pd18_select().output();
@@ -47,7 +50,7 @@ fn main() -> ! {
 
     // Initialize LED loop
     d1.kernel
-        .initialize(async move {
+        .initialize(Priority::User, async move {
             loop {
                 p.GPIO.pd_dat.modify(|_r, w| {
                     w.pd_dat().variant(1 << 18);
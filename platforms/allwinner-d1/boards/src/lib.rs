@@ -3,11 +3,26 @@
 extern crate alloc;
 
 use core::{panic::PanicInfo, ptr::NonNull};
-use kernel::mnemos_alloc::heap::{MnemosAlloc, SingleThreadedLinkedListAllocator};
+use kernel::{
+    config::BootConfig,
+    mnemos_alloc::heap::{
+        HeapStats, MnemosAlloc, SingleThreadedLinkedListAllocator, SlabAllocator,
+    },
+};
 use mnemos_d1_core::{Ram, D1};
 
+/// Small, fixed-size kernel objects are served from slabs, everything else
+/// from the linked list heap.
 #[global_allocator]
-static AHEAP: MnemosAlloc<SingleThreadedLinkedListAllocator> = MnemosAlloc::new();
+static AHEAP: MnemosAlloc<SlabAllocator<SingleThreadedLinkedListAllocator>> = MnemosAlloc::new();
+
+const BOOTCFG_SIZE: usize = 64 * 1024;
+
+/// Reserved for a boot config, which may be written to RAM (at `0x47FF0000`)
+/// before the kernel is started. See `memory.x`.
+#[link_section = ".bootcfg.BOOTCFG"]
+#[used]
+static BOOTCFG_BUF: Ram<BOOTCFG_SIZE> = Ram::new();
 
 /// Initialize the heap.
 ///
@@ -18,7 +33,29 @@ pub unsafe fn initialize_heap<const HEAP_SIZE: usize>(buf: &'static Ram<HEAP_SIZ
     AHEAP.init(NonNull::new(buf.as_ptr()).unwrap(), HEAP_SIZE);
 }
 
+/// Returns the global allocator's usage statistics, including the largest
+/// free block.
+///
+/// Finding the largest free block uses the heap, so the panic handler only
+/// reports [`MnemosAlloc::stats()`].
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        largest_free_block: AHEAP.largest_free_block(),
+        ..AHEAP.stats()
+    }
+}
+
+/// Load the boot config, if one was written to the boot config region, or
+/// the default config otherwise.
+pub fn boot_config() -> BootConfig {
+    // Safety: the region is never written to by the kernel, and it is not
+    // initialized by the loader, so whatever bytes were written there before
+    // boot (if any) are still there.
+    let region = unsafe { core::slice::from_raw_parts(BOOTCFG_BUF.as_ptr(), BOOTCFG_SIZE) };
+    D1::boot_config(region)
+}
+
 #[panic_handler]
 fn handler(info: &PanicInfo) -> ! {
-    D1::handle_panic(info)
+    D1::handle_panic(info, Some(AHEAP.stats()))
 }
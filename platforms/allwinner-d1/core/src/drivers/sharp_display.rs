@@ -38,6 +38,7 @@ use kernel::{
     mnemos_alloc::containers::{Arc, FixedVec},
     registry::Message,
     services::emb_display::{EmbDisplayService, FrameChunk, FrameError, Request, Response},
+    tasks::Priority,
     Kernel,
 };
 
@@ -116,9 +117,9 @@ impl SharpDisplay {
             ctxt,
         };
 
-        kernel.spawn(commander.cmd_run()).await;
-        kernel.spawn(vcom.vcom_run()).await;
-        kernel.spawn(draw.draw_run()).await;
+        kernel.spawn(Priority::System, commander.cmd_run()).await;
+        kernel.spawn(Priority::System, vcom.vcom_run()).await;
+        kernel.spawn(Priority::System, draw.draw_run()).await;
 
         kernel
             .with_registry(|reg| reg.register_konly::<EmbDisplayService>(&cmd_prod))
@@ -246,6 +247,9 @@ struct Draw {
 impl Draw {
     #[tracing::instrument(skip(self))]
     async fn draw_run(mut self) {
+        // Draw any pending changes before the kernel shuts down.
+        let _guard = self.kernel.shutdown_guard();
+        let mut shutting_down = false;
         loop {
             let c = self.ctxt.lock().await;
             // render into the buffer
@@ -279,11 +283,17 @@ impl Draw {
 
             self.buf = self.spim.send_wait(self.buf).await.map_err(drop).unwrap();
 
+            if shutting_down {
+                tracing::debug!("Final frame drawn");
+                return;
+            }
+
             // Wait a reasonable amount of time to redraw
             let _ = self
                 .kernel
                 .timeout(Duration::from_millis(500), DIRTY.wait())
                 .await;
+            shutting_down = self.kernel.shutdown_requested().is_some();
         }
     }
 }
@@ -305,8 +315,9 @@ impl CommanderTask {
         //
         // Generally, don't handle errors when replying to clients, this indicates that they
         // sent us a message and "hung up" without waiting for a response.
-        loop {
-            let msg = self.cmd.dequeue_async().await.map_err(drop).unwrap();
+        //
+        // The queue is closed once the service is deregistered.
+        while let Ok(msg) = self.cmd.dequeue_async().await {
             let Message {
                 msg: mut req,
                 reply,
@@ -14,6 +14,7 @@ use kernel::{
     maitake::sync::WaitCell,
     mnemos_alloc::containers::FixedVec,
     registry::{uuid, Envelope, KernelHandle, Message, RegisteredDriver, ReplyTo, Uuid},
+    tasks::Priority,
     Kernel,
 };
 
@@ -101,7 +102,7 @@ impl SpiSenderServer {
         let (kprod, kcons) = KChannel::new_async(queued).await.split();
 
         kernel
-            .spawn(async move {
+            .spawn(Priority::System, async move {
                 let kcons = kcons;
                 let spi = unsafe { &*SPI_DBI::PTR };
 
@@ -109,10 +110,10 @@ impl SpiSenderServer {
                 let txd_ptr: *mut u8 = txd_ptr.cast();
                 let txd_ptr: *mut () = txd_ptr.cast();
 
-                loop {
-                    let msg: Message<SpiSender> = kcons.dequeue_async().await.unwrap();
+                // The queue is closed once the service is deregistered.
+                while let Ok(msg) = kcons.dequeue_async().await {
                     // println!("DEQUEUE");
-                    let Message { msg, reply } = msg;
+                    let Message::<SpiSender> { msg, reply } = msg;
                     let SpiSenderRequest::Send(ref payload) = msg.body;
 
                     let len = payload.as_slice().len();
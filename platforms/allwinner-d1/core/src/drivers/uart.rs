@@ -20,6 +20,7 @@ use kernel::{
     mnemos_alloc::containers::Box,
     registry::Message,
     services::simple_serial::{Request, Response, SimpleSerialError, SimpleSerialService},
+    tasks::Priority,
     Kernel,
 };
 
@@ -143,24 +144,21 @@ impl D1Uart {
     }
 
     async fn serial_server(handle: BidiHandle, kcons: KConsumer<Message<SimpleSerialService>>) {
-        loop {
-            if let Ok(req) = kcons.dequeue_async().await {
-                let Request::GetPort = req.msg.body;
-                let resp = req.msg.reply_with(Ok(Response::PortHandle { handle }));
-                let _ = req.reply.reply_konly(resp).await;
-                break;
-            }
-        }
+        // The queue is closed once the service is deregistered.
+        let Ok(req) = kcons.dequeue_async().await else {
+            return;
+        };
+        let Request::GetPort = req.msg.body;
+        let resp = req.msg.reply_with(Ok(Response::PortHandle { handle }));
+        let _ = req.reply.reply_konly(resp).await;
 
         // And deny all further requests after the first
-        loop {
-            if let Ok(req) = kcons.dequeue_async().await {
-                let Request::GetPort = req.msg.body;
-                let resp = req
-                    .msg
-                    .reply_with(Err(SimpleSerialError::AlreadyAssignedPort));
-                let _ = req.reply.reply_konly(resp).await;
-            }
+        while let Ok(req) = kcons.dequeue_async().await {
+            let Request::GetPort = req.msg.body;
+            let resp = req
+                .msg
+                .reply_with(Err(SimpleSerialError::AlreadyAssignedPort));
+            let _ = req.reply.reply_konly(resp).await;
         }
     }
 
@@ -177,10 +175,14 @@ impl D1Uart {
             .split();
         let (fifo_a, fifo_b) = new_bidi_channel(cap_in, cap_out).await;
 
-        let _server_hdl = k.spawn(D1Uart::serial_server(fifo_b, kcons)).await;
+        let _server_hdl = k
+            .spawn(Priority::System, D1Uart::serial_server(fifo_b, kcons))
+            .await;
 
         let (prod, cons) = fifo_a.split();
-        let _send_hdl = k.spawn(D1Uart::sending(cons, tx_channel)).await;
+        let _send_hdl = k
+            .spawn(Priority::System, D1Uart::sending(cons, tx_channel))
+            .await;
 
         let boxed_prod = Box::new(prod).await;
         let leaked_prod = Box::into_raw(boxed_prod);
@@ -16,11 +16,13 @@ use core::{
 };
 use d1_pac::{Interrupt, DMAC, TIMER};
 use kernel::{
-    daemons::sermux::{hello, loopback, HelloSettings, LoopbackSettings},
-    mnemos_alloc::containers::Box,
-    services::{forth_spawnulator::SpawnulatorServer, serial_mux::SerialMuxServer},
-    trace::{self, Instrument},
-    Kernel, KernelSettings,
+    config::BootConfig,
+    daemons::shells::GraphicalShellSettings,
+    mnemos_alloc::{containers::Box, heap::HeapStats},
+    services::clock::ClockServer,
+    shutdown::ShutdownReason,
+    tasks::Priority,
+    trace, Kernel,
 };
 
 pub use self::ram::Ram;
@@ -48,8 +50,8 @@ impl D1 {
     /// Initialize MnemOS for the D1.
     ///
     /// This function configures the hardware platform and spawns driver
-    /// services for SPI and UART, as well as the Serial Mux and Tracing
-    /// services.
+    /// services for SPI and UART, and the Clock and Tracing services,
+    /// followed by the services and daemons in the boot `config`.
     ///
     /// **Note**: Initialize the global allocator prior to calling this
     /// function.
@@ -59,13 +61,14 @@ impl D1 {
         spim: spim::Spim1,
         dmac: Dmac,
         plic: Plic,
+        config: BootConfig,
     ) -> Result<Self, ()> {
-        let k_settings = KernelSettings {
-            max_drivers: 16,
+        let k_settings = config.kernel_settings(
             // Note: The timers used will be configured to 3MHz, leading to (approximately)
             // 333ns granularity.
-            timer_granularity: Duration::from_nanos(333),
-        };
+            Duration::from_nanos(333),
+            Some(timer::timer0_ticks),
+        );
         let k = unsafe {
             Box::into_raw(Kernel::new(k_settings).map_err(drop)?)
                 .as_ref()
@@ -82,55 +85,33 @@ impl D1 {
         });
 
         // Initialize SPI stuff
-        k.initialize(async move {
+        k.initialize(Priority::System, async move {
             // Register a new SpiSenderServer
             SpiSenderServer::register(k, 4).await.unwrap();
         })
         .unwrap();
 
-        // Initialize SimpleSerial driver
-        k.initialize(async move {
-            D1Uart::register(k, 4096, 4096, ch0).await.unwrap();
+        // Initialize the clock. The D1's RTC isn't supported yet, so the wall
+        // clock is unknown until it is set.
+        k.initialize(Priority::System, async move {
+            ClockServer::register(k, 4, None).await.unwrap();
         })
         .unwrap();
 
-        // Initialize the SerialMuxServer
-        k.initialize({
-            const PORTS: usize = 16;
-            const FRAME_SIZE: usize = 512;
-            async {
-                // * Up to 16 virtual ports max
-                // * Framed messages up to 512 bytes max each
-                tracing::debug!("initializing SerialMuxServer...");
-                SerialMuxServer::register(k, PORTS, FRAME_SIZE)
-                    .await
-                    .unwrap();
-                tracing::info!("SerialMuxServer initialized!");
-            }
-            .instrument(tracing::info_span!(
-                "SerialMuxServer",
-                ports = PORTS,
-                frame_size = FRAME_SIZE
-            ))
+        // Initialize SimpleSerial driver
+        k.initialize(Priority::System, async move {
+            D1Uart::register(k, 4096, 4096, ch0).await.unwrap();
         })
         .unwrap();
 
         // initialize tracing
-        k.initialize(async move {
+        k.initialize(Priority::System, async move {
             COLLECTOR.start(k).await;
         })
         .unwrap();
 
-        // Spawn a loopback port
-        let loopback_settings = LoopbackSettings::default();
-        k.initialize(loopback(k, loopback_settings)).unwrap();
-
-        // Spawn a hello port
-        let hello_settings = HelloSettings::default();
-        k.initialize(hello(k, hello_settings)).unwrap();
-
-        // Spawn the spawnulator
-        k.initialize(SpawnulatorServer::register(k, 16)).unwrap();
+        // Spawn the services and daemons from the boot config
+        config.initialize(k).map_err(drop)?;
 
         Ok(Self {
             kernel: k,
@@ -141,50 +122,63 @@ impl D1 {
         })
     }
 
-    /// Spawns a SHARP Memory Display driver and a graphical Forth REPL on the Sharp
-    /// Memory Display.
+    /// Loads the boot config from `region`, falling back to
+    /// [`D1::default_boot_config()`] if `region` does not hold a valid config.
+    ///
+    /// This runs before the kernel (and tracing) are started, so a config which
+    /// fails to parse is silently ignored.
+    pub fn boot_config(region: &[u8]) -> BootConfig {
+        BootConfig::from_bytes(region).unwrap_or_else(|_| Self::default_boot_config())
+    }
+
+    /// The boot config used when none is loaded.
+    ///
+    /// This is the kernel's default config, with a graphical Forth REPL on the
+    /// SHARP Memory Display (see [`D1::initialize_sharp_display()`]).
+    pub fn default_boot_config() -> BootConfig {
+        use drivers::sharp_display::SharpDisplay;
+
+        let mut config = BootConfig::default();
+        config.poll_budget = Some(Duration::from_millis(10));
+        config.graphical_shell = Some(GraphicalShellSettings::with_display_size(
+            SharpDisplay::WIDTH as u32,
+            SharpDisplay::HEIGHT as u32,
+        ));
+        config
+    }
+
+    /// Spawns a SHARP Memory Display driver.
     ///
     /// This function requires a SHARP memory display to be connected to the D1's
-    /// SPI_DBI pins (SPI1).
+    /// SPI_DBI pins (SPI1). The graphical Forth REPL on the display is started
+    /// by the boot config, see [`D1::default_boot_config()`].
     ///
     /// # Panics
     ///
-    /// If the SHARP Memory Display driver or the graphical Forth REPL tasks
-    /// could not be spawned.
+    /// If the SHARP Memory Display driver task could not be spawned.
     pub fn initialize_sharp_display(&self) {
         use drivers::sharp_display::SharpDisplay;
-        use kernel::daemons::shells;
 
         const MAX_FRAMES: usize = 4;
 
         // the `'static` kernel reference is the only thing from `self` that
-        // must be moved into the spawned tasks.
+        // must be moved into the spawned task.
         let k = self.kernel;
 
-        let sharp_display = self
-            .kernel
-            .initialize(SharpDisplay::register(k, MAX_FRAMES))
-            .expect("failed to spawn SHARP display driver");
-
-        // spawn Forth shell
         self.kernel
-            .initialize(async move {
-                trace::debug!("waiting for SHARP display driver...");
-                sharp_display
+            .initialize(Priority::System, async move {
+                SharpDisplay::register(k, MAX_FRAMES)
                     .await
-                    .expect("display driver task isn't cancelled")
                     .expect("display driver must come up");
                 trace::debug!("display driver ready!");
-                let settings = shells::GraphicalShellSettings::with_display_size(
-                    SharpDisplay::WIDTH as u32,
-                    SharpDisplay::HEIGHT as u32,
-                );
-                k.spawn(shells::graphical_shell_mono(k, settings)).await;
-                trace::info!("graphical shell running.");
             })
-            .expect("failed to spawn graphical forth shell");
+            .expect("failed to spawn SHARP display driver");
     }
 
+    /// Run the kernel, until it is [shut down][kernel::shutdown].
+    ///
+    /// Once the shutdown completes, the D1 is halted, or reset if the reason
+    /// is [`ShutdownReason::Reboot`].
     pub fn run(self) -> ! {
         let Self {
             kernel: k,
@@ -232,47 +226,78 @@ impl D1 {
 
         timer0.start_counter(0xFFFF_FFFF);
 
+        // Returns the number of timer ticks since the last call.
+        let mut last = timer0.current_value();
+        let mut elapsed = || {
+            let now = timer0.current_value();
+            // Timer is downcounting
+            let elapsed = last.wrapping_sub(now);
+            last = now;
+            u64::from(elapsed)
+        };
+
         loop {
-            // Tick the scheduler
-            let start = timer0.current_value();
-            let tick = k.tick();
+            // Tick the scheduler and advance the timer, until there is nothing
+            // else scheduled, and we didn't just wake something up.
+            let idle = k.run_until_idle(&mut elapsed);
 
-            // Timer is downcounting
-            let elapsed = start.wrapping_sub(timer0.current_value());
-            let turn = k.timer().force_advance_ticks(elapsed.into());
-
-            // If there is nothing else scheduled, and we didn't just wake something up,
-            // sleep for some amount of time
-            if turn.expired == 0 && !tick.has_remaining {
-                let wfi_start = timer0.current_value();
-
-                // TODO(AJM): Sometimes there is no "next" in the timer wheel, even though there should
-                // be. Don't take lack of timer wheel presence as the ONLY heuristic of whether we
-                // should just wait for SOME interrupt to occur. For now, force a max sleep of 100ms
-                // which is still probably wrong.
-                let amount = turn.ticks_to_next_deadline().unwrap_or(100 * 1000 * 3); // 3 ticks per us, 1000 us per ms, 100ms sleep
-
-                // Don't sleep for too long until james figures out wrapping timers
-                let amount = amount.min(0x4000_0000) as u32;
-                let _ = timer1.get_and_clear_interrupt();
-                unsafe {
-                    plic.activate(Interrupt::TIMER1, Priority::P1).unwrap();
-                }
-                timer1.set_interrupt_en(true);
-                timer1.start_counter(amount);
+            if let Some(reason) = k.shutdown_complete() {
+                Self::shutdown(reason);
+            }
 
-                unsafe {
-                    riscv::asm::wfi();
-                }
-                // Disable the timer interrupt in case that wasn't what woke us up
-                plic.deactivate(Interrupt::TIMER1).unwrap();
-                timer1.set_interrupt_en(false);
-                timer1.stop();
-
-                // Account for time slept
-                let elapsed = wfi_start.wrapping_sub(timer0.current_value());
-                let _turn = k.timer().force_advance_ticks(elapsed.into());
+            // Sleep until the next deadline, or an interrupt.
+            //
+            // TODO(AJM): Sometimes there is no "next" in the timer wheel, even though there should
+            // be. Don't take lack of timer wheel presence as the ONLY heuristic of whether we
+            // should just wait for SOME interrupt to occur. For now, force a max sleep of 100ms
+            // which is still probably wrong.
+            let amount = idle.next_deadline.unwrap_or(100 * 1000 * 3); // 3 ticks per us, 1000 us per ms, 100ms sleep
+
+            // Don't sleep for too long until james figures out wrapping timers
+            let amount = amount.min(0x4000_0000) as u32;
+            let _ = timer1.get_and_clear_interrupt();
+            unsafe {
+                plic.activate(Interrupt::TIMER1, Priority::P1).unwrap();
             }
+            timer1.set_interrupt_en(true);
+            timer1.start_counter(amount);
+
+            unsafe {
+                riscv::asm::wfi();
+            }
+            // Disable the timer interrupt in case that wasn't what woke us up
+            plic.deactivate(Interrupt::TIMER1).unwrap();
+            timer1.set_interrupt_en(false);
+            timer1.stop();
+
+            // Account for time slept
+            k.advance_timer(elapsed());
+        }
+    }
+
+    /// Halt or reset the D1, once the kernel has shut down.
+    fn shutdown(reason: ShutdownReason) -> ! {
+        // Offset of the watchdog soft reset register, and the key
+        // which must be written to it along with the reset bit.
+        const WDOG_SOFT_RST: usize = 0xA8;
+        const WDOG_SOFT_RST_KEY: u32 = 0x16AA << 16;
+
+        tracing::info!(?reason, "Kernel shut down");
+        unsafe {
+            riscv::interrupt::disable();
+        }
+        if reason == ShutdownReason::Reboot {
+            unsafe {
+                let reg = TIMER::PTR
+                    .cast::<u8>()
+                    .add(WDOG_SOFT_RST)
+                    .cast::<u32>()
+                    .cast_mut();
+                reg.write_volatile(WDOG_SOFT_RST_KEY | 1);
+            }
+        }
+        loop {
+            unsafe { riscv::asm::wfi() };
         }
     }
 
@@ -311,7 +336,11 @@ impl D1 {
         while timer.tmr_irq_sta.read().tmr1_irq_pend().bit_is_set() {}
     }
 
-    pub fn handle_panic(info: &PanicInfo) -> ! {
+    /// Report a panic on the UART, and halt.
+    ///
+    /// `heap` is the global allocator's usage, which is included in the
+    /// structured panic report sent after the plaintext message.
+    pub fn handle_panic(info: &PanicInfo, heap: Option<HeapStats>) -> ! {
         // Disable interrupts.
         unsafe {
             riscv::interrupt::disable();
@@ -345,6 +374,17 @@ impl D1 {
         // end the SerMux frame so crowtty can decode the panic message as utf8
         uart.write(&[0]);
 
+        // then send a structured report, which includes the recent trace
+        // events, as a SerMux frame of its own. if it doesn't fit in half of
+        // the buffer, the oldest events are left out.
+        static mut REPORT_BUF: [u8; 4096] = [0; 4096];
+        // SAFETY: we checked that we're not already panicking above, so
+        // nothing else can be using the buffer.
+        let buf = unsafe { &mut *core::ptr::addr_of_mut!(REPORT_BUF) };
+        if let Some(frame) = COLLECTOR.panic_report(info, heap, buf) {
+            uart.write(frame);
+        }
+
         write!(
             &mut uart,
             "you've met with a terrible fate, haven't you?\r\n"
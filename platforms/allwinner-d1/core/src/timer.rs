@@ -189,6 +189,16 @@ pub trait Timer: sealed::TimerSealed {
     }
 }
 
+/// Returns the number of ticks counted by [`Timer0`], wrapping.
+///
+/// `Timer0` is a free running downcounter, so this is just its current value,
+/// inverted. Used as the kernel's busy clock, which doesn't have access to the
+/// `Timer0` handle.
+pub fn timer0_ticks() -> u32 {
+    let timer = unsafe { &*TIMER::PTR };
+    !timer.tmr0_cur_value.read().bits()
+}
+
 impl Timers {
     pub fn new(periph: TIMER) -> Self {
         // 1. Configure the timer parameters clock source, prescale factor, and timing mode by writing **TMRn_CTRL_REG**. There is no sequence requirement of configuring the parameters.
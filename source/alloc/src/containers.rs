@@ -1,12 +1,22 @@
 //! Async-aware Container Types
 //!
 //! These types play well with [MnemosAlloc][crate::heap::MnemosAlloc]
+//!
+//! Each async constructor also has an `_or_cancel` variant, which gives up
+//! and returns an error if a cancellation future completes before the
+//! allocation succeeds, see [alloc_or_cancel()][crate::heap::alloc_or_cancel].
 
-use crate::heap::alloc;
+use crate::{
+    heap::{alloc, alloc_or_cancel, or_cancel, retry, Cancelled},
+    quota,
+};
+use alloc::collections::TryReserveError;
 use core::{
     alloc::Layout,
+    borrow::Borrow,
     cell::UnsafeCell,
-    mem::MaybeUninit,
+    future::Future,
+    mem::{size_of, MaybeUninit},
     ops::{Deref, DerefMut},
     ptr::NonNull,
 };
@@ -30,24 +40,43 @@ impl<T> Arc<T> {
     ///
     /// Returns an error containing the provided value if the allocation
     /// could not immediately succeed.
-    ///
-    /// NOTE/TODO: Today this will panic if not immediately successful. This should
-    /// be fixed in the future
     pub fn try_new(t: T) -> Result<Self, T> {
-        Ok(Self {
-            inner: alloc::sync::Arc::new(t),
-        })
+        let _enforce = quota::enforce();
+        match alloc::sync::Arc::try_new_uninit() {
+            Ok(uninit) => Ok(Self::init(uninit, t)),
+            Err(_) => Err(t),
+        }
     }
 
     /// Attempt to allocate a new reference counted T.
     ///
     /// Will not complete until the allocation succeeds
-    ///
-    /// NOTE/TODO: Today this will panic if not immediately successful. This should
-    /// be fixed in the future
     pub async fn new(t: T) -> Self {
+        Self::init(Self::alloc_uninit().await, t)
+    }
+
+    /// Attempt to allocate a new reference counted T.
+    ///
+    /// Returns an error containing the provided value if `cancel` completes
+    /// before the allocation succeeds.
+    pub async fn new_or_cancel<C: Future>(t: T, cancel: C) -> Result<Self, T> {
+        match or_cancel(Self::alloc_uninit(), cancel).await {
+            Ok(uninit) => Ok(Self::init(uninit, t)),
+            Err(Cancelled) => Err(t),
+        }
+    }
+
+    async fn alloc_uninit() -> alloc::sync::Arc<MaybeUninit<T>> {
+        // The reference counts are allocated along with the T.
+        let size = size_of::<T>() + 2 * size_of::<usize>();
+        retry(size, || alloc::sync::Arc::try_new_uninit().ok()).await
+    }
+
+    fn init(mut uninit: alloc::sync::Arc<MaybeUninit<T>>, t: T) -> Self {
+        // We just allocated it, so this is the only reference.
+        alloc::sync::Arc::get_mut(&mut uninit).unwrap().write(t);
         Self {
-            inner: alloc::sync::Arc::new(t),
+            inner: unsafe { uninit.assume_init() },
         }
     }
 
@@ -119,11 +148,27 @@ impl<T> Box<T> {
         }
     }
 
+    /// Attempt to allocate a new owned T.
+    ///
+    /// Returns an error containing the provided value if `cancel` completes
+    /// before the allocation succeeds.
+    pub async fn new_or_cancel<C: Future>(t: T, cancel: C) -> Result<Self, T> {
+        match alloc_or_cancel(Layout::new::<T>(), cancel).await {
+            Ok(ptr) => unsafe {
+                let ptr = ptr.cast::<T>().as_ptr();
+                ptr.write(t);
+                Ok(Self::from_raw(ptr))
+            },
+            Err(Cancelled) => Err(t),
+        }
+    }
+
     /// Attempt to allocate a new owned T.
     ///
     /// Returns an error containing the provided value if the allocation
     /// could not immediately succeed.
     pub fn try_new(t: T) -> Result<Self, T> {
+        let _enforce = quota::enforce();
         match NonNull::new(unsafe { alloc::alloc::alloc(Layout::new::<T>()) }) {
             Some(ptr) => unsafe {
                 let ptr = ptr.cast::<T>().as_ptr();
@@ -207,6 +252,7 @@ impl<T> ArrayBuf<T> {
     pub fn try_new_uninit(len: usize) -> Option<Self> {
         assert_ne!(len, 0, "ZST ArrayBuf doesn't make sense");
         let layout = Self::layout(len);
+        let _enforce = quota::enforce();
         let ptr = NonNull::new(unsafe { alloc::alloc::alloc(layout) })?.cast();
         Some(ArrayBuf { ptr, len })
     }
@@ -223,6 +269,18 @@ impl<T> ArrayBuf<T> {
         ArrayBuf { ptr, len }
     }
 
+    /// Try to allocate a new ArrayBuf with storage for `len` items.
+    ///
+    /// Returns an error if `cancel` completes before the allocation succeeds.
+    ///
+    /// Panics if the len is zero, or large enough that creating the layout would fail
+    pub async fn new_uninit_or_cancel<C: Future>(len: usize, cancel: C) -> Result<Self, Cancelled> {
+        assert_ne!(len, 0, "ZST ArrayBuf doesn't make sense");
+        let layout = Self::layout(len);
+        let ptr = alloc_or_cancel(layout, cancel).await?.cast();
+        Ok(ArrayBuf { ptr, len })
+    }
+
     /// Obtain a pointer to the heap allocated storage, as well as the length of items
     ///
     /// This does NOT leak the heap allocation. The returned pointer has the lifetime
@@ -267,6 +325,7 @@ impl<T> FixedVec<T> {
     pub fn try_new(capacity: usize) -> Option<Self> {
         assert_ne!(capacity, 0, "ZST FixedVec doesn't make sense");
         let layout = Layout::array::<T>(capacity).unwrap();
+        let _enforce = quota::enforce();
 
         unsafe {
             let ptr = NonNull::new(alloc::alloc::alloc(layout))?;
@@ -293,6 +352,23 @@ impl<T> FixedVec<T> {
         }
     }
 
+    /// Try to allocate a new FixedVec with storage for UP TO `capacity` items.
+    ///
+    /// Returns an error if `cancel` completes before the allocation succeeds.
+    ///
+    /// Panics if the len is zero, or large enough that creating the layout would fail
+    pub async fn new_or_cancel<C: Future>(capacity: usize, cancel: C) -> Result<Self, Cancelled> {
+        assert_ne!(capacity, 0, "ZST FixedVec doesn't make sense");
+        let layout = Layout::array::<T>(capacity).unwrap();
+
+        unsafe {
+            let ptr = alloc_or_cancel(layout, cancel).await?;
+            Ok(FixedVec {
+                inner: alloc::vec::Vec::from_raw_parts(ptr.cast().as_ptr(), 0, capacity),
+            })
+        }
+    }
+
     /// Attempt to push an item into the fixed vec.
     ///
     /// Returns an error if the fixed vec is full
@@ -360,6 +436,15 @@ impl<T> FixedVec<T> {
         &mut self.inner
     }
 
+    /// Remove and return the item at `index`, shifting all items after it
+    /// to the left.
+    ///
+    /// Panics if `index` is out of bounds.
+    #[inline]
+    pub fn remove(&mut self, index: usize) -> T {
+        self.inner.remove(index)
+    }
+
     /// Clear the FixedVec
     #[inline]
     pub fn clear(&mut self) {
@@ -372,3 +457,776 @@ impl<T> FixedVec<T> {
         self.inner.len() == self.inner.capacity()
     }
 }
+
+//
+// Vec
+//
+
+/// A growable `Vec`
+///
+/// Unlike [FixedVec], a [Vec] grows as items are added. Growing it may need
+/// to reallocate, so the methods which add items are async, and yield until
+/// the reallocation succeeds, like [alloc()]. Each of them also has a `try_`
+/// variant, which returns an error instead if the reallocation fails.
+pub struct Vec<T> {
+    inner: alloc::vec::Vec<T>,
+}
+
+unsafe impl<T: Send> Send for Vec<T> {}
+unsafe impl<T: Sync> Sync for Vec<T> {}
+
+impl<T> Vec<T> {
+    /// Create a new, empty Vec. This does not allocate.
+    pub const fn new() -> Self {
+        Self {
+            inner: alloc::vec::Vec::new(),
+        }
+    }
+
+    /// Allocate a new Vec with room for at least `capacity` items.
+    ///
+    /// Will not return until allocation succeeds.
+    pub async fn with_capacity(capacity: usize) -> Self {
+        let mut vec = Self::new();
+        vec.reserve(capacity).await;
+        vec
+    }
+
+    /// Allocate a new Vec with room for at least `capacity` items.
+    ///
+    /// Returns an error if `cancel` completes before the allocation succeeds.
+    pub async fn with_capacity_or_cancel<C: Future>(
+        capacity: usize,
+        cancel: C,
+    ) -> Result<Self, Cancelled> {
+        let mut vec = Self::new();
+        or_cancel(vec.reserve(capacity), cancel).await?;
+        Ok(vec)
+    }
+
+    /// Try to allocate a new Vec with room for at least `capacity` items.
+    ///
+    /// Returns None if the allocation does not succeed immediately.
+    pub fn try_with_capacity(capacity: usize) -> Option<Self> {
+        let mut vec = Self::new();
+        vec.try_reserve(capacity).ok()?;
+        Some(vec)
+    }
+
+    /// Make room for at least `additional` more items.
+    ///
+    /// Will not return until the reallocation succeeds.
+    pub async fn reserve(&mut self, additional: usize) {
+        let size = self.grown_size(additional);
+        let inner = &mut self.inner;
+        retry(size, || inner.try_reserve(additional).ok()).await
+    }
+
+    /// Try to make room for at least `additional` more items.
+    ///
+    /// Returns an error if the reallocation does not succeed immediately.
+    /// If an error is returned, the contents of the Vec are unchanged.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let _enforce = quota::enforce();
+        self.inner.try_reserve(additional)
+    }
+
+    /// Push an item onto the end of the Vec.
+    ///
+    /// Will not return until there is room for the item.
+    pub async fn push(&mut self, t: T) {
+        self.reserve(1).await;
+        self.inner.push(t);
+    }
+
+    /// Attempt to push an item onto the end of the Vec.
+    ///
+    /// Returns an error containing the item if there is no room for it, and
+    /// the reallocation does not succeed immediately.
+    pub fn try_push(&mut self, t: T) -> Result<(), T> {
+        match self.try_reserve(1) {
+            Ok(()) => {
+                self.inner.push(t);
+                Ok(())
+            }
+            Err(_) => Err(t),
+        }
+    }
+
+    /// Append the items of a slice to the end of the Vec.
+    ///
+    /// Will not return until there is room for the items.
+    pub async fn extend_from_slice(&mut self, sli: &[T])
+    where
+        T: Clone,
+    {
+        self.reserve(sli.len()).await;
+        self.inner.extend_from_slice(sli);
+    }
+
+    /// Attempt to append the items of a slice to the end of the Vec.
+    ///
+    /// Returns an error if there is no room for the items, and the
+    /// reallocation does not succeed immediately. If an error is returned,
+    /// the contents of the Vec are unchanged.
+    pub fn try_extend_from_slice(&mut self, sli: &[T]) -> Result<(), TryReserveError>
+    where
+        T: Clone,
+    {
+        self.try_reserve(sli.len())?;
+        self.inner.extend_from_slice(sli);
+        Ok(())
+    }
+
+    /// Remove and return the last item, if any.
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        self.inner.pop()
+    }
+
+    /// Remove and return the item at `index`, shifting all items after it
+    /// to the left.
+    ///
+    /// Panics if `index` is out of bounds.
+    #[inline]
+    pub fn remove(&mut self, index: usize) -> T {
+        self.inner.remove(index)
+    }
+
+    /// Remove all items after the first `len`. This does not free any memory.
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        self.inner.truncate(len);
+    }
+
+    /// Clear the Vec. This does not free any memory.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    /// Obtain a reference to the underlying [alloc::vec::Vec]
+    #[inline]
+    pub fn as_vec(&self) -> &alloc::vec::Vec<T> {
+        &self.inner
+    }
+
+    /// Obtain a reference to the current contents
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        &self.inner
+    }
+
+    /// Obtain a mutable reference to the current contents
+    #[inline]
+    pub fn as_slice_mut(&mut self) -> &mut [T] {
+        &mut self.inner
+    }
+
+    /// The number of items in the Vec
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Is the Vec empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// The number of items the Vec can hold without reallocating
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// Convert to a regular old alloc vec
+    pub fn into_alloc_vec(self) -> alloc::vec::Vec<T> {
+        self.inner
+    }
+
+    /// Roughly how many bytes growing by `additional` items will allocate,
+    /// for [quota][crate::quota] checks. The standard `Vec` at least
+    /// doubles its capacity when it grows.
+    fn grown_size(&self, additional: usize) -> usize {
+        let len = self.inner.len().saturating_add(additional);
+        len.max(self.inner.capacity().saturating_mul(2))
+            .saturating_mul(size_of::<T>())
+    }
+}
+
+impl<T> Default for Vec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//
+// String
+//
+
+/// A growable `String`
+///
+/// Like [Vec], the methods which add to a [String] are async, and yield
+/// until any reallocation succeeds, and each has a `try_` variant which
+/// returns an error instead.
+///
+/// [core::fmt::Write] is implemented with the `try_` methods, so writing
+/// returns an error rather than waiting if the String needs to grow and
+/// there is no memory.
+pub struct String {
+    inner: Vec<u8>,
+}
+
+impl String {
+    /// Create a new, empty String. This does not allocate.
+    pub const fn new() -> Self {
+        Self { inner: Vec::new() }
+    }
+
+    /// Allocate a new String with room for at least `capacity` bytes.
+    ///
+    /// Will not return until allocation succeeds.
+    pub async fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Vec::with_capacity(capacity).await,
+        }
+    }
+
+    /// Allocate a new String with room for at least `capacity` bytes.
+    ///
+    /// Returns an error if `cancel` completes before the allocation succeeds.
+    pub async fn with_capacity_or_cancel<C: Future>(
+        capacity: usize,
+        cancel: C,
+    ) -> Result<Self, Cancelled> {
+        Ok(Self {
+            inner: Vec::with_capacity_or_cancel(capacity, cancel).await?,
+        })
+    }
+
+    /// Try to allocate a new String with room for at least `capacity` bytes.
+    ///
+    /// Returns None if the allocation does not succeed immediately.
+    pub fn try_with_capacity(capacity: usize) -> Option<Self> {
+        Some(Self {
+            inner: Vec::try_with_capacity(capacity)?,
+        })
+    }
+
+    /// Make room for at least `additional` more bytes.
+    ///
+    /// Will not return until the reallocation succeeds.
+    pub async fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional).await
+    }
+
+    /// Try to make room for at least `additional` more bytes.
+    ///
+    /// Returns an error if the reallocation does not succeed immediately.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.inner.try_reserve(additional)
+    }
+
+    /// Append a string slice to the end of the String.
+    ///
+    /// Will not return until there is room for it.
+    pub async fn push_str(&mut self, s: &str) {
+        self.inner.extend_from_slice(s.as_bytes()).await
+    }
+
+    /// Attempt to append a string slice to the end of the String.
+    ///
+    /// Returns an error if there is no room for it, and the reallocation does
+    /// not succeed immediately. If an error is returned, the contents of the
+    /// String are unchanged.
+    pub fn try_push_str(&mut self, s: &str) -> Result<(), TryReserveError> {
+        self.inner.try_extend_from_slice(s.as_bytes())
+    }
+
+    /// Append a char to the end of the String.
+    ///
+    /// Will not return until there is room for it.
+    pub async fn push(&mut self, c: char) {
+        self.push_str(c.encode_utf8(&mut [0; 4])).await
+    }
+
+    /// Attempt to append a char to the end of the String.
+    ///
+    /// Returns an error if there is no room for it, and the reallocation does
+    /// not succeed immediately.
+    pub fn try_push(&mut self, c: char) -> Result<(), TryReserveError> {
+        self.try_push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    /// Clear the String. This does not free any memory.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    /// Obtain a reference to the current contents
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        // SAFETY: only ever appended to from `&str`s
+        unsafe { core::str::from_utf8_unchecked(self.inner.as_slice()) }
+    }
+
+    /// The length of the String, in bytes
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Is the String empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// The number of bytes the String can hold without reallocating
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// Convert to a regular old alloc string
+    pub fn into_alloc_string(self) -> alloc::string::String {
+        // SAFETY: only ever appended to from `&str`s
+        unsafe { alloc::string::String::from_utf8_unchecked(self.inner.into_alloc_vec()) }
+    }
+}
+
+impl Default for String {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Write for String {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.try_push_str(s).map_err(|_| core::fmt::Error)
+    }
+}
+
+impl core::fmt::Display for String {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl core::fmt::Debug for String {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+//
+// SortedMap
+//
+
+/// A growable map, ordered by key
+///
+/// Entries are kept sorted by key in a [Vec], so lookups are a binary
+/// search, and iteration is in key order. Inserting and removing shifts the
+/// entries after the key, which is cheap for the small tables kernel services
+/// keep, and there is only a single allocation, rather than one per entry.
+///
+/// Like [Vec], inserting a new key may need to grow the map, so [insert]
+/// is async, and yields until the reallocation succeeds. [try_insert]
+/// returns the entry instead if the reallocation fails.
+///
+/// [insert]: SortedMap::insert
+/// [try_insert]: SortedMap::try_insert
+pub struct SortedMap<K, V> {
+    entries: Vec<(K, V)>,
+}
+
+impl<K: Ord, V> SortedMap<K, V> {
+    /// Create a new, empty map. This does not allocate.
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Allocate a new map with room for at least `capacity` entries.
+    ///
+    /// Will not return until allocation succeeds.
+    pub async fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity).await,
+        }
+    }
+
+    /// Allocate a new map with room for at least `capacity` entries.
+    ///
+    /// Returns an error if `cancel` completes before the allocation succeeds.
+    pub async fn with_capacity_or_cancel<C: Future>(
+        capacity: usize,
+        cancel: C,
+    ) -> Result<Self, Cancelled> {
+        Ok(Self {
+            entries: Vec::with_capacity_or_cancel(capacity, cancel).await?,
+        })
+    }
+
+    /// Try to allocate a new map with room for at least `capacity` entries.
+    ///
+    /// Returns None if the allocation does not succeed immediately.
+    pub fn try_with_capacity(capacity: usize) -> Option<Self> {
+        Some(Self {
+            entries: Vec::try_with_capacity(capacity)?,
+        })
+    }
+
+    /// Make room for at least `additional` more entries.
+    ///
+    /// Will not return until the reallocation succeeds.
+    pub async fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional).await;
+    }
+
+    /// Try to make room for at least `additional` more entries.
+    ///
+    /// Returns an error if the reallocation does not succeed immediately.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.entries.try_reserve(additional)
+    }
+
+    /// Insert a value for `key`, returning the previous value, if any.
+    ///
+    /// Replacing the value of an existing key never allocates. Otherwise,
+    /// this will not return until there is room for the new entry.
+    pub async fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.search(&key) {
+            Ok(idx) => Some(core::mem::replace(&mut self.entries.inner[idx].1, value)),
+            Err(idx) => {
+                self.entries.reserve(1).await;
+                self.entries.inner.insert(idx, (key, value));
+                None
+            }
+        }
+    }
+
+    /// Attempt to insert a value for `key`, returning the previous value, if any.
+    ///
+    /// Returns an error containing the entry if `key` is new, there is no
+    /// room for it, and the reallocation does not succeed immediately.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        match self.search(&key) {
+            Ok(idx) => Ok(Some(core::mem::replace(
+                &mut self.entries.inner[idx].1,
+                value,
+            ))),
+            Err(idx) => match self.entries.try_reserve(1) {
+                Ok(()) => {
+                    self.entries.inner.insert(idx, (key, value));
+                    Ok(None)
+                }
+                Err(_) => Err((key, value)),
+            },
+        }
+    }
+
+    /// Obtain a reference to the value for `key`, if any.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let idx = self.search(key).ok()?;
+        Some(&self.entries.inner[idx].1)
+    }
+
+    /// Obtain a mutable reference to the value for `key`, if any.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let idx = self.search(key).ok()?;
+        Some(&mut self.entries.inner[idx].1)
+    }
+
+    /// Does the map contain a value for `key`?
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.search(key).is_ok()
+    }
+
+    /// Remove and return the entry for `key`, if any. This does not free any memory.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let idx = self.search(key).ok()?;
+        Some(self.entries.remove(idx))
+    }
+
+    /// Keep only the entries for which `f` returns `true`.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.entries.inner.retain_mut(|(k, v)| f(k, v));
+    }
+
+    /// Clear the map. This does not free any memory.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Iterate over the entries, in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.entries.as_slice().iter().map(|(k, v)| (k, v))
+    }
+
+    /// Iterate over the entries, in key order, with mutable values
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> + '_ {
+        self.entries
+            .as_slice_mut()
+            .iter_mut()
+            .map(|(k, v)| (&*k, v))
+    }
+
+    /// Iterate over the keys, in order
+    pub fn keys(&self) -> impl Iterator<Item = &K> + '_ {
+        self.entries.as_slice().iter().map(|(k, _)| k)
+    }
+
+    /// Iterate over the values, in key order
+    pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
+        self.entries.as_slice().iter().map(|(_, v)| v)
+    }
+
+    /// The number of entries in the map
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Is the map empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of entries the map can hold without reallocating
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    /// Find the index of `key`, or the index where it would be inserted.
+    fn search<Q>(&self, key: &Q) -> Result<usize, usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.entries
+            .as_slice()
+            .binary_search_by(|(k, _)| k.borrow().cmp(key))
+    }
+}
+
+impl<K: Ord, V> Default for SortedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + core::fmt::Debug, V: core::fmt::Debug> core::fmt::Debug for SortedMap<K, V> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::*;
+    use crate::test_alloc;
+    use alloc::borrow::ToOwned;
+    use core::{
+        fmt::Write,
+        pin::pin,
+        ptr::null,
+        task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    };
+
+    /// Poll `fut` once, expecting it to complete, as there is always memory
+    /// on the host.
+    fn now<F: Future>(fut: F) -> F::Output {
+        // Not an `Arc` waker, so that this doesn't allocate.
+        const VTABLE: RawWakerVTable =
+            RawWakerVTable::new(|_| RawWaker::new(null(), &VTABLE), |_| {}, |_| {}, |_| {});
+        // SAFETY: the vtable functions do nothing.
+        let waker = unsafe { Waker::from_raw(RawWaker::new(null(), &VTABLE)) };
+        match pin!(fut).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future did not complete immediately"),
+        }
+    }
+
+    #[test]
+    fn vec_grows() {
+        let mut vec = Vec::new();
+        assert_eq!(vec.capacity(), 0);
+        for i in 0..10 {
+            now(vec.push(i));
+        }
+        now(vec.extend_from_slice(&[10, 11, 12]));
+        vec.try_push(13).unwrap();
+        vec.try_extend_from_slice(&[14, 15]).unwrap();
+        assert_eq!(vec.len(), 16);
+        assert!(vec.capacity() >= 16);
+        assert_eq!(vec.as_slice(), (0..16).collect::<std::vec::Vec<_>>());
+
+        assert_eq!(vec.pop(), Some(15));
+        assert_eq!(vec.remove(0), 0);
+        vec.truncate(3);
+        assert_eq!(vec.as_slice(), [1, 2, 3]);
+        vec.clear();
+        assert!(vec.is_empty());
+        assert_eq!(vec.pop(), None);
+    }
+
+    #[test]
+    fn vec_try_push_returns_item() {
+        let mut vec = Vec::try_with_capacity(2).unwrap();
+        // Pushing into spare capacity doesn't allocate...
+        while vec.len() < vec.capacity() {
+            test_alloc::failing(|| vec.try_push(1)).unwrap();
+        }
+        let full = vec.len();
+        // ...but growing does.
+        assert_eq!(test_alloc::failing(|| vec.try_push(2)), Err(2));
+        assert!(test_alloc::failing(|| vec.try_extend_from_slice(&[2, 3])).is_err());
+        assert_eq!(vec.len(), full);
+        assert!(vec.as_slice().iter().all(|&i| i == 1));
+        assert!(test_alloc::failing(|| Vec::<u32>::try_with_capacity(1)).is_none());
+    }
+
+    #[test]
+    fn string_utf8_round_trip() {
+        let text = "aé€😀";
+        let mut string = String::new();
+        for c in text.chars() {
+            now(string.push(c));
+        }
+        assert_eq!(string.as_str(), text);
+        assert_eq!(string.len(), 1 + 2 + 3 + 4);
+
+        string.try_push('ß').unwrap();
+        now(string.push_str("ñ"));
+        string.try_push_str("!").unwrap();
+        write!(string, " {}-{:02}", "ü", 7).unwrap();
+        assert_eq!(string.as_str(), "aé€😀ßñ! ü-07");
+        assert_eq!(std::format!("{string}"), "aé€😀ßñ! ü-07");
+        assert_eq!(std::format!("{string:?}"), "\"aé€😀ßñ! ü-07\"");
+        assert_eq!(string.into_alloc_string(), "aé€😀ßñ! ü-07");
+    }
+
+    #[test]
+    fn string_write_fails_without_memory() {
+        let mut string = String::new();
+        assert!(test_alloc::failing(|| write!(string, "{}", "😀")).is_err());
+        assert!(test_alloc::failing(|| string.try_push('é')).is_err());
+        assert!(string.is_empty());
+        let before = test_alloc::allocs();
+        write!(string, "{}", "😀").unwrap();
+        assert_eq!(test_alloc::allocs(), before + 1);
+        assert_eq!(string.as_str(), "😀");
+    }
+
+    fn map(keys: &[u32]) -> SortedMap<u32, u32> {
+        let mut map = SortedMap::new();
+        for &key in keys {
+            assert_eq!(map.try_insert(key, key * 10), Ok(None));
+        }
+        map
+    }
+
+    #[test]
+    fn sorted_after_out_of_order_inserts() {
+        let map = map(&[5, 1, 9, 3, 7, 0]);
+        assert_eq!(map.len(), 6);
+        assert!(map.keys().copied().eq([0, 1, 3, 5, 7, 9]));
+        assert!(map.values().copied().eq([0, 10, 30, 50, 70, 90]));
+        assert!(map.iter().all(|(&key, &value)| value == key * 10));
+        assert_eq!(
+            std::format!("{map:?}"),
+            "{0: 0, 1: 10, 3: 30, 5: 50, 7: 70, 9: 90}"
+        );
+    }
+
+    #[test]
+    fn insert_replaces_without_allocating() {
+        let mut map = map(&[1, 2, 3]);
+        let capacity = map.capacity();
+        let before = test_alloc::allocs();
+        assert_eq!(now(map.insert(2, 200)), Some(20));
+        // Even if allocations would fail.
+        assert_eq!(test_alloc::failing(|| map.try_insert(3, 300)), Ok(Some(30)));
+        assert_eq!(test_alloc::allocs(), before);
+        assert_eq!(map.capacity(), capacity);
+        assert_eq!(map.len(), 3);
+        assert!(map.values().copied().eq([10, 200, 300]));
+    }
+
+    #[test]
+    fn try_insert_returns_entry() {
+        let mut map = SortedMap::new();
+        assert_eq!(test_alloc::failing(|| map.try_insert(1, 10)), Err((1, 10)));
+        assert!(map.is_empty());
+
+        let mut map = self::map(&[1]);
+        while map.len() < map.capacity() {
+            let key = map.len() as u32 + 1;
+            test_alloc::failing(|| map.try_insert(key, 0)).unwrap();
+        }
+        let full = map.len();
+        assert_eq!(test_alloc::failing(|| map.try_insert(0, 0)), Err((0, 0)));
+        assert_eq!(map.len(), full);
+        assert!(!map.contains_key(&0));
+    }
+
+    #[test]
+    fn remove_retain_get() {
+        let mut map = map(&[4, 2, 8, 6]);
+        let capacity = map.capacity();
+        assert_eq!(map.remove(&2), Some((2, 20)));
+        assert_eq!(map.remove(&2), None);
+        assert!(!map.contains_key(&2));
+        assert_eq!(map.capacity(), capacity);
+
+        *map.get_mut(&8).unwrap() += 1;
+        assert_eq!(map.get(&8), Some(&81));
+        assert_eq!(map.get(&5), None);
+
+        map.retain(|&key, value| {
+            *value += 1;
+            key != 6
+        });
+        assert!(map.iter().eq([(&4, &41), (&8, &82)]));
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn get_with_borrowed_key() {
+        let mut map = SortedMap::<alloc::string::String, u32>::new();
+        for (i, key) in ["charlie", "alpha", "bravo"].into_iter().enumerate() {
+            map.try_insert(key.to_owned(), i as u32).unwrap();
+        }
+        assert_eq!(map.get("bravo"), Some(&2));
+        assert!(map.contains_key("alpha"));
+        assert_eq!(map.get("delta"), None);
+        *map.get_mut("charlie").unwrap() = 10;
+        assert_eq!(map.remove("charlie"), Some(("charlie".to_owned(), 10)));
+        assert!(map.keys().map(|k| k.as_str()).eq(["alpha", "bravo"]));
+    }
+}
@@ -2,13 +2,21 @@
 
 use core::{
     alloc::{GlobalAlloc, Layout},
+    future::{poll_fn, Future},
+    pin::pin,
     ptr::{null_mut, NonNull},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::Poll,
 };
 
 use linked_list_allocator::Heap;
 use maitake::sync::{Mutex, WaitQueue};
 
+use crate::{
+    policy::{self, OomPolicy, WaiterTable},
+    quota,
+};
+
 /// # Mnemos Allocator
 ///
 /// This is a wrapper type over an implementor of [UnderlyingAllocator].
@@ -19,16 +27,30 @@ use maitake::sync::{Mutex, WaitQueue};
 /// Out of Memory conditions
 ///
 /// * On **alloc**:
-///     * We check whether allocation is inhibited. If it is - a nullptr
-///       is returned, regardless of whether there is sufficient room to
-///       allocate the requested amount.
+///     * We check whether allocation is inhibited, or whether the [OomPolicy]
+///       holds async allocations back for those that are already waiting. If
+///       so - a nullptr is returned, regardless of whether there is
+///       sufficient room to allocate the requested amount. Other allocations
+///       are never held back for waiters.
 ///     * If we are NOT inhibited, but are now out of memory (the underlying
 ///       allocator returned a nullptr), we inhibit further allocations until
 ///       the next deallocation occurs
+///     * The allocation is charged to the current [quota] account, if any.
+///       For async and `try_` allocations, which enforce quota limits, a
+///       nullptr is returned if that would take the account over its limit,
+///       but allocations are NOT inhibited for anyone else
 /// * On **dealloc**:
 ///     * The "inhibit allocations" flag is cleared
-///     * If any tasks are waiting on the "OOM" queue, they are ALL awoken if
-///       the inhibit flag was previously set
+///     * Waiting async allocations are woken, as chosen by the [OomPolicy]
+///       the allocator was constructed with. With the default
+///       [OomPolicy::Inhibit], they are ALL awoken if the inhibit flag was
+///       previously set
+///     * The [quota] account the allocation was charged to is credited
+///
+/// With the `quota` feature, every allocation is preceded by a small tag, to
+/// find the account to credit on dealloc, so each allocation uses a few more
+/// bytes of the [UnderlyingAllocator] than requested. Without it, nothing is
+/// charged to [quota] accounts.
 ///
 /// These two details are intended to allow the "async allocation aware" types
 /// defined in [crate::containers] to yield if allocation is not currently possible.
@@ -39,66 +61,309 @@ use maitake::sync::{Mutex, WaitQueue};
 /// to normal OOM handling, which typically means panicking.
 pub struct MnemosAlloc<U> {
     allocator: U,
+    /// The async allocations waiting for memory, and the [OomPolicy] they
+    /// are served by
+    waiters: WaiterTable,
+    /// Flag to inhibit allocs. With [OomPolicy::Inhibit], this ensures that allocations
+    /// are served in a FIFO order, so if there are 50 bytes left, then we get a sequence
+    /// of alloc requests like [64, 10, 30], none will be served until there is room for
+    /// 64. This prevents large allocations from being starved, at the cost of delaying
+    /// small allocations that *could* potentially succeed
+    inhibit: AtomicBool,
+    /// The size of the region passed to [MnemosAlloc::init()]
+    heap_size: AtomicUsize,
+    /// Bytes currently allocated
+    allocated: AtomicUsize,
+    /// The most bytes ever allocated at once
+    peak_allocated: AtomicUsize,
+    /// Number of successful allocations
+    allocs: AtomicUsize,
+    /// Number of allocations which returned a null pointer
+    failed_allocs: AtomicUsize,
+    /// Number of times allocations were inhibited
+    inhibited: AtomicUsize,
+}
+
+/// A snapshot of a [MnemosAlloc]'s usage, see [MnemosAlloc::stats()].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The size of the heap, in bytes, or zero if the [UnderlyingAllocator]
+    /// was not initialized with a region of memory.
+    pub heap_size: usize,
+    /// The number of bytes currently allocated, as requested by the callers.
+    ///
+    /// This does not include the [quota] tags in front of each allocation
+    /// (with the `quota` feature), or any overhead or padding of the
+    /// [UnderlyingAllocator], so the underlying allocator may actually be
+    /// using more memory than this.
+    pub allocated: usize,
+    /// The most bytes that have ever been allocated at once.
+    pub peak_allocated: usize,
+    /// The total number of successful allocations.
+    pub allocs: usize,
+    /// The total number of allocations which failed, either because the
+    /// [UnderlyingAllocator] was out of memory, or because allocations were
+    /// inhibited.
+    pub failed_allocs: usize,
+    /// The number of times allocations were inhibited after running out of
+    /// memory.
+    pub inhibited: usize,
+    /// The size of the largest block that could currently be allocated, if
+    /// known.
+    ///
+    /// This is always `None` in the stats returned by [MnemosAlloc::stats()],
+    /// as finding it uses the heap. Callers which can afford that fill it in
+    /// with [MnemosAlloc::largest_free_block()].
+    pub largest_free_block: Option<usize>,
+}
+
+impl HeapStats {
+    /// The number of bytes not currently allocated, or zero if the heap size
+    /// is unknown.
+    pub fn free(&self) -> usize {
+        self.heap_size.saturating_sub(self.allocated)
+    }
+
+    /// How fragmented the free memory is, as a percentage: zero if all of the
+    /// free memory could be allocated as a single block, approaching 100 as
+    /// the free memory is split into many small blocks.
+    ///
+    /// Returns `None` if the largest free block or the heap size is unknown.
+    pub fn fragmentation_pct(&self) -> Option<usize> {
+        let largest = self.largest_free_block?;
+        match self.free() {
+            0 => None,
+            free => Some(100 - (largest.min(free) * 100 / free)),
+        }
+    }
 }
 
 impl<U: UnderlyingAllocator> MnemosAlloc<U> {
+    /// Create an allocator with the default [OomPolicy::Inhibit] policy.
     pub const fn new() -> Self {
-        Self { allocator: U::INIT }
+        Self::with_policy(OomPolicy::Inhibit)
+    }
+
+    /// Create an allocator which serves waiting async allocations according
+    /// to `policy`, see the [policy][crate::policy] module.
+    pub const fn with_policy(policy: OomPolicy) -> Self {
+        Self {
+            allocator: U::INIT,
+            waiters: WaiterTable::new(policy),
+            inhibit: AtomicBool::new(false),
+            heap_size: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+            peak_allocated: AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            failed_allocs: AtomicUsize::new(0),
+            inhibited: AtomicUsize::new(0),
+        }
     }
 
     pub unsafe fn init(&self, start: NonNull<u8>, len: usize) {
-        self.allocator.init(start, len)
+        self.allocator.init(start, len);
+        self.heap_size.store(len, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the allocator's usage.
+    ///
+    /// This only reads counters, and never touches the heap itself, so it is
+    /// safe to call at any time, including from a panic handler. The
+    /// [HeapStats::largest_free_block] is left as `None`, see
+    /// [MnemosAlloc::largest_free_block()].
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            heap_size: self.heap_size.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            peak_allocated: self.peak_allocated.load(Ordering::Relaxed),
+            allocs: self.allocs.load(Ordering::Relaxed),
+            failed_allocs: self.failed_allocs.load(Ordering::Relaxed),
+            inhibited: self.inhibited.load(Ordering::Relaxed),
+            largest_free_block: None,
+        }
+    }
+
+    /// Returns the size of the largest block that could currently be
+    /// allocated, if the [UnderlyingAllocator] can tell.
+    ///
+    /// This may use the heap, see [UnderlyingAllocator::largest_free_block()],
+    /// so it should not be called often, and never from a panic handler.
+    pub fn largest_free_block(&self) -> Option<usize> {
+        self.allocator.largest_free_block()
     }
 }
 
 unsafe impl<U: UnderlyingAllocator> GlobalAlloc for MnemosAlloc<U> {
     #[inline(always)]
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        if INHIBIT_ALLOC.load(Ordering::Acquire) {
+        if self
+            .waiters
+            .holds_back(self.inhibit.load(Ordering::Acquire))
+        {
+            self.waiters.failed();
+            self.failed_allocs.fetch_add(1, Ordering::Relaxed);
             return null_mut();
         }
-        let ptr = self.allocator.alloc(layout);
+        let Some((tagged, offset)) = tagged(layout) else {
+            self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+            return null_mut();
+        };
+        let Some(tag) = quota::charge(layout.size()) else {
+            self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+            return null_mut();
+        };
+        let ptr = self.allocator.alloc(tagged);
         if ptr.is_null() {
-            INHIBIT_ALLOC.store(true, Ordering::Release);
+            quota::credit(tag, layout.size());
+            self.waiters.failed();
+            self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+            if !self.inhibit.swap(true, Ordering::AcqRel) {
+                self.inhibited.fetch_add(1, Ordering::Relaxed);
+            }
+            return ptr;
         }
+        let allocated = self.allocated.fetch_add(layout.size(), Ordering::Relaxed);
+        self.peak_allocated
+            .fetch_max(allocated + layout.size(), Ordering::Relaxed);
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        self.waiters.allocated();
+        if quota::ENABLED {
+            ptr.cast::<u32>().write(tag);
+        }
+        let ptr = ptr.add(offset);
+        #[cfg(feature = "leak-trace")]
+        crate::leaks::record(ptr, layout);
         ptr
     }
 
     #[inline]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        self.allocator.dealloc(ptr, layout);
-        let was_inhib = INHIBIT_ALLOC.swap(false, Ordering::AcqRel);
+        let Some((tagged, offset)) = tagged(layout) else {
+            debug_assert!(false, "Deallocating a layout that could never be allocated?");
+            return;
+        };
+        #[cfg(feature = "leak-trace")]
+        crate::leaks::forget(ptr);
+        let ptr = ptr.sub(offset);
+        let tag = if quota::ENABLED {
+            ptr.cast::<u32>().read()
+        } else {
+            0
+        };
+        self.allocator.dealloc(ptr, tagged);
+        self.allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+        quota::credit(tag, layout.size());
+        let was_inhib = self.inhibit.swap(false, Ordering::AcqRel);
+        self.waiters.deallocated(was_inhib);
         if was_inhib {
             OOM_WAITER.wake_all();
         }
     }
 }
 
+/// Returns the layout of an allocation preceded by its [quota] tag, and the
+/// offset of the allocation itself. Without the `quota` feature, allocations
+/// aren't tagged.
+#[inline(always)]
+fn tagged(layout: Layout) -> Option<(Layout, usize)> {
+    if quota::ENABLED {
+        Layout::new::<u32>().extend(layout).ok()
+    } else {
+        Some((layout, 0))
+    }
+}
+
 /// A [WaitQueue] for tasks that would like to allocate, but the allocator is
-/// currently in temporary OOM mode
+/// currently in temporary OOM mode, and its [policy][crate::policy] waiter
+/// table is full
 static OOM_WAITER: WaitQueue = WaitQueue::new();
 
-/// Flag to inhibit allocs. This ensures that allocations are served in a FIFO
-/// order, so if there are 50 bytes left, then we get a sequence of alloc requests
-/// like [64, 10, 30], none will be served until there is room for 64. This prevents
-/// large allocations from being starved, at the cost of delaying small allocations
-/// that *could* potentially succeed
-static INHIBIT_ALLOC: AtomicBool = AtomicBool::new(false);
-
 /// Asynchronously allocate with the given [Layout].
 ///
 /// Analogous to [alloc::alloc::alloc()], but will never return a null pointer,
 /// and will instead yield until allocation succeeds (which could theoretically
 /// be never).
+///
+/// If the current [quota] account is over its limit, this yields until the
+/// account frees enough memory, or its limit is raised. An allocation larger
+/// than the account's limit will never succeed.
+///
+/// Otherwise, this waits in the allocator's [policy][crate::policy] waiter
+/// table, and its [OomPolicy] decides when it is woken to try again.
 pub async fn alloc(layout: Layout) -> NonNull<u8> {
+    retry(layout.size(), || {
+        NonNull::new(unsafe { alloc::alloc::alloc(layout) })
+    })
+    .await
+}
+
+/// Asynchronously allocate with the given [Layout], unless `cancel`
+/// completes first.
+///
+/// This is the same as [alloc()], but returns [Cancelled] if `cancel`
+/// completes before the allocation succeeds. To give up after a timeout,
+/// pass a sleep future as `cancel`, such as the kernel's `Kernel::sleep()`.
+pub async fn alloc_or_cancel<C: Future>(
+    layout: Layout,
+    cancel: C,
+) -> Result<NonNull<u8>, Cancelled> {
+    or_cancel(alloc(layout), cancel).await
+}
+
+/// Returned by the `_or_cancel` allocation functions (such as
+/// [alloc_or_cancel()]) if they were cancelled before the allocation
+/// succeeded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Cancelled;
+
+/// Run `fut` until it completes, or until `cancel` completes, whichever
+/// comes first.
+pub(crate) async fn or_cancel<F: Future, C: Future>(
+    fut: F,
+    cancel: C,
+) -> Result<F::Output, Cancelled> {
+    let mut fut = pin!(fut);
+    let mut cancel = pin!(cancel);
+    poll_fn(|cx| {
+        if let Poll::Ready(out) = fut.as_mut().poll(cx) {
+            return Poll::Ready(Ok(out));
+        }
+        if cancel.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(Cancelled));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Call `try_alloc` until it succeeds, waiting for memory to be freed in
+/// between, as described in [alloc()]. `size` is the number of bytes
+/// `try_alloc` allocates.
+pub(crate) async fn retry<R>(size: usize, mut try_alloc: impl FnMut() -> Option<R>) -> R {
+    let mut waiter = None;
     loop {
-        unsafe {
-            match NonNull::new(alloc::alloc::alloc(layout.clone())) {
-                Some(nn) => return nn,
-                None => {
-                    let _ = OOM_WAITER.wait().await;
-                    continue;
-                }
+        let (res, failed) = {
+            let asynchronous = policy::asynchronous(waiter.as_ref());
+            let _enforce = quota::enforce();
+            (try_alloc(), asynchronous.failed())
+        };
+        if let Some(res) = res {
+            return res;
+        }
+        if quota::over_limit(size) {
+            // Don't hold up other allocations while we wait for our own
+            // account to free memory.
+            waiter = None;
+            quota::wait_for_room().await;
+            continue;
+        }
+        if waiter.is_none() {
+            waiter = failed.and_then(|table| table.claim(size));
+        }
+        match waiter.as_ref() {
+            Some(waiter) => waiter.wait().await,
+            None => {
+                let _ = OOM_WAITER.wait().await;
             }
         }
     }
@@ -144,6 +409,16 @@ pub trait UnderlyingAllocator {
     ///
     /// SAFETY: The same as [GlobalAlloc::dealloc()].
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout);
+
+    /// Returns the size of the largest block of memory that could currently
+    /// be allocated, or `None` if this is unknown.
+    ///
+    /// This is used to report heap fragmentation in [HeapStats]. It must not
+    /// block, and should return `None` rather than panic if the allocator is
+    /// in use. The default implementation always returns `None`.
+    fn largest_free_block(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// A wrapper of [linked_list_allocator::Heap] that uses [maitake::sync::Mutex].
@@ -193,6 +468,215 @@ impl UnderlyingAllocator for SingleThreadedLinkedListAllocator {
             }
         }
     }
+
+    /// The linked list allocator doesn't expose its free list, so this does a
+    /// binary search for the largest allocation that succeeds, freeing each
+    /// probe allocation right away. This takes `O(log(free bytes))` trial
+    /// allocations, so it should not be called often.
+    ///
+    /// Returns `None` if the heap is currently locked.
+    fn largest_free_block(&self) -> Option<usize> {
+        let mut heap = self.mlla.try_lock()?;
+        let (mut lo, mut hi) = (0, heap.free());
+        while lo < hi {
+            let probe = lo + (hi - lo + 1) / 2;
+            let layout = Layout::from_size_align(probe, 1).ok()?;
+            match heap.allocate_first_fit(layout) {
+                Ok(nn) => {
+                    unsafe { heap.deallocate(nn, layout) };
+                    lo = probe;
+                }
+                Err(()) => hi = probe - 1,
+            }
+        }
+        Some(lo)
+    }
+}
+
+/// A size-class slab allocator, which sits in front of another
+/// [UnderlyingAllocator].
+///
+/// Small allocations are rounded up to one of a few size classes (16 to 512
+/// bytes, see [SlabAllocator::CLASSES]), and served from a free list for that
+/// class. When a class runs out, a slab of at least
+/// [SlabAllocator::SLAB_SIZE] bytes (see [SlabAllocator::slab_size()]) is
+/// allocated from the fallback allocator and split into objects of that
+/// class. Freed objects go back on
+/// their class's free list, so the many small, same-sized objects the kernel
+/// creates (oneshot slots, channel cells, envelopes, ...) are allocated and
+/// freed in constant time, and don't fragment the fallback allocator.
+/// Allocations larger than the largest class go straight to the fallback.
+///
+/// Slabs are never returned to the fallback allocator, even once every
+/// object in them has been freed, so memory on the free lists only counts as
+/// "free" for allocations of the same class. A burst of small allocations
+/// therefore dedicates memory to their class for good, and leaves less for
+/// other classes and for large allocations. This also means
+/// [HeapStats::free()] includes memory that is only available to one size
+/// class, and [UnderlyingAllocator::largest_free_block()] doesn't include it
+/// at all.
+///
+/// As with [SingleThreadedLinkedListAllocator], this should ONLY be used in a
+/// single threaded context, and will panic if an allocation is attempted while
+/// the free lists are locked.
+///
+/// For example, to put a slab allocator in front of the linked list allocator:
+///
+/// ```rust
+/// use mnemos_alloc::heap::{MnemosAlloc, SingleThreadedLinkedListAllocator, SlabAllocator};
+///
+/// static AHEAP: MnemosAlloc<SlabAllocator<SingleThreadedLinkedListAllocator>> =
+///     MnemosAlloc::new();
+/// ```
+pub struct SlabAllocator<U> {
+    free: Mutex<FreeLists>,
+    fallback: U,
+}
+
+const NUM_SLAB_CLASSES: usize = 6;
+
+/// The heads of each size class's free list.
+struct FreeLists {
+    heads: [Option<NonNull<FreeObject>>; NUM_SLAB_CLASSES],
+}
+
+/// A free object, which holds a pointer to the next free object of its class.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+// SAFETY: the free objects are only ever accessed through the mutex.
+unsafe impl Send for FreeLists {}
+
+/// The room [MnemosAlloc] needs for the [quota] tag in front of an
+/// allocation aligned to at most 8 bytes, or zero without the `quota` feature.
+const SLAB_TAG_SIZE: usize = if quota::ENABLED { 8 } else { 0 };
+
+impl<U> SlabAllocator<U> {
+    /// The object sizes of each size class, in bytes.
+    ///
+    /// These are powers of two from 16 to 512. With the `quota` feature,
+    /// [MnemosAlloc] puts a tag in front of every allocation, so each class
+    /// has room for the tag on top, and e.g. a 64 byte object still uses the
+    /// 64 byte class.
+    pub const CLASSES: [usize; NUM_SLAB_CLASSES] = [
+        16 + SLAB_TAG_SIZE,
+        32 + SLAB_TAG_SIZE,
+        64 + SLAB_TAG_SIZE,
+        128 + SLAB_TAG_SIZE,
+        256 + SLAB_TAG_SIZE,
+        512 + SLAB_TAG_SIZE,
+    ];
+
+    /// The minimum number of bytes allocated from the fallback allocator
+    /// when a size class runs out of free objects, see
+    /// [SlabAllocator::slab_size()].
+    pub const SLAB_SIZE: usize = 2048;
+
+    /// Returns the size of the slabs of the size class with object size
+    /// `class`, in bytes.
+    ///
+    /// This is the smallest whole number of objects that is at least
+    /// [SlabAllocator::SLAB_SIZE], so no part of a slab is wasted, even when
+    /// the object size doesn't divide [SlabAllocator::SLAB_SIZE] (e.g. with
+    /// the quota tag).
+    pub const fn slab_size(class: usize) -> usize {
+        (Self::SLAB_SIZE + class - 1) / class * class
+    }
+
+    /// Returns the size class for `layout`, if it is small enough.
+    fn class(layout: Layout) -> Option<usize> {
+        Self::CLASSES
+            .iter()
+            .position(|&class| layout.size() <= class && layout.align() <= Self::class_align(class))
+    }
+
+    /// Returns the alignment of objects of the size class with object size
+    /// `class`.
+    ///
+    /// Slabs are aligned to the largest power of two that divides their
+    /// object size, so every object in the slab is too. Without the quota tag,
+    /// that is the object size itself, and with it, 8 bytes.
+    const fn class_align(class: usize) -> usize {
+        class & class.wrapping_neg()
+    }
+
+    /// Returns a reference to the fallback allocator.
+    pub fn fallback(&self) -> &U {
+        &self.fallback
+    }
+}
+
+impl FreeLists {
+    unsafe fn push(&mut self, class: usize, ptr: *mut u8) {
+        let obj = ptr.cast::<FreeObject>();
+        obj.write(FreeObject {
+            next: self.heads[class],
+        });
+        self.heads[class] = NonNull::new(obj);
+    }
+
+    unsafe fn pop(&mut self, class: usize) -> *mut u8 {
+        match self.heads[class] {
+            Some(obj) => {
+                self.heads[class] = obj.as_ptr().read().next;
+                obj.as_ptr().cast()
+            }
+            None => null_mut(),
+        }
+    }
+}
+
+impl<U: UnderlyingAllocator> UnderlyingAllocator for SlabAllocator<U> {
+    const INIT: Self = SlabAllocator {
+        free: Mutex::new(FreeLists {
+            heads: [None; NUM_SLAB_CLASSES],
+        }),
+        fallback: U::INIT,
+    };
+
+    #[inline]
+    unsafe fn init(&self, start: NonNull<u8>, len: usize) {
+        self.fallback.init(start, len);
+    }
+
+    #[inline]
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let Some(class) = Self::class(layout) else {
+            return self.fallback.alloc(layout);
+        };
+        let mut free = self.free.try_lock().unwrap();
+        if free.heads[class].is_none() {
+            let size = Self::CLASSES[class];
+            let slab_size = Self::slab_size(size);
+            let slab = self.fallback.alloc(Layout::from_size_align_unchecked(
+                slab_size,
+                Self::class_align(size),
+            ));
+            if slab.is_null() {
+                return null_mut();
+            }
+            // Push in reverse, so objects are handed out in address order.
+            for idx in (0..slab_size / size).rev() {
+                free.push(class, slab.add(idx * size));
+            }
+        }
+        free.pop(class)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        match Self::class(layout) {
+            Some(class) => self.free.try_lock().unwrap().push(class, ptr),
+            None => self.fallback.dealloc(ptr, layout),
+        }
+    }
+
+    /// Returns the fallback allocator's largest free block. Objects on the
+    /// free lists are not included.
+    fn largest_free_block(&self) -> Option<usize> {
+        self.fallback.largest_free_block()
+    }
 }
 
 #[cfg(feature = "use-std")]
@@ -211,3 +695,138 @@ impl UnderlyingAllocator for std::alloc::System {
         <std::alloc::System as GlobalAlloc>::dealloc(self, ptr, layout)
     }
 }
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::*;
+    use std::alloc::System;
+
+    type Slab = SlabAllocator<System>;
+
+    fn layout_of_class(class: usize) -> Layout {
+        let size = Slab::CLASSES[class];
+        Layout::from_size_align(size, Slab::class_align(size)).unwrap()
+    }
+
+    fn free_lists_empty(slab: &Slab) -> bool {
+        slab.free
+            .try_lock()
+            .unwrap()
+            .heads
+            .iter()
+            .all(Option::is_none)
+    }
+
+    #[test]
+    fn power_of_two_objects_keep_their_class() {
+        // Whatever MnemosAlloc adds in front of them, power-of-two sized
+        // objects must not be bumped up to the next class.
+        for (class, size) in [16, 32, 64, 128, 256, 512].into_iter().enumerate() {
+            for align in [1, 4, 8] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let (tagged, _) = tagged(layout).unwrap();
+                assert_eq!(Slab::class(tagged), Some(class), "{layout:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn free_list_push_pop() {
+        let slab = <Slab as UnderlyingAllocator>::INIT;
+        let layout = layout_of_class(1);
+        unsafe {
+            let a = slab.alloc(layout);
+            let b = slab.alloc(layout);
+            assert!(!a.is_null());
+            assert!(!b.is_null());
+            assert_ne!(a, b);
+
+            // Freed objects are handed out again, most recently freed first.
+            slab.dealloc(a, layout);
+            assert_eq!(slab.alloc(layout), a);
+            slab.dealloc(b, layout);
+            slab.dealloc(a, layout);
+            assert_eq!(slab.alloc(layout), a);
+            assert_eq!(slab.alloc(layout), b);
+        }
+    }
+
+    #[test]
+    fn slab_refill() {
+        let slab = <Slab as UnderlyingAllocator>::INIT;
+        for class in 0..NUM_SLAB_CLASSES {
+            let layout = layout_of_class(class);
+            let size = layout.size();
+            let slab_size = Slab::slab_size(size);
+            let per_slab = slab_size / size;
+            let objs = (0..=per_slab)
+                .map(|_| unsafe { slab.alloc(layout) })
+                .collect::<std::vec::Vec<_>>();
+
+            // The first slab is handed out in address order, without
+            // overrunning the slab.
+            let first = objs[0] as usize;
+            for (idx, &obj) in objs[..per_slab].iter().enumerate() {
+                assert_eq!(obj as usize, first + idx * size);
+                assert_eq!(obj as usize % layout.align(), 0);
+            }
+
+            // Then the class is refilled with a new slab.
+            let next = objs[per_slab] as usize;
+            assert!(!objs[per_slab].is_null());
+            assert!(next < first || next >= first + slab_size);
+        }
+    }
+
+    #[test]
+    fn slabs_fit_whole_objects() {
+        for size in Slab::CLASSES {
+            let slab_size = Slab::slab_size(size);
+            assert_eq!(slab_size % size, 0, "{size}");
+            assert!(slab_size >= Slab::SLAB_SIZE, "{size}");
+            assert!(slab_size - size < Slab::SLAB_SIZE, "{size}");
+        }
+        assert_eq!(Slab::slab_size(520), 2080);
+        assert_eq!(Slab::slab_size(512), 2048);
+    }
+
+    #[test]
+    fn fallback() {
+        let slab = <Slab as UnderlyingAllocator>::INIT;
+        let largest = Slab::CLASSES[NUM_SLAB_CLASSES - 1];
+        let too_large = Layout::from_size_align(largest + 1, 8).unwrap();
+        let over_aligned = Layout::from_size_align(16, 1024).unwrap();
+        for layout in [too_large, over_aligned] {
+            assert_eq!(Slab::class(layout), None);
+            unsafe {
+                let ptr = slab.alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % layout.align(), 0);
+                slab.dealloc(ptr, layout);
+            }
+            // Nothing went through the free lists.
+            assert!(free_lists_empty(&slab));
+        }
+    }
+
+    #[test]
+    fn holds_back_only_async() {
+        let heap = MnemosAlloc::<System>::with_policy(OomPolicy::Fifo);
+        let heap = std::boxed::Box::leak(std::boxed::Box::new(heap));
+        let _waiter = heap.waiters.claim(4096).unwrap();
+        let layout = Layout::new::<u64>();
+        unsafe {
+            // Synchronous allocations go ahead of the waiter...
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            heap.dealloc(ptr, layout);
+
+            // ...but async ones wait behind it, in this allocator's table.
+            let asynchronous = policy::asynchronous(None);
+            assert!(heap.alloc(layout).is_null());
+            let failed = asynchronous.failed().unwrap();
+            assert!(core::ptr::eq(failed, &heap.waiters));
+        }
+        assert_eq!(heap.stats().failed_allocs, 1);
+    }
+}
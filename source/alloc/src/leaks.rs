@@ -0,0 +1,481 @@
+//! Leak tracing
+//!
+//! With the `leak-trace` feature enabled, every live allocation made through
+//! [MnemosAlloc][crate::heap::MnemosAlloc] is recorded in a fixed-size table,
+//! along with its layout, the owner of the [quota][crate::quota] account it
+//! was charged to (the kernel task, for allocations made by tasks), and the
+//! current [Tag], if any. Entries are removed again when the allocation is
+//! freed, so the table holds everything that is currently allocated.
+//!
+//! Every allocation gets an increasing sequence number. To look for leaks,
+//! take a [mark()] before doing something that shouldn't leave anything
+//! allocated behind, and then list the allocations made since then with
+//! [for_each_since()]. Two dumps of the table can also be diffed offline by
+//! their sequence numbers.
+//!
+//! The table doesn't allocate, and allocations made while it is full (or in
+//! use) are not recorded, see [LeakStats::untracked]. Frees made while the
+//! table is in use are queued, and removed from the table the next time it is
+//! locked. The table is indexed by address, so recording an allocation and
+//! finding it again when it is freed usually takes constant time, but this
+//! grows as the table fills up, and it still adds a lock to every allocation,
+//! so this is only meant for debugging.
+//!
+//! Without the `leak-trace` feature, nothing is recorded, but the API is
+//! still available so that code can tag its allocations unconditionally.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "leak-trace")]
+use {
+    core::{alloc::Layout, sync::atomic::AtomicUsize},
+    maitake::sync::{Mutex, MutexGuard},
+};
+
+/// Whether allocations are being recorded, i.e. whether the `leak-trace`
+/// feature is enabled.
+pub const ENABLED: bool = cfg!(feature = "leak-trace");
+
+/// The maximum number of live allocations that can be recorded.
+///
+/// This must be a power of two, as the table is indexed by a hash of each
+/// allocation's address.
+pub const MAX_TRACKED: usize = 1024;
+
+/// The maximum number of frees that can be queued while the table is in use.
+pub const MAX_PENDING_FREES: usize = 16;
+
+/// A label for allocations, see [enter_tag()] and [tagged()].
+#[derive(Debug)]
+pub struct Tag(pub &'static str);
+
+/// A recorded live allocation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LiveAlloc {
+    /// The allocation's sequence number. Later allocations have larger numbers.
+    pub seq: usize,
+    /// The address of the allocation
+    pub addr: usize,
+    pub size: usize,
+    pub align: usize,
+    /// The owner of the [quota][crate::quota] account the allocation was
+    /// charged to, if any
+    pub owner: Option<u32>,
+    /// The [Tag] that was current when the allocation was made, if any
+    pub tag: Option<&'static str>,
+}
+
+/// A snapshot of the leak table's usage, see [stats()].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LeakStats {
+    /// The number of live allocations currently recorded
+    pub tracked: usize,
+    /// The number of allocations that were not recorded, because the table
+    /// was full or in use
+    pub untracked: usize,
+    /// The number of frees that were not removed from the table, because it
+    /// was in use and the queue of pending frees was full. Their entries stay
+    /// in the table until the address is allocated again.
+    pub lost_frees: usize,
+}
+
+/// Returned by [enter_tag()], restores the previous tag when dropped.
+#[must_use = "the previous tag is restored when the guard is dropped"]
+pub struct TagGuard {
+    prev: *mut Tag,
+}
+
+/// A future which tags the allocations made while polling it, see [tagged()].
+pub struct Tagged<F> {
+    tag: &'static Tag,
+    inner: F,
+}
+
+static CURRENT_TAG: AtomicPtr<Tag> = AtomicPtr::new(ptr::null_mut());
+
+/// The recorded allocations, in an open addressing hash table keyed by
+/// address, see [slot_of()].
+#[cfg(feature = "leak-trace")]
+static TABLE: Mutex<[Option<LiveAlloc>; MAX_TRACKED]> = Mutex::new([None; MAX_TRACKED]);
+
+/// The addresses of allocations freed while [TABLE] was locked, or zero.
+#[cfg(feature = "leak-trace")]
+#[allow(clippy::declare_interior_mutable_const)]
+static PENDING_FREES: [AtomicUsize; MAX_PENDING_FREES] = {
+    const EMPTY: AtomicUsize = AtomicUsize::new(0);
+    [EMPTY; MAX_PENDING_FREES]
+};
+
+#[cfg(feature = "leak-trace")]
+static NEXT_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "leak-trace")]
+static UNTRACKED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "leak-trace")]
+static LOST_FREES: AtomicUsize = AtomicUsize::new(0);
+
+/// Tag allocations with `tag` until the returned guard is dropped.
+///
+/// The tag is global, so don't hold the guard across an `.await`, or other
+/// tasks' allocations will be tagged too. Use [tagged()] for async code.
+pub fn enter_tag(tag: &'static Tag) -> TagGuard {
+    let tag = tag as *const Tag as *mut Tag;
+    TagGuard {
+        prev: CURRENT_TAG.swap(tag, Ordering::AcqRel),
+    }
+}
+
+/// Tag the allocations made by `fut` with `tag`.
+///
+/// The tag is only current while `fut` is being polled, so this can be used
+/// across `.await`s.
+pub fn tagged<F: Future>(tag: &'static Tag, fut: F) -> Tagged<F> {
+    Tagged { tag, inner: fut }
+}
+
+/// Returns the sequence number the next allocation will get.
+///
+/// Always zero without the `leak-trace` feature.
+pub fn mark() -> usize {
+    imp::mark()
+}
+
+/// Call `f` with every recorded live allocation with a sequence number of
+/// at least `seq`, in table order. Use `0` to visit every live allocation.
+///
+/// The table is not locked while `f` runs, so `f` may allocate.
+pub fn for_each_since(seq: usize, f: impl FnMut(&LiveAlloc)) {
+    imp::for_each_since(seq, f)
+}
+
+/// Returns the table's usage, or `None` if it is in use.
+pub fn stats() -> Option<LeakStats> {
+    imp::stats()
+}
+
+#[cfg(feature = "leak-trace")]
+mod imp {
+    use super::*;
+
+    pub(super) fn mark() -> usize {
+        NEXT_SEQ.load(Ordering::Acquire)
+    }
+
+    pub(super) fn for_each_since(seq: usize, mut f: impl FnMut(&LiveAlloc)) {
+        for idx in 0..MAX_TRACKED {
+            let entry = match lock() {
+                Some(table) => table[idx],
+                None => continue,
+            };
+            match entry {
+                Some(entry) if entry.seq >= seq => f(&entry),
+                _ => {}
+            }
+        }
+    }
+
+    pub(super) fn stats() -> Option<LeakStats> {
+        let table = lock()?;
+        Some(LeakStats {
+            tracked: table.iter().filter(|e| e.is_some()).count(),
+            untracked: UNTRACKED.load(Ordering::Relaxed),
+            lost_frees: LOST_FREES.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(not(feature = "leak-trace"))]
+mod imp {
+    use super::*;
+
+    pub(super) fn mark() -> usize {
+        0
+    }
+
+    pub(super) fn for_each_since(_seq: usize, _f: impl FnMut(&LiveAlloc)) {}
+
+    pub(super) fn stats() -> Option<LeakStats> {
+        Some(LeakStats {
+            tracked: 0,
+            untracked: 0,
+            lost_frees: 0,
+        })
+    }
+}
+
+/// Record a new allocation.
+#[cfg(feature = "leak-trace")]
+pub(crate) fn record(ptr: *mut u8, layout: Layout) {
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::AcqRel);
+    let tag = CURRENT_TAG.load(Ordering::Acquire);
+    let entry = LiveAlloc {
+        seq,
+        addr: ptr as usize,
+        size: layout.size(),
+        align: layout.align(),
+        owner: crate::quota::current_owner(),
+        // SAFETY: only ever set from a `&'static Tag`
+        tag: unsafe { tag.as_ref() }.map(|tag| tag.0),
+    };
+    let recorded = lock().and_then(|mut table| insert(&mut table, entry));
+    if recorded.is_none() {
+        UNTRACKED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Remove a freed allocation.
+#[cfg(feature = "leak-trace")]
+pub(crate) fn forget(ptr: *mut u8) {
+    let addr = ptr as usize;
+    match lock() {
+        Some(mut table) => remove(&mut table, addr),
+        // Remove it the next time the table is locked, before the address
+        // can be recorded again.
+        None => {
+            let queued = PENDING_FREES.iter().any(|pending| {
+                pending
+                    .compare_exchange(0, addr, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            });
+            if !queued {
+                LOST_FREES.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Lock the table, and remove the frees queued while it was locked.
+#[cfg(feature = "leak-trace")]
+fn lock() -> Option<MutexGuard<'static, [Option<LiveAlloc>; MAX_TRACKED]>> {
+    let mut table = TABLE.try_lock()?;
+    for pending in PENDING_FREES.iter() {
+        match pending.swap(0, Ordering::AcqRel) {
+            0 => {}
+            addr => remove(&mut table, addr),
+        }
+    }
+    Some(table)
+}
+
+/// The slot where the table's probe for `addr` starts.
+///
+/// Allocations are at least word aligned, so the low bits of their addresses
+/// are mostly the same. This uses the high bits of a Fibonacci hash instead,
+/// which depend on every bit of the address.
+#[cfg(feature = "leak-trace")]
+fn slot_of(addr: usize) -> usize {
+    const FIB: usize = (0x9E37_79B9_7F4A_7C15_u64 >> (64 - usize::BITS)) as usize;
+    addr.wrapping_mul(FIB) >> (usize::BITS - MAX_TRACKED.trailing_zeros())
+}
+
+/// Insert `entry` into the table, replacing any stale entry for the same
+/// address, whose free was lost. Returns `None` if the table is full.
+#[cfg(feature = "leak-trace")]
+fn insert(table: &mut [Option<LiveAlloc>; MAX_TRACKED], entry: LiveAlloc) -> Option<()> {
+    let start = slot_of(entry.addr);
+    for probe in 0..MAX_TRACKED {
+        let slot = &mut table[(start + probe) % MAX_TRACKED];
+        match slot {
+            Some(e) if e.addr != entry.addr => {}
+            _ => {
+                *slot = Some(entry);
+                return Some(());
+            }
+        }
+    }
+    None
+}
+
+/// Remove the entry for `addr` from the table, if there is one.
+#[cfg(feature = "leak-trace")]
+fn remove(table: &mut [Option<LiveAlloc>; MAX_TRACKED], addr: usize) {
+    let start = slot_of(addr);
+    let Some(mut hole) = (0..MAX_TRACKED)
+        .map(|probe| (start + probe) % MAX_TRACKED)
+        .take_while(|&idx| table[idx].is_some())
+        .find(|&idx| matches!(table[idx], Some(e) if e.addr == addr))
+    else {
+        return;
+    };
+    table[hole] = None;
+    // Move later entries of the probe sequence back into the hole, so that
+    // lookups don't stop early at it.
+    let mut idx = hole;
+    loop {
+        idx = (idx + 1) % MAX_TRACKED;
+        let Some(entry) = table[idx] else {
+            return;
+        };
+        // The distance from each slot to the entry's starting slot.
+        let home = slot_of(entry.addr);
+        let dist = |slot: usize| (slot + MAX_TRACKED - home) % MAX_TRACKED;
+        if dist(hole) < dist(idx) {
+            table[hole] = Some(entry);
+            table[idx] = None;
+            hole = idx;
+        }
+    }
+}
+
+// TagGuard
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        CURRENT_TAG.store(self.prev, Ordering::Release);
+    }
+}
+
+// Tagged
+
+impl<F: Future> Future for Tagged<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _tag = enter_tag(self.tag);
+        // SAFETY: `inner` is structurally pinned, it is never moved out of.
+        unsafe { self.map_unchecked_mut(|this| &mut this.inner) }.poll(cx)
+    }
+}
+
+#[cfg(all(test, feature = "use-std", feature = "leak-trace"))]
+mod test {
+    use super::*;
+
+    /// Addresses that won't be recorded by the allocations of other tests.
+    fn addr(n: usize) -> *mut u8 {
+        (usize::MAX - n * 16) as *mut u8
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    /// The recorded allocations made since `seq`, out of those made by
+    /// these tests.
+    fn since(seq: usize) -> std::vec::Vec<LiveAlloc> {
+        let mut allocs = std::vec::Vec::new();
+        for_each_since(seq, |alloc| {
+            if alloc.addr > usize::MAX - 1024 * 1024 {
+                allocs.push(*alloc);
+            }
+        });
+        allocs.sort_by_key(|alloc| alloc.seq);
+        allocs
+    }
+
+    #[test]
+    fn record_and_forget() {
+        let _lock = crate::test_lock();
+        let seq = mark();
+        static TAG: Tag = Tag("leak test");
+        {
+            let _tag = enter_tag(&TAG);
+            record(addr(1), layout(24));
+        }
+        let allocs = since(seq);
+        assert_eq!(allocs.len(), 1);
+        assert!(allocs[0].seq >= seq);
+        assert_eq!(allocs[0].addr, addr(1) as usize);
+        assert_eq!((allocs[0].size, allocs[0].align), (24, 8));
+        assert_eq!(allocs[0].tag, Some("leak test"));
+
+        forget(addr(1));
+        assert!(since(seq).is_empty());
+    }
+
+    #[test]
+    fn diff_since_mark() {
+        let _lock = crate::test_lock();
+        let before = mark();
+        record(addr(1), layout(8));
+        let after = mark();
+        assert!(after > before);
+        record(addr(2), layout(16));
+        record(addr(3), layout(32));
+        forget(addr(2));
+
+        // Only what is still live, and was allocated after the mark.
+        let addrs = |seq| {
+            since(seq)
+                .iter()
+                .map(|alloc| alloc.addr)
+                .collect::<std::vec::Vec<_>>()
+        };
+        assert_eq!(addrs(after), [addr(3) as usize]);
+        assert_eq!(addrs(before), [addr(1) as usize, addr(3) as usize]);
+
+        forget(addr(1));
+        forget(addr(3));
+        assert!(since(before).is_empty());
+    }
+
+    #[test]
+    fn colliding_addresses() {
+        let _lock = crate::test_lock();
+        let seq = mark();
+        // Enough entries that many of them share probe sequences.
+        for n in 0..MAX_TRACKED / 2 {
+            record(addr(n), layout(8));
+        }
+        assert_eq!(since(seq).len(), MAX_TRACKED / 2);
+        // Removing every other entry must not hide the rest from lookups.
+        for n in (0..MAX_TRACKED / 2).step_by(2) {
+            forget(addr(n));
+        }
+        let allocs = since(seq);
+        assert_eq!(allocs.len(), MAX_TRACKED / 4);
+        for n in (1..MAX_TRACKED / 2).step_by(2) {
+            forget(addr(n));
+        }
+        assert!(since(seq).is_empty());
+    }
+
+    #[test]
+    fn contended() {
+        let _lock = crate::test_lock();
+        let seq = mark();
+        record(addr(1), layout(8));
+        let before = stats().unwrap();
+
+        {
+            let _table = TABLE.try_lock().unwrap();
+            // Allocations can't be recorded while the table is in use...
+            record(addr(2), layout(8));
+            // ...but frees are queued...
+            forget(addr(1));
+            assert!(stats().is_none());
+        }
+        assert_eq!(stats().unwrap().untracked, before.untracked + 1);
+        // ...and removed the next time the table is locked.
+        assert!(since(seq).is_empty());
+
+        // Once the queue is full, frees are lost, until the address is
+        // allocated again.
+        for n in 0..=MAX_PENDING_FREES {
+            record(addr(n), layout(8));
+        }
+        {
+            let _table = TABLE.try_lock().unwrap();
+            for n in 0..=MAX_PENDING_FREES {
+                forget(addr(n));
+            }
+        }
+        assert_eq!(stats().unwrap().lost_frees, before.lost_frees + 1);
+        let lost = since(seq);
+        assert_eq!(lost.len(), 1);
+        assert_eq!(lost[0].addr, addr(MAX_PENDING_FREES) as usize);
+        record(addr(MAX_PENDING_FREES), layout(16));
+        let reused = since(seq);
+        assert_eq!(reused.len(), 1);
+        assert_eq!(reused[0].size, 16);
+        forget(addr(MAX_PENDING_FREES));
+        assert!(since(seq).is_empty());
+    }
+}
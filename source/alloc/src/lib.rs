@@ -5,8 +5,75 @@
 //! types that are intended for use in mnemos' kernel and services.
 
 #![cfg_attr(not(feature = "use-std"), no_std)]
+// For `Arc::try_new_uninit()`
+#![feature(allocator_api, new_uninit)]
 
 pub mod containers;
 pub mod heap;
+pub mod leaks;
+pub mod policy;
+pub mod quota;
 
 extern crate alloc;
+
+/// Serializes tests which use the global quota account and waiter tables.
+#[cfg(all(test, feature = "use-std"))]
+pub(crate) fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    // A failed test poisons the lock, but doesn't leave anything locked.
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The global allocator for tests, which counts the allocations made by each
+/// thread, and can be told to fail them.
+#[cfg(all(test, feature = "use-std"))]
+pub(crate) mod test_alloc {
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        cell::Cell,
+        ptr::null_mut,
+    };
+    use std::alloc::System;
+
+    std::thread_local! {
+        static ALLOCS: Cell<usize> = const { Cell::new(0) };
+        static FAIL: Cell<bool> = const { Cell::new(false) };
+    }
+
+    struct TestAlloc;
+
+    #[global_allocator]
+    static ALLOC: TestAlloc = TestAlloc;
+
+    unsafe impl GlobalAlloc for TestAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if FAIL.try_with(Cell::get).unwrap_or(false) {
+                return null_mut();
+            }
+            let _ = ALLOCS.try_with(|allocs| allocs.set(allocs.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    /// Returns the number of allocations this thread has made.
+    pub(crate) fn allocs() -> usize {
+        ALLOCS.with(Cell::get)
+    }
+
+    /// Fail all of this thread's allocations while `f` runs.
+    pub(crate) fn failing<R>(f: impl FnOnce() -> R) -> R {
+        struct Reset;
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                FAIL.with(|fail| fail.set(false));
+            }
+        }
+        FAIL.with(|fail| fail.set(true));
+        let _reset = Reset;
+        f()
+    }
+}
@@ -0,0 +1,620 @@
+//! Out of memory policies
+//!
+//! When [async allocations][crate::heap::alloc()] can't be served, they wait
+//! in a fixed-size table of waiters until memory is freed. Each
+//! [MnemosAlloc][crate::heap::MnemosAlloc] has its own table, and the
+//! [OomPolicy] it was constructed with decides which waiters are woken when
+//! memory is freed, and whether other async allocations may go ahead of them.
+//!
+//! Only async allocations are ever held back by a policy. Other allocations
+//! (through [alloc::boxed::Box], the `try_` constructors in
+//! [containers][crate::containers], and so on) can't wait, so they are tried
+//! right away, even if that means going ahead of the waiters.
+//!
+//! Except with [OomPolicy::Inhibit], waiters are woken one at a time: when a
+//! woken waiter's allocation succeeds, the next waiter chosen by the policy
+//! is woken, so that a large deallocation can serve several waiters without
+//! waking all of them at once. If the waiter table is full, further async
+//! allocations wait for any deallocation instead, as with [OomPolicy::Inhibit].
+//!
+//! If a waiter gives up before its allocation succeeds (e.g. because it was
+//! [cancelled][crate::heap::alloc_or_cancel()], or its task was killed), the
+//! next waiter is woken in its place, so a wakeup is never lost.
+//!
+//! With the `use-std` feature, the [priority][enter_priority()] and whether
+//! an allocation is async are tracked per thread. Otherwise, they are global.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use maitake::sync::WaitCell;
+
+/// Which waiting allocations are served first, see the [module level docs][self].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OomPolicy {
+    /// Once an allocation fails, all allocations fail until the next
+    /// deallocation, which wakes every waiter to try again.
+    ///
+    /// This prevents large allocations from being starved, but delays small
+    /// allocations that could succeed, and every deallocation wakes every
+    /// waiter.
+    Inhibit,
+    /// Waiters are served in the order they started waiting, whatever their
+    /// size. Other async allocations wait behind them while anyone is waiting.
+    Fifo,
+    /// The smallest waiting allocation is served first. Other allocations
+    /// are never held back, so a large allocation may wait for a long time if
+    /// memory is tight.
+    SmallFirst,
+    /// Waiters with the highest priority (see [enter_priority()]) are served
+    /// first, and waiters with the same priority in the order they started
+    /// waiting. Other async allocations wait behind them while anyone is
+    /// waiting.
+    Priority,
+}
+
+/// Returned by [enter_priority()], restores the previous priority when dropped.
+#[must_use = "the previous priority is restored when the guard is dropped"]
+pub struct PriorityGuard {
+    prev: u8,
+}
+
+/// The maximum number of async allocations that can wait in a waiter table.
+pub const MAX_WAITERS: usize = 32;
+
+/// The waiters of a [MnemosAlloc][crate::heap::MnemosAlloc], and the
+/// [OomPolicy] they are served by.
+pub(crate) struct WaiterTable {
+    policy: OomPolicy,
+    waiters: [Waiter; MAX_WAITERS],
+    /// The number of claimed slots in `waiters`.
+    waiting: AtomicUsize,
+}
+
+struct Waiter {
+    /// The waiter's ticket, or zero if the slot is free. Tickets increase
+    /// in the order waiters started waiting.
+    ticket: AtomicUsize,
+    size: AtomicUsize,
+    priority: AtomicU8,
+    cell: WaitCell,
+}
+
+/// A claimed slot in a waiter table, released when dropped.
+pub(crate) struct WaiterSlot {
+    table: &'static WaiterTable,
+    idx: usize,
+    ticket: usize,
+}
+
+/// Returned by [asynchronous()], restores the previous state when dropped.
+pub(crate) struct Async {
+    prev_async: bool,
+    prev_retrying: usize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_WAITER: Waiter = Waiter {
+    ticket: AtomicUsize::new(0),
+    size: AtomicUsize::new(0),
+    priority: AtomicU8::new(0),
+    cell: WaitCell::new(),
+};
+
+/// Tickets are unique across all waiter tables, so that a retry can't be
+/// mistaken for one of another table's waiters.
+static NEXT_TICKET: AtomicUsize = AtomicUsize::new(1);
+
+/// The priority of the current caller, whether its allocations are async,
+/// the ticket of the waiter whose allocation it is retrying (or zero), and
+/// the waiter table of the allocator that failed its last async allocation.
+#[cfg(not(feature = "use-std"))]
+mod local {
+    use super::WaiterTable;
+    use core::{
+        ptr::null_mut,
+        sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering},
+    };
+
+    static PRIORITY: AtomicU8 = AtomicU8::new(0);
+    static ASYNC: AtomicBool = AtomicBool::new(false);
+    static RETRYING: AtomicUsize = AtomicUsize::new(0);
+    static FAILED: AtomicPtr<WaiterTable> = AtomicPtr::new(null_mut());
+
+    pub(super) fn priority() -> u8 {
+        PRIORITY.load(Ordering::Acquire)
+    }
+
+    pub(super) fn replace_priority(priority: u8) -> u8 {
+        PRIORITY.swap(priority, Ordering::AcqRel)
+    }
+
+    pub(super) fn is_async() -> bool {
+        ASYNC.load(Ordering::Acquire)
+    }
+
+    pub(super) fn replace_async(asynchronous: bool) -> bool {
+        ASYNC.swap(asynchronous, Ordering::AcqRel)
+    }
+
+    pub(super) fn retrying() -> usize {
+        RETRYING.load(Ordering::Acquire)
+    }
+
+    pub(super) fn replace_retrying(ticket: usize) -> usize {
+        RETRYING.swap(ticket, Ordering::AcqRel)
+    }
+
+    pub(super) fn replace_failed(table: *const WaiterTable) -> *const WaiterTable {
+        FAILED.swap(table.cast_mut(), Ordering::AcqRel)
+    }
+}
+
+/// As above, but per thread.
+#[cfg(feature = "use-std")]
+mod local {
+    use super::WaiterTable;
+    use core::{cell::Cell, ptr::null};
+
+    std::thread_local! {
+        static PRIORITY: Cell<u8> = const { Cell::new(0) };
+        static ASYNC: Cell<bool> = const { Cell::new(false) };
+        static RETRYING: Cell<usize> = const { Cell::new(0) };
+        static FAILED: Cell<*const WaiterTable> = const { Cell::new(null()) };
+    }
+
+    pub(super) fn priority() -> u8 {
+        PRIORITY.try_with(Cell::get).unwrap_or(0)
+    }
+
+    pub(super) fn replace_priority(priority: u8) -> u8 {
+        PRIORITY
+            .try_with(|current| current.replace(priority))
+            .unwrap_or(0)
+    }
+
+    pub(super) fn is_async() -> bool {
+        ASYNC.try_with(Cell::get).unwrap_or(false)
+    }
+
+    pub(super) fn replace_async(asynchronous: bool) -> bool {
+        ASYNC
+            .try_with(|current| current.replace(asynchronous))
+            .unwrap_or(false)
+    }
+
+    pub(super) fn retrying() -> usize {
+        RETRYING.try_with(Cell::get).unwrap_or(0)
+    }
+
+    pub(super) fn replace_retrying(ticket: usize) -> usize {
+        RETRYING
+            .try_with(|current| current.replace(ticket))
+            .unwrap_or(0)
+    }
+
+    pub(super) fn replace_failed(table: *const WaiterTable) -> *const WaiterTable {
+        FAILED
+            .try_with(|current| current.replace(table))
+            .unwrap_or(null())
+    }
+}
+
+/// Set the priority of allocations made until the returned guard is dropped.
+///
+/// Higher values are served first under [OomPolicy::Priority], and the
+/// priority is ignored by the other policies. The kernel sets the priority of
+/// each task while polling it.
+pub fn enter_priority(priority: u8) -> PriorityGuard {
+    PriorityGuard {
+        prev: local::replace_priority(priority),
+    }
+}
+
+/// Mark allocations made until the returned guard is dropped as async, and
+/// as the retry of `waiter`'s allocation, if any.
+///
+/// Async allocations may be held back by the allocator's [OomPolicy], and if
+/// one fails, [Async::failed()] returns the waiter table to wait in.
+pub(crate) fn asynchronous(waiter: Option<&WaiterSlot>) -> Async {
+    local::replace_failed(core::ptr::null());
+    Async {
+        prev_async: local::replace_async(true),
+        prev_retrying: local::replace_retrying(waiter.map_or(0, |waiter| waiter.ticket)),
+    }
+}
+
+// PriorityGuard
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        local::replace_priority(self.prev);
+    }
+}
+
+// Async
+
+impl Async {
+    /// Returns the waiter table of the allocator that failed an allocation
+    /// since the guard was created, if any.
+    pub(crate) fn failed(&self) -> Option<&'static WaiterTable> {
+        let table = local::replace_failed(core::ptr::null());
+        // SAFETY: tables are only recorded by async allocations, which are
+        // always made through the global allocator, so the table lives for
+        // the rest of the program.
+        unsafe { table.as_ref() }
+    }
+}
+
+impl Drop for Async {
+    fn drop(&mut self) {
+        local::replace_async(self.prev_async);
+        local::replace_retrying(self.prev_retrying);
+    }
+}
+
+// WaiterTable
+
+impl WaiterTable {
+    pub(crate) const fn new(policy: OomPolicy) -> Self {
+        Self {
+            policy,
+            waiters: [EMPTY_WAITER; MAX_WAITERS],
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns `true` if an allocation should fail without trying the
+    /// underlying allocator.
+    pub(crate) fn holds_back(&self, inhibited: bool) -> bool {
+        match self.policy {
+            OomPolicy::Inhibit => inhibited,
+            // Only new async allocations queue up behind the waiters. A
+            // waiter's own retry must go ahead, and other allocations can't
+            // wait.
+            OomPolicy::Fifo | OomPolicy::Priority => {
+                local::is_async()
+                    && local::retrying() == 0
+                    && (inhibited || self.waiting.load(Ordering::Acquire) != 0)
+            }
+            OomPolicy::SmallFirst => false,
+        }
+    }
+
+    /// Called when an allocation fails. If it was async, this is the table
+    /// it should wait in.
+    pub(crate) fn failed(&self) {
+        if local::is_async() {
+            local::replace_failed(self);
+        }
+    }
+
+    /// Called after a successful allocation. If it was a waiter's retry, the
+    /// waiter's slot is released and the next waiter is woken.
+    pub(crate) fn allocated(&self) {
+        let ticket = local::retrying();
+        if ticket == 0 {
+            return;
+        }
+        let released = self.waiters.iter().any(|waiter| {
+            waiter
+                .ticket
+                .compare_exchange(ticket, 0, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+        if released {
+            self.waiting.fetch_sub(1, Ordering::AcqRel);
+            // With `Inhibit`, the remaining waiters were all woken already.
+            if self.policy != OomPolicy::Inhibit {
+                self.wake_next();
+            }
+        }
+    }
+
+    /// Called after a deallocation. `was_inhibited` is `true` if allocations
+    /// were inhibited until now.
+    pub(crate) fn deallocated(&self, was_inhibited: bool) {
+        match self.policy {
+            OomPolicy::Inhibit if was_inhibited => {
+                for waiter in self.waiters.iter() {
+                    if waiter.ticket.load(Ordering::Acquire) != 0 {
+                        waiter.cell.wake();
+                    }
+                }
+            }
+            OomPolicy::Inhibit => {}
+            _ => self.wake_next(),
+        }
+    }
+
+    /// Wake the waiter the policy serves next, if there is one.
+    fn wake_next(&self) {
+        if self.waiting.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let key = |w: &Waiter| -> (usize, usize) {
+            let ticket = w.ticket.load(Ordering::Acquire);
+            match self.policy {
+                OomPolicy::SmallFirst => (w.size.load(Ordering::Acquire), ticket),
+                OomPolicy::Priority => {
+                    let priority = usize::from(w.priority.load(Ordering::Acquire));
+                    (usize::from(u8::MAX) - priority, ticket)
+                }
+                OomPolicy::Inhibit | OomPolicy::Fifo => (ticket, 0),
+            }
+        };
+        let next = self
+            .waiters
+            .iter()
+            .filter(|w| w.ticket.load(Ordering::Acquire) != 0)
+            .min_by_key(|w| key(w));
+        if let Some(waiter) = next {
+            waiter.cell.wake();
+        }
+    }
+
+    /// Claim a slot in the table for an allocation of `size` bytes, at the
+    /// current priority. Returns `None` if the table is full.
+    pub(crate) fn claim(&'static self, size: usize) -> Option<WaiterSlot> {
+        let ticket = NEXT_TICKET.fetch_add(1, Ordering::Relaxed);
+        // Skip zero, which marks a free slot.
+        let ticket = if ticket == 0 {
+            NEXT_TICKET.fetch_add(1, Ordering::Relaxed)
+        } else {
+            ticket
+        };
+        let idx = self.waiters.iter().position(|w| {
+            w.ticket
+                .compare_exchange(0, ticket, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })?;
+        let waiter = &self.waiters[idx];
+        waiter.size.store(size, Ordering::Release);
+        waiter.priority.store(local::priority(), Ordering::Release);
+        self.waiting.fetch_add(1, Ordering::AcqRel);
+        Some(WaiterSlot {
+            table: self,
+            idx,
+            ticket,
+        })
+    }
+}
+
+// WaiterSlot
+
+impl WaiterSlot {
+    /// Wait to be woken by the [OomPolicy].
+    pub(crate) async fn wait(&self) {
+        let _ = self.table.waiters[self.idx].cell.wait().await;
+    }
+}
+
+impl Drop for WaiterSlot {
+    fn drop(&mut self) {
+        // The slot was already released if the allocation succeeded.
+        let released = self.table.waiters[self.idx].ticket.compare_exchange(
+            self.ticket,
+            0,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        if released.is_ok() {
+            self.table.waiting.fetch_sub(1, Ordering::AcqRel);
+            // This waiter may have been woken to retry, and given up instead.
+            // Pass the wakeup on, or the other waiters would keep waiting
+            // for an unrelated deallocation. With `Inhibit`, they were all
+            // woken already.
+            if self.table.policy != OomPolicy::Inhibit {
+                self.table.wake_next();
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::*;
+    use core::{
+        future::Future,
+        pin::{pin, Pin},
+    };
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    #[derive(Default)]
+    struct Woken(AtomicBool);
+
+    impl Wake for Woken {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl Woken {
+        fn get(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Poll a waiter's `wait()` future once, so that it can be woken.
+    fn register(wait: Pin<&mut impl Future>) -> Arc<Woken> {
+        let woken = Arc::new(Woken::default());
+        let waker = Waker::from(woken.clone());
+        let poll = wait.poll(&mut Context::from_waker(&waker));
+        assert!(matches!(poll, Poll::Pending));
+        woken
+    }
+
+    fn table(policy: OomPolicy) -> &'static WaiterTable {
+        std::boxed::Box::leak(std::boxed::Box::new(WaiterTable::new(policy)))
+    }
+
+    /// Claim waiters with the given sizes and priorities, in order, and
+    /// return the order in which `policy` serves them, by index.
+    fn serve_order(policy: OomPolicy, waiters: &[(usize, u8)]) -> std::vec::Vec<usize> {
+        let table = table(policy);
+        let slots = waiters
+            .iter()
+            .map(|&(size, priority)| {
+                let _priority = enter_priority(priority);
+                table.claim(size).unwrap()
+            })
+            .collect::<std::vec::Vec<_>>();
+        let mut waits = slots
+            .iter()
+            .map(|slot| std::boxed::Box::pin(slot.wait()))
+            .collect::<std::vec::Vec<_>>();
+        let woken = waits
+            .iter_mut()
+            .map(|wait| register(wait.as_mut()))
+            .collect::<std::vec::Vec<_>>();
+
+        let mut order = std::vec::Vec::new();
+        // The first waiter is woken by a deallocation, and each woken
+        // waiter's successful retry wakes the next one.
+        table.deallocated(false);
+        while order.len() < slots.len() {
+            let next = (0..slots.len())
+                .filter(|idx| !order.contains(idx) && woken[*idx].get())
+                .collect::<std::vec::Vec<_>>();
+            assert_eq!(next.len(), 1, "{policy:?} woke {next:?} after {order:?}");
+            let idx = next[0];
+            order.push(idx);
+            let _retry = asynchronous(Some(&slots[idx]));
+            table.allocated();
+        }
+        assert_eq!(table.waiting.load(Ordering::Acquire), 0);
+        order
+    }
+
+    #[test]
+    fn fifo_order() {
+        let order = serve_order(OomPolicy::Fifo, &[(64, 0), (16, 1), (256, 0), (32, 1)]);
+        assert_eq!(order, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn small_first_order() {
+        let order = serve_order(
+            OomPolicy::SmallFirst,
+            &[(64, 0), (16, 1), (256, 0), (16, 0), (32, 1)],
+        );
+        // Equal sizes are served in the order they started waiting.
+        assert_eq!(order, [1, 3, 4, 0, 2]);
+    }
+
+    #[test]
+    fn priority_order() {
+        let order = serve_order(
+            OomPolicy::Priority,
+            &[(64, 0), (16, 1), (256, 0), (32, 2), (8, 1)],
+        );
+        // Equal priorities are served in the order they started waiting.
+        assert_eq!(order, [3, 1, 4, 0, 2]);
+    }
+
+    #[test]
+    fn holds_back_async_while_waiting() {
+        for policy in [OomPolicy::Fifo, OomPolicy::Priority] {
+            let table = table(policy);
+            {
+                let _async = asynchronous(None);
+                assert!(!table.holds_back(false), "{policy:?}");
+            }
+            let waiter = table.claim(16).unwrap();
+            // Synchronous allocations can't wait, so they go ahead...
+            assert!(!table.holds_back(false), "{policy:?}");
+            // ...but other async allocations must not jump the queue...
+            {
+                let _async = asynchronous(None);
+                assert!(table.holds_back(false), "{policy:?}");
+            }
+            // ...except for the waiter's own retry.
+            {
+                let _retry = asynchronous(Some(&waiter));
+                assert!(!table.holds_back(true), "{policy:?}");
+            }
+            drop(waiter);
+            let _async = asynchronous(None);
+            assert!(!table.holds_back(false), "{policy:?}");
+            assert!(table.holds_back(true), "{policy:?}");
+        }
+
+        let table = table(OomPolicy::SmallFirst);
+        let _waiter = table.claim(16).unwrap();
+        let _async = asynchronous(None);
+        assert!(!table.holds_back(true));
+
+        let table = self::table(OomPolicy::Inhibit);
+        assert!(table.holds_back(true));
+        assert!(!table.holds_back(false));
+    }
+
+    #[test]
+    fn failed_table_only_recorded_when_async() {
+        let table = table(OomPolicy::Fifo);
+        table.failed();
+        let asynchronous = asynchronous(None);
+        assert!(asynchronous.failed().is_none());
+        table.failed();
+        let failed = asynchronous.failed().unwrap();
+        assert!(core::ptr::eq(failed, table));
+        // Taking the failed table clears it.
+        assert!(asynchronous.failed().is_none());
+    }
+
+    #[test]
+    fn tables_are_independent() {
+        let fifo = table(OomPolicy::Fifo);
+        let small_first = table(OomPolicy::SmallFirst);
+        let waiter = small_first.claim(16).unwrap();
+        let mut wait = pin!(waiter.wait());
+        let woken = register(wait.as_mut());
+
+        // Waiters in one allocator hold nothing back in another...
+        {
+            let _async = asynchronous(None);
+            assert!(!fifo.holds_back(false));
+        }
+        // ...and aren't woken by its deallocations...
+        fifo.deallocated(true);
+        assert!(!woken.get());
+        // ...or released by its retries.
+        {
+            let _retry = asynchronous(Some(&waiter));
+            fifo.allocated();
+        }
+        assert_eq!(small_first.waiting.load(Ordering::Acquire), 1);
+        small_first.deallocated(false);
+        assert!(woken.get());
+    }
+
+    #[test]
+    fn cancelled_waiter_passes_wakeup_on() {
+        for policy in [OomPolicy::Fifo, OomPolicy::SmallFirst, OomPolicy::Priority] {
+            let table = table(policy);
+            // The head is first, smallest, and of equal priority, so it is
+            // served first by every policy.
+            let head = table.claim(16).unwrap();
+            let next = table.claim(32).unwrap();
+            let mut next_wait = pin!(next.wait());
+            let next_woken = register(next_wait.as_mut());
+
+            {
+                let head = head;
+                let mut head_wait = pin!(head.wait());
+                let head_woken = register(head_wait.as_mut());
+
+                // A deallocation wakes only the head...
+                table.deallocated(false);
+                assert!(head_woken.get(), "{policy:?}");
+                assert!(!next_woken.get(), "{policy:?}");
+                // ...which is dropped before it retries.
+            }
+
+            assert!(next_woken.get(), "{policy:?}");
+            assert_eq!(table.waiting.load(Ordering::Acquire), 1);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+//! Per-owner heap accounting and quotas
+//!
+//! Every allocation made through [MnemosAlloc][crate::heap::MnemosAlloc] is
+//! charged to the *current* [Account], if one has been [entered][Account::enter].
+//! The kernel enters each task's account while polling it, so allocations are
+//! charged to the task that made them, and freeing memory credits the account
+//! it was charged to, no matter which task frees it.
+//!
+//! An account may have a limit, which is only enforced for allocations that
+//! can cope with failing: [async allocations][crate::heap::alloc()] wait until
+//! the account has enough room again, and the `try_` constructors in
+//! [containers][crate::containers] return an error. Unlike running out of
+//! memory, this does not [inhibit][crate::heap::MnemosAlloc] allocations for
+//! other owners.
+//!
+//! Other allocations (such as through [alloc::boxed::Box] or
+//! [alloc::vec::Vec]) are charged to the account, but never denied, as a
+//! failed allocation there would panic the whole kernel. An account can
+//! therefore go over its limit, after which its async and `try_` allocations
+//! wait or fail until it frees enough memory.
+//!
+//! With the `use-std` feature, the current account is per thread, so that
+//! on a hosted platform such as melpomene, allocations made by other threads
+//! while a task is being polled aren't charged to that task's account.
+//! Otherwise, there is only one current account.
+//!
+//! Accounts are kept in a fixed-size table, so accounting never allocates. If
+//! the table is full, [Account::open()] fails, and the owner's allocations
+//! are simply not accounted for.
+//!
+//! To find the account to credit when memory is freed, every allocation is
+//! preceded by a small tag, which costs a few bytes per allocation. So
+//! accounting is only done with the `quota` feature. Without it, accounts can
+//! still be opened, but nothing is charged to them, and limits are never
+//! enforced.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use maitake::sync::WaitQueue;
+
+/// Whether allocations are charged to accounts, i.e. whether the `quota`
+/// feature is enabled.
+pub const ENABLED: bool = cfg!(feature = "quota");
+
+/// The maximum number of accounts that can be open at once.
+pub const MAX_ACCOUNTS: usize = 64;
+
+/// A handle to an open account, see the [module level docs][self].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Account {
+    tag: u32,
+}
+
+/// A snapshot of an [Account]'s usage.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Usage {
+    /// The owner ID the account was opened with
+    pub owner: u32,
+    /// Bytes currently allocated by the owner
+    pub allocated: usize,
+    /// The most bytes the owner has had allocated at once
+    pub peak_allocated: usize,
+    /// The account's limit, in bytes, if any
+    pub limit: Option<usize>,
+    /// The number of allocations which failed because of the limit
+    pub denied: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum QuotaError {
+    /// All [MAX_ACCOUNTS] accounts are in use
+    NoFreeAccounts,
+}
+
+/// Returned by [Account::enter()], leaves the account when dropped.
+#[must_use = "the account is left again when the guard is dropped"]
+pub struct Entered {
+    prev: u32,
+}
+
+/// Returned by [enforce()], restores the previous setting when dropped.
+pub(crate) struct Enforced {
+    prev: bool,
+}
+
+struct Slot {
+    /// The generation of the account using this slot, or zero if the slot
+    /// is free. Allocations are tagged with the generation, so that freeing
+    /// them after the account was closed doesn't credit a different account
+    /// that reused the slot.
+    generation: AtomicU32,
+    owner: AtomicU32,
+    allocated: AtomicUsize,
+    peak_allocated: AtomicUsize,
+    /// `usize::MAX` if there is no limit
+    limit: AtomicUsize,
+    denied: AtomicUsize,
+}
+
+/// Tags are the slot index in the low bits, and the slot's generation in
+/// the high bits. Generations start at one, so a tag is never zero, which
+/// means "no account".
+const INDEX_BITS: u32 = 8;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const NO_ACCOUNT: u32 = 0;
+const NO_LIMIT: usize = usize::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    generation: AtomicU32::new(0),
+    owner: AtomicU32::new(0),
+    allocated: AtomicUsize::new(0),
+    peak_allocated: AtomicUsize::new(0),
+    limit: AtomicUsize::new(NO_LIMIT),
+    denied: AtomicUsize::new(0),
+};
+
+static SLOTS: [Slot; MAX_ACCOUNTS] = [EMPTY_SLOT; MAX_ACCOUNTS];
+
+/// Generation counter, shared by all slots.
+static NEXT_GENERATION: AtomicU32 = AtomicU32::new(1);
+
+/// Tasks waiting for an account over its limit to free some memory.
+static QUOTA_WAITER: WaitQueue = WaitQueue::new();
+
+/// The tag of the account that allocations are currently charged to, and
+/// whether allocations over its limit are denied, see [enforce()].
+#[cfg(not(feature = "use-std"))]
+mod local {
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    static CURRENT: AtomicU32 = AtomicU32::new(super::NO_ACCOUNT);
+    static ENFORCE: AtomicBool = AtomicBool::new(false);
+
+    pub(super) fn current() -> u32 {
+        CURRENT.load(Ordering::Acquire)
+    }
+
+    pub(super) fn replace_current(tag: u32) -> u32 {
+        CURRENT.swap(tag, Ordering::AcqRel)
+    }
+
+    pub(super) fn enforcing() -> bool {
+        ENFORCE.load(Ordering::Acquire)
+    }
+
+    pub(super) fn replace_enforcing(enforce: bool) -> bool {
+        ENFORCE.swap(enforce, Ordering::AcqRel)
+    }
+}
+
+/// As above, but per thread.
+///
+/// Allocations can happen while the thread locals are being destroyed, in
+/// which case there is no current account, and nothing is enforced.
+#[cfg(feature = "use-std")]
+mod local {
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static CURRENT: Cell<u32> = const { Cell::new(super::NO_ACCOUNT) };
+        static ENFORCE: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub(super) fn current() -> u32 {
+        CURRENT.try_with(Cell::get).unwrap_or(super::NO_ACCOUNT)
+    }
+
+    pub(super) fn replace_current(tag: u32) -> u32 {
+        CURRENT
+            .try_with(|current| current.replace(tag))
+            .unwrap_or(super::NO_ACCOUNT)
+    }
+
+    pub(super) fn enforcing() -> bool {
+        ENFORCE.try_with(Cell::get).unwrap_or(false)
+    }
+
+    pub(super) fn replace_enforcing(enforce: bool) -> bool {
+        ENFORCE
+            .try_with(|enforcing| enforcing.replace(enforce))
+            .unwrap_or(false)
+    }
+}
+
+// Account
+
+impl Account {
+    /// Open a new account for `owner`, with an optional `limit` in bytes.
+    pub fn open(owner: u32, limit: Option<usize>) -> Result<Self, QuotaError> {
+        for (idx, slot) in SLOTS.iter().enumerate() {
+            if slot.generation.load(Ordering::Acquire) != 0 {
+                continue;
+            }
+            // Generations wrap within the bits left over by the index, skipping zero.
+            let generation = loop {
+                let generation =
+                    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed) & (u32::MAX >> INDEX_BITS);
+                if generation != 0 {
+                    break generation;
+                }
+            };
+            if slot
+                .generation
+                .compare_exchange(0, generation, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                continue;
+            }
+            slot.owner.store(owner, Ordering::Relaxed);
+            slot.allocated.store(0, Ordering::Relaxed);
+            slot.peak_allocated.store(0, Ordering::Relaxed);
+            slot.denied.store(0, Ordering::Relaxed);
+            slot.limit
+                .store(limit.unwrap_or(NO_LIMIT), Ordering::Release);
+            return Ok(Account {
+                tag: (generation << INDEX_BITS) | idx as u32,
+            });
+        }
+        Err(QuotaError::NoFreeAccounts)
+    }
+
+    /// Returns the account allocations are currently charged to, if any.
+    pub fn current() -> Option<Self> {
+        let tag = local::current();
+        slot(tag).map(|_| Account { tag })
+    }
+
+    /// Charge allocations to this account until the returned guard is dropped.
+    pub fn enter(&self) -> Entered {
+        Entered {
+            prev: local::replace_current(self.tag),
+        }
+    }
+
+    /// Set the account's limit, in bytes, or remove it with `None`.
+    ///
+    /// Lowering the limit below the current usage doesn't free anything, it
+    /// only denies further allocations until usage drops below the limit.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        if let Some(slot) = slot(self.tag) {
+            slot.limit
+                .store(limit.unwrap_or(NO_LIMIT), Ordering::Release);
+            // The new limit may be higher.
+            QUOTA_WAITER.wake_all();
+        }
+    }
+
+    /// Returns the account's usage, or `None` if it has been closed.
+    pub fn usage(&self) -> Option<Usage> {
+        let slot = slot(self.tag)?;
+        let limit = slot.limit.load(Ordering::Acquire);
+        Some(Usage {
+            owner: slot.owner.load(Ordering::Relaxed),
+            allocated: slot.allocated.load(Ordering::Relaxed),
+            peak_allocated: slot.peak_allocated.load(Ordering::Relaxed),
+            limit: (limit != NO_LIMIT).then_some(limit),
+            denied: slot.denied.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Close the account, freeing its slot.
+    ///
+    /// Memory still allocated by the owner is no longer accounted for, and
+    /// freeing it doesn't credit any account.
+    pub fn close(self) {
+        if let Some(slot) = slot(self.tag) {
+            let generation = self.tag >> INDEX_BITS;
+            let _ = slot.generation.compare_exchange(
+                generation,
+                0,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        }
+    }
+}
+
+// Entered
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        local::replace_current(self.prev);
+    }
+}
+
+// Enforced
+
+impl Drop for Enforced {
+    fn drop(&mut self) {
+        local::replace_enforcing(self.prev);
+    }
+}
+
+// Used by MnemosAlloc
+
+/// Returns the slot for `tag`, if its account is still open.
+fn slot(tag: u32) -> Option<&'static Slot> {
+    if tag == NO_ACCOUNT {
+        return None;
+    }
+    let slot = SLOTS.get((tag & INDEX_MASK) as usize)?;
+    (slot.generation.load(Ordering::Acquire) == tag >> INDEX_BITS).then_some(slot)
+}
+
+/// Returns the owner of the current account, if any.
+#[cfg(feature = "leak-trace")]
+pub(crate) fn current_owner() -> Option<u32> {
+    slot(local::current()).map(|slot| slot.owner.load(Ordering::Relaxed))
+}
+
+/// Deny allocations which would take the current account over its limit,
+/// until the returned guard is dropped.
+///
+/// Only the allocation paths which handle failure gracefully (async and
+/// `try_` allocations) enforce limits.
+pub(crate) fn enforce() -> Enforced {
+    Enforced {
+        prev: local::replace_enforcing(true),
+    }
+}
+
+/// Charge `size` bytes to the current account, returning the tag to store
+/// with the allocation, or `None` if limits are being [enforced][enforce()]
+/// and the account is over its limit.
+pub(crate) fn charge(size: usize) -> Option<u32> {
+    if !ENABLED {
+        return Some(NO_ACCOUNT);
+    }
+    let tag = local::current();
+    let Some(slot) = slot(tag) else {
+        return Some(NO_ACCOUNT);
+    };
+    let limit = if local::enforcing() {
+        slot.limit.load(Ordering::Acquire)
+    } else {
+        NO_LIMIT
+    };
+    let charged = slot
+        .allocated
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |allocated| {
+            allocated.checked_add(size).filter(|&total| total <= limit)
+        });
+    match charged {
+        Ok(allocated) => {
+            slot.peak_allocated
+                .fetch_max(allocated + size, Ordering::Relaxed);
+            Some(tag)
+        }
+        Err(_) => {
+            slot.denied.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Credit `size` bytes back to the account `tag`.
+pub(crate) fn credit(tag: u32, size: usize) {
+    let Some(slot) = slot(tag) else {
+        return;
+    };
+    let _ = slot
+        .allocated
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |allocated| {
+            Some(allocated.saturating_sub(size))
+        });
+    if slot.limit.load(Ordering::Acquire) != NO_LIMIT {
+        QUOTA_WAITER.wake_all();
+    }
+}
+
+/// Returns `true` if the current account can't fit `size` more bytes.
+pub(crate) fn over_limit(size: usize) -> bool {
+    if !ENABLED {
+        return false;
+    }
+    let Some(slot) = slot(local::current()) else {
+        return false;
+    };
+    let allocated = slot.allocated.load(Ordering::Acquire);
+    allocated.saturating_add(size) > slot.limit.load(Ordering::Acquire)
+}
+
+/// Wait until an account with a limit frees some memory.
+pub(crate) async fn wait_for_room() {
+    let _ = QUOTA_WAITER.wait().await;
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "quota")]
+    fn index(account: &Account) -> u32 {
+        account.tag & INDEX_MASK
+    }
+
+    #[test]
+    fn current_account_is_per_thread() {
+        let _lock = crate::test_lock();
+        let account = Account::open(1, None).unwrap();
+        {
+            let _entered = account.enter();
+            assert_eq!(Account::current(), Some(account));
+            std::thread::spawn(|| assert_eq!(Account::current(), None))
+                .join()
+                .unwrap();
+        }
+        assert_eq!(Account::current(), None);
+        account.close();
+    }
+
+    #[test]
+    #[cfg(feature = "quota")]
+    fn limit_is_only_enforced_when_enforcing() {
+        let _lock = crate::test_lock();
+        let account = Account::open(1, Some(100)).unwrap();
+        let _entered = account.enter();
+
+        let tag = charge(80).unwrap();
+        assert_eq!(tag, account.tag);
+        // Allocations which can't fail go over the limit...
+        assert_eq!(charge(40), Some(tag));
+        assert!(over_limit(0));
+        // ...after which those which can fail are denied.
+        {
+            let _enforce = enforce();
+            assert_eq!(charge(1), None);
+        }
+        let usage = account.usage().unwrap();
+        assert_eq!(usage.allocated, 120);
+        assert_eq!(usage.peak_allocated, 120);
+        assert_eq!(usage.limit, Some(100));
+        assert_eq!(usage.denied, 1);
+
+        credit(tag, 120);
+        assert!(!over_limit(100));
+        assert!(over_limit(101));
+        {
+            let _enforce = enforce();
+            assert_eq!(charge(100), Some(tag));
+            assert_eq!(charge(1), None);
+        }
+        let usage = account.usage().unwrap();
+        assert_eq!(usage.allocated, 100);
+        assert_eq!(usage.denied, 2);
+
+        drop(_entered);
+        account.close();
+    }
+
+    #[test]
+    #[cfg(feature = "quota")]
+    fn close_frees_the_slot() {
+        let _lock = crate::test_lock();
+        let account = Account::open(1, Some(100)).unwrap();
+        let tag = {
+            let _entered = account.enter();
+            charge(60).unwrap()
+        };
+        credit(tag, 60);
+        let usage = account.usage().unwrap();
+        assert_eq!(usage.allocated, 0);
+        assert_eq!(usage.peak_allocated, 60);
+
+        account.close();
+        assert_eq!(account.usage(), None);
+        // Nothing is charged to a closed account.
+        {
+            let _entered = account.enter();
+            assert_eq!(charge(60), Some(NO_ACCOUNT));
+        }
+
+        let reopened = Account::open(2, None).unwrap();
+        assert_eq!(index(&reopened), index(&account));
+        assert_ne!(reopened, account);
+        let usage = reopened.usage().unwrap();
+        assert_eq!(usage.owner, 2);
+        assert_eq!(usage.allocated, 0);
+        assert_eq!(usage.peak_allocated, 0);
+        assert_eq!(usage.limit, None);
+        reopened.close();
+    }
+
+    #[test]
+    #[cfg(feature = "quota")]
+    fn stale_tag_doesnt_credit_reused_slot() {
+        let _lock = crate::test_lock();
+        let stale = Account::open(1, None).unwrap();
+        let stale_tag = {
+            let _entered = stale.enter();
+            charge(64).unwrap()
+        };
+        stale.close();
+
+        let account = Account::open(2, None).unwrap();
+        assert_eq!(index(&account), index(&stale));
+        {
+            let _entered = account.enter();
+            charge(64).unwrap();
+        }
+        // Freeing the closed account's allocation doesn't touch the new one.
+        credit(stale_tag, 64);
+        assert_eq!(account.usage().unwrap().allocated, 64);
+        // Nor does closing the stale handle again.
+        stale.close();
+        assert_eq!(account.usage().unwrap().allocated, 64);
+        account.close();
+    }
+
+    #[test]
+    #[cfg(not(feature = "quota"))]
+    fn nothing_is_charged_without_quota() {
+        let _lock = crate::test_lock();
+        let account = Account::open(1, Some(0)).unwrap();
+        {
+            let _entered = account.enter();
+            let _enforce = enforce();
+            assert_eq!(charge(64), Some(NO_ACCOUNT));
+            assert!(!over_limit(64));
+        }
+        assert_eq!(account.usage().unwrap().allocated, 0);
+        account.close();
+    }
+}
@@ -4,7 +4,11 @@
 //! This extends the underlying bbqueue type exposed by the ABI crate, allowing
 //! for async kernel-to-kernel (including driver services) usage.
 
-use core::ops::{Deref, DerefMut};
+use core::{
+    future::{pending, Future},
+    ops::{Deref, DerefMut},
+    pin::pin,
+};
 
 use crate::fmt;
 use crate::tracing::{self, info, trace};
@@ -12,7 +16,10 @@ use abi::bbqueue_ipc::{BBBuffer, Consumer as InnerConsumer, Producer as InnerPro
 use abi::bbqueue_ipc::{GrantR as InnerGrantR, GrantW as InnerGrantW};
 use maitake::sync::Mutex;
 use maitake::sync::WaitCell;
-use mnemos_alloc::containers::{Arc, ArrayBuf};
+use mnemos_alloc::{
+    containers::{Arc, ArrayBuf},
+    heap::Cancelled,
+};
 
 struct BBQStorage {
     commit_waitcell: WaitCell,
@@ -82,8 +89,21 @@ impl SpscProducer {
 }
 
 pub async fn new_spsc_channel(capacity: usize) -> (SpscProducer, Consumer) {
+    match new_spsc_channel_or_cancel(capacity, pending::<()>()).await {
+        Ok(channel) => channel,
+        Err(Cancelled) => unreachable!("allocations are never cancelled"),
+    }
+}
+
+/// Like [`new_spsc_channel()`], but gives up if `cancel` completes before
+/// the channel's storage has been allocated.
+pub async fn new_spsc_channel_or_cancel<C: Future>(
+    capacity: usize,
+    cancel: C,
+) -> Result<(SpscProducer, Consumer), Cancelled> {
     info!(capacity, "Creating new mpsc BBQueue channel");
-    let mut _array = ArrayBuf::new_uninit(capacity).await;
+    let mut cancel = pin!(cancel);
+    let mut _array = ArrayBuf::new_uninit_or_cancel(capacity, cancel.as_mut()).await?;
 
     let ring = BBBuffer::new();
 
@@ -92,14 +112,18 @@ pub async fn new_spsc_channel(capacity: usize) -> (SpscProducer, Consumer) {
         ring.initialize(ptr.as_ptr().cast(), len);
     }
 
-    let storage = Arc::new(BBQStorage {
-        commit_waitcell: WaitCell::new(),
-        release_waitcell: WaitCell::new(),
-        producer: Mutex::new(None),
-        ring,
-        _array,
-    })
-    .await;
+    let storage = Arc::new_or_cancel(
+        BBQStorage {
+            commit_waitcell: WaitCell::new(),
+            release_waitcell: WaitCell::new(),
+            producer: Mutex::new(None),
+            ring,
+            _array,
+        },
+        cancel,
+    )
+    .await
+    .map_err(|_| Cancelled)?;
 
     // Now that we've allocated storage, the producer can be created.
 
@@ -123,7 +147,7 @@ pub async fn new_spsc_channel(capacity: usize) -> (SpscProducer, Consumer) {
 
     info!("Channel created successfully");
 
-    (prod, cons)
+    Ok((prod, cons))
 }
 
 pub struct GrantW {
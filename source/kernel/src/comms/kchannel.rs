@@ -24,6 +24,9 @@ pub struct KProducer<T> {
 /// Only a single `KConsumer` can exist at a time for each backing [KChannel],
 /// as it is an MPSC queue. A `KConsumer` can also be used to create a new
 /// [KProducer] instance.
+///
+/// Dropping the `KConsumer` closes the channel, causing any further sends
+/// from a [KProducer] to fail.
 pub struct KConsumer<T> {
     q: Arc<MpScQueue<T, sealed::SpiteData<T>>>,
 }
@@ -37,6 +40,8 @@ pub(crate) struct ErasedKProducer {
     erased_q: NonNull<MpScQueue<(), sealed::SpiteData<()>>>,
     dropper: unsafe fn(NonNull<MpScQueue<(), sealed::SpiteData<()>>>),
     cloner: unsafe fn(&Self) -> Self,
+    closer: unsafe fn(NonNull<MpScQueue<(), sealed::SpiteData<()>>>),
+    is_closed: unsafe fn(NonNull<MpScQueue<(), sealed::SpiteData<()>>>) -> bool,
 }
 
 // KChannel
@@ -119,6 +124,26 @@ impl<T> KProducer<T> {
         self.q.enqueue_async(item).await
     }
 
+    /// Has the channel been closed, e.g. because the [KConsumer] was dropped?
+    ///
+    /// Once closed, a channel will never re-open.
+    #[inline(always)]
+    pub fn is_closed(&self) -> bool {
+        self.q.is_closed()
+    }
+
+    /// Returns the number of items currently waiting in the channel.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.q.len()
+    }
+
+    /// Returns `true` if no items are currently waiting in the channel.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.q.is_empty()
+    }
+
     pub(crate) fn type_erase(self) -> ErasedKProducer {
         let typed_q: NonNull<MpScQueue<T, sealed::SpiteData<T>>> = Arc::into_raw(self.q);
         let erased_q: NonNull<MpScQueue<(), sealed::SpiteData<()>>> = typed_q.cast();
@@ -127,6 +152,8 @@ impl<T> KProducer<T> {
             erased_q,
             dropper: ErasedKProducer::drop_erased::<T>,
             cloner: ErasedKProducer::clone_erased::<T>,
+            closer: ErasedKProducer::close_erased::<T>,
+            is_closed: ErasedKProducer::is_closed_erased::<T>,
         }
     }
 }
@@ -156,6 +183,15 @@ impl<T> KConsumer<T> {
     }
 }
 
+impl<T> Drop for KConsumer<T> {
+    fn drop(&mut self) {
+        // Nobody is left to receive messages, so don't let producers keep
+        // sending them. Any messages still in the queue are dropped along
+        // with the queue itself, once the last producer goes away.
+        self.q.close();
+    }
+}
+
 // ErasedKProducer
 
 impl Clone for ErasedKProducer {
@@ -177,9 +213,21 @@ impl ErasedKProducer {
             erased_q: self.erased_q,
             dropper: self.dropper,
             cloner: self.cloner,
+            closer: self.closer,
+            is_closed: self.is_closed,
         }
     }
 
+    /// Close the underlying [KChannel], causing all further sends to fail.
+    pub(crate) fn close(&self) {
+        unsafe { (self.closer)(self.erased_q) }
+    }
+
+    /// Has the underlying [KChannel] been closed?
+    pub(crate) fn is_closed(&self) -> bool {
+        unsafe { (self.is_closed)(self.erased_q) }
+    }
+
     /// Clone the ErasedKProducer, while also re-typing to the unleaked [KProducer] type.
     ///
     /// SAFETY:
@@ -206,6 +254,30 @@ impl ErasedKProducer {
         let ptr = ptr.cast::<MpScQueue<T, sealed::SpiteData<T>>>();
         let _ = Arc::from_raw(ptr);
     }
+
+    /// Close the channel, while also re-typing the leaked [KProducer] type.
+    ///
+    /// SAFETY:
+    ///
+    /// The type `T` MUST be the same `T` that was used to create this ErasedKProducer,
+    /// otherwise undefined behavior will occur.
+    pub(crate) unsafe fn close_erased<T>(ptr: NonNull<MpScQueue<(), sealed::SpiteData<()>>>) {
+        let ptr = ptr.cast::<MpScQueue<T, sealed::SpiteData<T>>>();
+        ptr.as_ref().close();
+    }
+
+    /// Check whether the channel is closed, while also re-typing the leaked [KProducer] type.
+    ///
+    /// SAFETY:
+    ///
+    /// The type `T` MUST be the same `T` that was used to create this ErasedKProducer,
+    /// otherwise undefined behavior will occur.
+    pub(crate) unsafe fn is_closed_erased<T>(
+        ptr: NonNull<MpScQueue<(), sealed::SpiteData<()>>>,
+    ) -> bool {
+        let ptr = ptr.cast::<MpScQueue<T, sealed::SpiteData<T>>>();
+        ptr.as_ref().is_closed()
+    }
 }
 
 impl Drop for ErasedKProducer {
@@ -232,3 +304,64 @@ pub(crate) mod sealed {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc as StdArc;
+    use futures::FutureExt;
+
+    #[test]
+    fn dropping_consumer_closes() {
+        let (prod, cons) = KChannel::<u32>::new(4).split();
+        let prod2 = prod.clone();
+        prod.enqueue_sync(1).unwrap();
+        assert!(!prod.is_closed());
+
+        drop(cons);
+        assert!(prod.is_closed());
+        assert!(prod2.is_closed());
+        assert_eq!(prod.enqueue_sync(2), Err(EnqueueError::Closed(2)));
+        assert_eq!(
+            prod2.enqueue_async(3).now_or_never(),
+            Some(Err(EnqueueError::Closed(3)))
+        );
+    }
+
+    #[test]
+    fn full_producer_woken_by_close() {
+        let (prod, cons) = KChannel::<u32>::new(2).split();
+        while prod.enqueue_sync(0).is_ok() {}
+        let mut send = core::pin::pin!(prod.enqueue_async(1));
+        assert!((&mut send).now_or_never().is_none());
+        drop(cons);
+        assert_eq!(send.now_or_never(), Some(Err(EnqueueError::Closed(1))));
+    }
+
+    #[test]
+    fn queued_items_dropped_with_last_producer() {
+        let item = StdArc::new(());
+        let (prod, cons) = KChannel::new(4).split();
+        prod.enqueue_sync(item.clone()).unwrap();
+        prod.enqueue_sync(item.clone()).unwrap();
+
+        // Closing doesn't drop queued items, as producers still hold the queue...
+        drop(cons);
+        assert_eq!(StdArc::strong_count(&item), 3);
+        // ...but they are dropped along with it.
+        drop(prod);
+        assert_eq!(StdArc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn consumer_drains_after_close() {
+        let (prod, cons) = KChannel::<u32>::new(4).split();
+        prod.enqueue_sync(1).unwrap();
+        cons.q.close();
+        assert_eq!(cons.dequeue_async().now_or_never(), Some(Ok(1)));
+        assert_eq!(
+            cons.dequeue_async().now_or_never(),
+            Some(Err(DequeueError::Closed))
+        );
+    }
+}
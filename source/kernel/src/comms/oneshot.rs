@@ -7,24 +7,48 @@
 use core::{
     cell::UnsafeCell,
     mem::MaybeUninit,
-    sync::atomic::{AtomicU8, Ordering},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use maitake::sync::{Closed, WaitCell};
 use mnemos_alloc::containers::Arc;
 
 /// Not waiting for anything.
-const ROSC_IDLE: u8 = 0;
+const ROSC_IDLE: usize = 0;
 /// A Sender has been created, but no writes have begun yet
-const ROSC_WAITING: u8 = 1;
+const ROSC_WAITING: usize = 1;
 /// A Sender has begun writing, and will be dropped shortly.
-const ROSC_WRITING: u8 = 2;
+const ROSC_WRITING: usize = 2;
 /// The Sender has been dropped and the message has been send
-const ROSC_READY: u8 = 3;
+const ROSC_READY: usize = 3;
 /// Reading has already started
-const ROSC_READING: u8 = 4;
+const ROSC_READING: usize = 4;
 /// The receiver has been manually closed or dropped.
-const ROSC_CLOSED: u8 = 5;
+const ROSC_CLOSED: usize = 5;
+
+/// The state word holds one of the `ROSC_*` states in its lowest bits, and
+/// the "generation" of the current [Sender] in the remaining bits.
+///
+/// The generation is advanced every time a request is cancelled with
+/// [Reusable::cancel], so that a sender created before the cancellation
+/// can no longer complete (or reset) a newer request.
+const STATE_BITS: u32 = 3;
+const STATE_MASK: usize = (1 << STATE_BITS) - 1;
+
+#[inline]
+const fn state(word: usize) -> usize {
+    word & STATE_MASK
+}
+
+#[inline]
+const fn generation(word: usize) -> usize {
+    word >> STATE_BITS
+}
+
+#[inline]
+const fn pack(generation: usize, state: usize) -> usize {
+    (generation << STATE_BITS) | state
+}
 
 /// A reusable One-Shot channel.
 ///
@@ -47,6 +71,7 @@ pub struct Reusable<T> {
 /// the [Sender].
 pub struct Sender<T> {
     inner: Arc<Inner<T>>,
+    generation: usize,
 }
 
 // An error type for the Reusable channel and Sender
@@ -66,7 +91,7 @@ impl From<Closed> for ReusableError {
 
 /// An inner type shared between the Rosc and Sender.
 struct Inner<T> {
-    state: AtomicU8,
+    state: AtomicUsize,
     cell: UnsafeCell<MaybeUninit<T>>,
     wait: WaitCell,
 }
@@ -88,28 +113,30 @@ impl<T> Reusable<T> {
     /// This error can be cleared by awaiting [Reusable::receive].
     pub async fn sender(&self) -> Result<Sender<T>, ReusableError> {
         loop {
-            let swap = self.inner.state.compare_exchange(
-                ROSC_IDLE,
-                ROSC_WAITING,
-                Ordering::AcqRel,
-                Ordering::Relaxed,
-            );
-
-            match swap {
-                Ok(_) => {
-                    return Ok(Sender {
-                        inner: self.inner.clone(),
-                    })
-                }
-                Err(val) => {
-                    if val == ROSC_READY {
-                        let _ = self.receive().await;
-                    } else if (val == ROSC_WAITING) | (val == ROSC_WRITING) {
-                        return Err(ReusableError::SenderAlreadyActive);
-                    } else {
-                        return Err(ReusableError::InternalError);
+            let cur = self.inner.state.load(Ordering::Acquire);
+            match state(cur) {
+                ROSC_IDLE => {
+                    let swap = self.inner.state.compare_exchange(
+                        cur,
+                        pack(generation(cur), ROSC_WAITING),
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                    if swap.is_ok() {
+                        return Ok(Sender {
+                            inner: self.inner.clone(),
+                            generation: generation(cur),
+                        });
                     }
+                    // The state changed under us, take another look.
+                }
+                ROSC_READY => {
+                    let _ = self.receive().await;
                 }
+                ROSC_WAITING | ROSC_WRITING => {
+                    return Err(ReusableError::SenderAlreadyActive);
+                }
+                _ => return Err(ReusableError::InternalError),
             }
         }
     }
@@ -123,15 +150,20 @@ impl<T> Reusable<T> {
     /// return an error after the sender has been dropped.
     pub async fn receive(&self) -> Result<T, ReusableError> {
         loop {
-            let swap = self.inner.state.compare_exchange(
-                ROSC_READY,
-                ROSC_READING,
-                Ordering::AcqRel,
-                Ordering::Relaxed,
-            );
-
-            match swap {
-                Ok(_) => {
+            let cur = self.inner.state.load(Ordering::Acquire);
+            match state(cur) {
+                ROSC_READY => {
+                    let swap = self.inner.state.compare_exchange(
+                        cur,
+                        pack(generation(cur), ROSC_READING),
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                    if swap.is_err() {
+                        // The state changed under us, take another look.
+                        continue;
+                    }
+
                     // We just swapped from READY to READING, that's a success!
                     unsafe {
                         let mut ret = MaybeUninit::<T>::uninit();
@@ -140,11 +172,13 @@ impl<T> Reusable<T> {
                             ret.as_mut_ptr(),
                             1,
                         );
-                        self.inner.state.store(ROSC_IDLE, Ordering::Release);
+                        self.inner
+                            .state
+                            .store(pack(generation(cur), ROSC_IDLE), Ordering::Release);
                         return Ok(ret.assume_init());
                     }
                 }
-                Err(ROSC_WAITING | ROSC_WRITING) => {
+                ROSC_WAITING | ROSC_WRITING => {
                     // We are still waiting for the Sender to start or complete.
                     // Trigger another wait cycle.
                     //
@@ -153,12 +187,12 @@ impl<T> Reusable<T> {
                     // done while the borrow of self is active in this function.
                     self.inner.wait.wait().await?;
                 }
-                Err(ROSC_IDLE) => {
+                ROSC_IDLE => {
                     // We are currently idle, i.e. no sender has been created,
                     // or the existing one was dropped unused.
                     break Err(ReusableError::NoSenderActive);
                 }
-                Err(_) => {
+                _ => {
                     // Something has gone terribly wrong. Return an error.
                     break Err(ReusableError::InternalError);
                 }
@@ -166,12 +200,88 @@ impl<T> Reusable<T> {
         }
     }
 
+    /// Abandon the in-flight request, if there is one.
+    ///
+    /// If a [Sender] is still active, it is detached from this `Reusable`:
+    /// sending a response with it will fail with [ReusableError::ChannelClosed],
+    /// and a new sender can be created right away. If a response has already
+    /// been sent but not yet received, it is dropped.
+    ///
+    /// This is a no-op if there is no in-flight request.
+    pub fn cancel(&self) {
+        loop {
+            let cur = self.inner.state.load(Ordering::Acquire);
+            let gen = generation(cur);
+            match state(cur) {
+                ROSC_WAITING => {
+                    // Advance the generation, so the detached sender can't
+                    // touch the state anymore.
+                    let swap = self.inner.state.compare_exchange(
+                        cur,
+                        pack(gen.wrapping_add(1), ROSC_IDLE),
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                    if swap.is_ok() {
+                        return;
+                    }
+                }
+                ROSC_READY => {
+                    let swap = self.inner.state.compare_exchange(
+                        cur,
+                        pack(gen, ROSC_READING),
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                    if swap.is_ok() {
+                        // Nobody wants this response anymore, drop it.
+                        unsafe {
+                            let ptr: *mut MaybeUninit<T> = self.inner.cell.get();
+                            let ptr: *mut T = ptr.cast();
+                            core::ptr::drop_in_place(ptr);
+                        }
+                        self.inner
+                            .state
+                            .store(pack(gen, ROSC_IDLE), Ordering::Release);
+                        return;
+                    }
+                }
+                // * IDLE: nothing to cancel.
+                // * WRITING: the sender is about to finish writing, after
+                //   which `sender()` will discard the response.
+                // * READING/CLOSED: impossible while we hold `&self`.
+                _ => return,
+            }
+        }
+    }
+
     /// Close the receiver. This will cause any pending senders to fail.
     pub fn close(self) {
         drop(self);
     }
 }
 
+/// A guard which calls [Reusable::cancel] when dropped.
+///
+/// This can be held by a request/response future, so that if the future is
+/// dropped before the response arrives (for example, because it timed out),
+/// the [Reusable] is not left waiting for a response that nobody will read.
+pub struct CancelOnDrop<'a, T> {
+    reusable: &'a Reusable<T>,
+}
+
+impl<'a, T> CancelOnDrop<'a, T> {
+    pub fn new(reusable: &'a Reusable<T>) -> Self {
+        Self { reusable }
+    }
+}
+
+impl<T> Drop for CancelOnDrop<'_, T> {
+    fn drop(&mut self) {
+        self.reusable.cancel();
+    }
+}
+
 impl<T> Drop for Reusable<T> {
     fn drop(&mut self) {
         // Immediately mark the state as closed
@@ -181,7 +291,7 @@ impl<T> Drop for Reusable<T> {
         self.inner.wait.close();
 
         // Determine if we need to drop the payload, if there is one.
-        match old {
+        match state(old) {
             ROSC_IDLE => {
                 // Nothing to do, already idle, no contents
             }
@@ -227,15 +337,19 @@ impl<T> Sender<T> {
     /// Consume the sender, providing it with a reply.
     pub fn send(self, item: T) -> Result<(), ReusableError> {
         let swap = self.inner.state.compare_exchange(
-            ROSC_WAITING,
-            ROSC_WRITING,
+            pack(self.generation, ROSC_WAITING),
+            pack(self.generation, ROSC_WRITING),
             Ordering::AcqRel,
             Ordering::Relaxed,
         );
 
         match swap {
             Ok(_) => {}
-            Err(ROSC_CLOSED) => return Err(ReusableError::ChannelClosed),
+            Err(cur) if state(cur) == ROSC_CLOSED => return Err(ReusableError::ChannelClosed),
+            // The request was cancelled, nobody is waiting for our reply.
+            Err(cur) if generation(cur) != self.generation => {
+                return Err(ReusableError::ChannelClosed)
+            }
             Err(_) => return Err(ReusableError::InternalError),
         };
 
@@ -245,15 +359,15 @@ impl<T> Sender<T> {
         // but in that case we won't override the CLOSED state, and it becomes OUR
         // responsibility to drop the contents.
         let swap = self.inner.state.compare_exchange(
-            ROSC_WRITING,
-            ROSC_READY,
+            pack(self.generation, ROSC_WRITING),
+            pack(self.generation, ROSC_READY),
             Ordering::AcqRel,
             Ordering::Relaxed,
         );
 
         match swap {
             Ok(_) => {}
-            Err(ROSC_CLOSED) => {
+            Err(cur) if state(cur) == ROSC_CLOSED => {
                 // Yup, a close happened WHILE we were writing. Go ahead and drop the contents
                 unsafe {
                     let ptr: *mut MaybeUninit<T> = self.inner.cell.get();
@@ -274,9 +388,12 @@ impl<T> Drop for Sender<T> {
     fn drop(&mut self) {
         // Attempt to move the state from WAITING to IDLE, and wake any
         // pending waiters. This will cause an Err(()) on the receive side.
+        //
+        // If the request was cancelled, the generation won't match, and a
+        // newer request is left alone.
         let _ = self.inner.state.compare_exchange(
-            ROSC_WAITING,
-            ROSC_IDLE,
+            pack(self.generation, ROSC_WAITING),
+            pack(self.generation, ROSC_IDLE),
             Ordering::AcqRel,
             Ordering::Relaxed,
         );
@@ -294,9 +411,65 @@ unsafe impl<T: Send> Sync for Inner<T> {}
 impl<T> Inner<T> {
     fn new() -> Self {
         Self {
-            state: AtomicU8::new(ROSC_IDLE),
+            state: AtomicUsize::new(pack(0, ROSC_IDLE)),
             cell: UnsafeCell::new(MaybeUninit::uninit()),
             wait: WaitCell::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    /// Does what a request/response future does: hands a sender to the
+    /// "service" through `service`, and cancels the request if the future is
+    /// dropped before the response arrives.
+    async fn request(
+        reusable: &Reusable<u32>,
+        service: &mut Option<Sender<u32>>,
+    ) -> Result<u32, ReusableError> {
+        let tx = reusable.sender().await?;
+        let _cancel = CancelOnDrop::new(reusable);
+        *service = Some(tx);
+        reusable.receive().await
+    }
+
+    #[test]
+    fn stale_sender_after_timeout() {
+        let reusable = Reusable::<u32>::new_async().now_or_never().unwrap();
+
+        // The first request times out: the future is dropped after one poll.
+        let mut stale = None;
+        assert!(request(&reusable, &mut stale).now_or_never().is_none());
+        let stale = stale.unwrap();
+
+        // The `Reusable` can be used for a new request right away.
+        let tx = reusable.sender().now_or_never().unwrap().unwrap();
+
+        // The old sender's reply is rejected, and doesn't complete the new request.
+        assert_eq!(stale.send(1), Err(ReusableError::ChannelClosed));
+        assert!(reusable.receive().now_or_never().is_none());
+
+        tx.send(2).unwrap();
+        assert_eq!(reusable.receive().now_or_never(), Some(Ok(2)));
+    }
+
+    #[test]
+    fn stale_sender_dropped_after_timeout() {
+        let reusable = Reusable::<u32>::new_async().now_or_never().unwrap();
+
+        let mut stale = None;
+        assert!(request(&reusable, &mut stale).now_or_never().is_none());
+
+        let tx = reusable.sender().now_or_never().unwrap().unwrap();
+        // Dropping the stale sender without replying must not make the newer
+        // request look abandoned.
+        drop(stale);
+        assert!(reusable.receive().now_or_never().is_none());
+
+        tx.send(3).unwrap();
+        assert_eq!(reusable.receive().now_or_never(), Some(Ok(3)));
+    }
+}
@@ -0,0 +1,366 @@
+//! Boot configuration
+//!
+//! A [`BootConfig`] describes the kernel settings and the services and
+//! daemons started at boot. It can be serialized with `postcard`, so a
+//! platform can load it as bytes from wherever it likes (a file on the host
+//! for melpomene, a reserved region of RAM on the D1), and the system can be
+//! reconfigured without recompiling.
+//!
+//! The serialized form starts with [`BootConfig::MAGIC`] and the length of
+//! the `postcard` payload, as a little-endian `u32`, so a platform can tell
+//! whether a region of memory actually holds a config.
+//!
+//! Hardware drivers (serial ports, displays, etc.) are still set up by the
+//! platform, as they depend on the hardware; the boot config only covers the
+//! platform-independent services and daemons.
+
+use core::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    daemons::{
+        sermux::{hello, loopback, HelloSettings, LoopbackSettings},
+        shells::{graphical_shell_mono, sermux_shell, GraphicalShellSettings, SermuxShellSettings},
+        task_stats::{task_stats, TaskStatsSettings},
+    },
+    services::{forth_spawnulator::SpawnulatorServer, serial_mux::SerialMuxServer},
+    tasks::Priority,
+    tracing::{self, Instrument},
+    Kernel, KernelSettings,
+};
+
+/// Kernel settings, and which services and daemons to start.
+///
+/// Any service or daemon set to `None` is not started.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BootConfig {
+    /// Maximum number of registered drivers. Defaults to 16
+    pub max_drivers: usize,
+    /// Maximum number of tasks in the task table. Defaults to 64
+    pub max_tasks: usize,
+    /// See [`KernelSettings::poll_budget`]. Defaults to `None`
+    pub poll_budget: Option<Duration>,
+    /// The [SerialMuxServer]. Defaults to `Some`, with the default settings
+    pub sermux: Option<SerMuxConfig>,
+    /// The [SpawnulatorServer]. Defaults to `Some`, with the default settings
+    pub spawnulator: Option<SpawnulatorConfig>,
+    /// The [loopback] daemon. Defaults to `Some`, with the default settings
+    pub loopback: Option<LoopbackSettings>,
+    /// The [hello] daemon. Defaults to `Some`, with the default settings
+    pub hello: Option<HelloSettings>,
+    /// The [task_stats] daemon. Defaults to `Some`, with the default settings
+    pub task_stats: Option<TaskStatsSettings>,
+    /// The [sermux_shell] daemon. Defaults to `None`
+    pub sermux_shell: Option<SermuxShellSettings>,
+    /// The [graphical_shell_mono] daemon. Defaults to `None`, as it depends
+    /// on the size of the platform's display
+    pub graphical_shell: Option<GraphicalShellSettings>,
+    #[serde(skip)]
+    _priv: (),
+}
+
+/// [SerialMuxServer] settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerMuxConfig {
+    /// Maximum number of virtual ports. Defaults to 16
+    pub max_ports: usize,
+    /// Maximum size of a framed message, in bytes. Defaults to 512
+    pub max_frame: usize,
+    #[serde(skip)]
+    _priv: (),
+}
+
+/// [SpawnulatorServer] settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpawnulatorConfig {
+    /// Number of queued Forth VMs waiting to be spawned. Defaults to 16
+    pub capacity: usize,
+    #[serde(skip)]
+    _priv: (),
+}
+
+/// Errors returned by [`BootConfig::from_bytes()`] and [`BootConfig::to_slice()`]
+#[derive(Debug, Eq, PartialEq)]
+pub enum BootConfigError {
+    /// The bytes do not start with [`BootConfig::MAGIC`]
+    BadMagic,
+    /// The buffer is shorter than the header says
+    Truncated,
+    /// The payload could not be (de)serialized
+    Postcard(postcard::Error),
+}
+
+// BootConfig
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            max_drivers: 16,
+            max_tasks: 64,
+            poll_budget: None,
+            sermux: Some(SerMuxConfig::default()),
+            spawnulator: Some(SpawnulatorConfig::default()),
+            loopback: Some(LoopbackSettings::default()),
+            hello: Some(HelloSettings::default()),
+            task_stats: Some(TaskStatsSettings::default()),
+            sermux_shell: None,
+            graphical_shell: None,
+            _priv: (),
+        }
+    }
+}
+
+impl BootConfig {
+    /// Magic bytes at the start of a serialized boot config
+    pub const MAGIC: [u8; 4] = *b"MNBC";
+    const HEADER_LEN: usize = Self::MAGIC.len() + 4;
+
+    /// Deserialize a boot config. Any bytes after the end of the config are
+    /// ignored.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BootConfigError> {
+        if !bytes.starts_with(&Self::MAGIC) {
+            return Err(BootConfigError::BadMagic);
+        }
+        let len = bytes
+            .get(Self::MAGIC.len()..Self::HEADER_LEN)
+            .ok_or(BootConfigError::Truncated)?;
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        let payload = bytes
+            .get(Self::HEADER_LEN..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or(BootConfigError::Truncated)?;
+        postcard::from_bytes(payload).map_err(BootConfigError::Postcard)
+    }
+
+    /// Serialize this boot config into `buf`, returning the used part of `buf`.
+    pub fn to_slice<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], BootConfigError> {
+        if buf.len() < Self::HEADER_LEN {
+            return Err(BootConfigError::Truncated);
+        }
+        let len = postcard::to_slice(self, &mut buf[Self::HEADER_LEN..])
+            .map_err(BootConfigError::Postcard)?
+            .len();
+        buf[..Self::MAGIC.len()].copy_from_slice(&Self::MAGIC);
+        buf[Self::MAGIC.len()..Self::HEADER_LEN].copy_from_slice(&(len as u32).to_le_bytes());
+        Ok(&mut buf[..Self::HEADER_LEN + len])
+    }
+
+    /// Returns the [`KernelSettings`] for this config.
+    ///
+    /// The timer granularity and busy clock depend on the platform's
+    /// hardware, so they are provided by the platform.
+    pub fn kernel_settings(
+        &self,
+        timer_granularity: Duration,
+        busy_clock: Option<fn() -> u32>,
+    ) -> KernelSettings {
+        KernelSettings {
+            max_drivers: self.max_drivers,
+            max_tasks: self.max_tasks,
+            timer_granularity,
+            busy_clock,
+            poll_budget: self.poll_budget,
+        }
+    }
+
+    /// Spawns the configured services and daemons with [`Kernel::initialize()`].
+    pub fn initialize(self, k: &'static Kernel) -> Result<(), &'static str> {
+        let Self {
+            max_drivers: _,
+            max_tasks: _,
+            poll_budget: _,
+            sermux,
+            spawnulator,
+            loopback: loopback_settings,
+            hello: hello_settings,
+            task_stats: task_stats_settings,
+            sermux_shell: sermux_shell_settings,
+            graphical_shell,
+            _priv,
+        } = self;
+
+        if let Some(SerMuxConfig {
+            max_ports,
+            max_frame,
+            _priv,
+        }) = sermux
+        {
+            k.initialize(
+                Priority::System,
+                async move {
+                    tracing::debug!("initializing SerialMuxServer...");
+                    SerialMuxServer::register(k, max_ports, max_frame)
+                        .await
+                        .unwrap();
+                    tracing::info!("SerialMuxServer initialized!");
+                }
+                .instrument(tracing::info_span!(
+                    "SerialMuxServer",
+                    ports = max_ports,
+                    frame_size = max_frame
+                )),
+            )?;
+        }
+
+        if let Some(SpawnulatorConfig { capacity, _priv }) = spawnulator {
+            k.initialize(Priority::System, SpawnulatorServer::register(k, capacity))?;
+        }
+
+        if let Some(settings) = loopback_settings {
+            k.initialize(Priority::User, loopback(k, settings))?;
+        }
+
+        if let Some(settings) = hello_settings {
+            k.initialize(Priority::User, hello(k, settings))?;
+        }
+
+        if let Some(settings) = task_stats_settings {
+            k.initialize_named(
+                Priority::System,
+                Some("task_stats"),
+                task_stats(k, settings),
+            )?;
+        }
+
+        if let Some(settings) = sermux_shell_settings {
+            k.initialize(Priority::User, sermux_shell(k, settings))?;
+        }
+
+        if let Some(settings) = graphical_shell {
+            k.initialize(Priority::User, graphical_shell_mono(k, settings))?;
+        }
+
+        Ok(())
+    }
+}
+
+// SerMuxConfig
+
+impl Default for SerMuxConfig {
+    fn default() -> Self {
+        Self {
+            max_ports: 16,
+            max_frame: 512,
+            _priv: (),
+        }
+    }
+}
+
+// SpawnulatorConfig
+
+impl Default for SpawnulatorConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 16,
+            _priv: (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REGION_LEN: usize = 512;
+
+    /// Serialize `config` into a region of memory, as a platform would load it.
+    fn region(config: &BootConfig) -> ([u8; REGION_LEN], usize) {
+        let mut buf = [0u8; REGION_LEN];
+        let len = config.to_slice(&mut buf).unwrap().len();
+        (buf, len)
+    }
+
+    /// Load a config the way the platforms do, falling back to the default.
+    fn load(bytes: &[u8]) -> BootConfig {
+        BootConfig::from_bytes(bytes).unwrap_or_default()
+    }
+
+    fn assert_default(config: BootConfig) {
+        let (actual, len) = region(&config);
+        let (expected, expected_len) = region(&BootConfig::default());
+        assert_eq!(actual[..len], expected[..expected_len]);
+    }
+
+    fn set_len(buf: &mut [u8], len: u32) {
+        buf[BootConfig::MAGIC.len()..BootConfig::HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut config = BootConfig::default();
+        config.max_tasks = 7;
+        config.poll_budget = Some(Duration::from_millis(5));
+        config.hello = None;
+        config.sermux_shell = Some(SermuxShellSettings::default());
+
+        let (buf, len) = region(&config);
+        let loaded = BootConfig::from_bytes(&buf).unwrap();
+        assert_eq!(loaded.max_tasks, 7);
+        assert_eq!(loaded.poll_budget, Some(Duration::from_millis(5)));
+        assert!(loaded.hello.is_none());
+        assert!(loaded.sermux_shell.is_some());
+
+        // Bytes after the config are ignored, and it serializes the same way.
+        let (again, again_len) = region(&loaded);
+        assert_eq!(again[..again_len], buf[..len]);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let (mut buf, _) = region(&BootConfig::default());
+        buf[0] ^= 0xFF;
+        assert_eq!(
+            BootConfig::from_bytes(&buf).unwrap_err(),
+            BootConfigError::BadMagic
+        );
+        assert_default(load(&buf));
+
+        // An uninitialized (zeroed) region, and anything too short for the magic
+        assert_default(load(&[0; REGION_LEN]));
+        assert_default(load(&[]));
+        assert_default(load(&BootConfig::MAGIC[..2]));
+    }
+
+    #[test]
+    fn length_larger_than_region() {
+        let (mut buf, len) = region(&BootConfig::default());
+        set_len(&mut buf, (REGION_LEN - BootConfig::HEADER_LEN + 1) as u32);
+        assert_eq!(
+            BootConfig::from_bytes(&buf).unwrap_err(),
+            BootConfigError::Truncated
+        );
+        assert_default(load(&buf));
+
+        set_len(&mut buf, u32::MAX);
+        assert_eq!(
+            BootConfig::from_bytes(&buf).unwrap_err(),
+            BootConfigError::Truncated
+        );
+
+        // The region ends in the middle of the header or payload.
+        let (buf, _) = region(&BootConfig::default());
+        for end in BootConfig::MAGIC.len()..len {
+            assert_eq!(
+                BootConfig::from_bytes(&buf[..end]).unwrap_err(),
+                BootConfigError::Truncated
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let (buf, len) = region(&BootConfig::default());
+        let payload_len = len - BootConfig::HEADER_LEN;
+        // The header claims a shorter payload than the config needs.
+        for short in 0..payload_len {
+            let mut buf = buf;
+            set_len(&mut buf, short as u32);
+            assert!(matches!(
+                BootConfig::from_bytes(&buf),
+                Err(BootConfigError::Postcard(_))
+            ));
+            assert_default(load(&buf));
+        }
+    }
+}
@@ -8,3 +8,4 @@
 
 pub mod sermux;
 pub mod shells;
+pub mod task_stats;
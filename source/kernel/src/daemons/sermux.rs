@@ -4,6 +4,8 @@
 
 use core::time::Duration;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
     services::serial_mux::{PortHandle, WellKnown},
     tracing, Kernel,
@@ -14,12 +16,13 @@ use crate::{
 //
 
 /// Loopback Settings
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoopbackSettings {
     /// Port number. Defaults to [WellKnown::Loopback]
     pub port: u16,
     /// Buffer size, in bytes. Defaults to 128
     pub buffer_size: usize,
+    #[serde(skip)]
     _priv: (),
 }
 
@@ -61,16 +64,22 @@ pub async fn loopback(kernel: &'static Kernel, settings: LoopbackSettings) {
 //
 
 /// Hello Server Settings
-#[derive(Debug, Clone)]
+///
+/// When (de)serialized, e.g. as part of a [BootConfig](crate::config::BootConfig),
+/// the `message` is left out, and always has its default value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct HelloSettings {
     /// Port number. Defaults to [WellKnown::HelloWorld]
     pub port: u16,
     /// Buffer size, in bytes. Defaults to 32
     pub buffer_size: usize,
     /// Message to print. Defaults to `b"hello\r\n"`
+    #[serde(skip)]
     pub message: &'static [u8],
     /// Interval between messages. Defaults to 1 second
     pub interval: Duration,
+    #[serde(skip)]
     _priv: (),
 }
 
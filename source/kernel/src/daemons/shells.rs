@@ -10,6 +10,7 @@ use crate::{
         emb_display::EmbDisplayClient,
         serial_mux::{PortHandle, WellKnown},
     },
+    tasks::Priority,
     tracing, Kernel,
 };
 use embedded_graphics::{
@@ -24,11 +25,12 @@ use embedded_graphics::{
 use futures::FutureExt;
 use input_mgr::RingLine;
 use profont::PROFONT_12_POINT;
+use serde::{Deserialize, Serialize};
 
 use crate::forth::Forth;
 
 /// Settings for the [sermux_shell] daemon
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SermuxShellSettings {
     /// Sermux port to serve the shell on
     ///
@@ -43,6 +45,7 @@ pub struct SermuxShellSettings {
     /// Uses the default value of [Params]
     pub forth_settings: Params,
     /// Hidden for forwards compat
+    #[serde(skip)]
     _priv: (),
 }
 
@@ -70,8 +73,8 @@ pub async fn sermux_shell(k: &'static Kernel, settings: SermuxShellSettings) {
     let (task, tid_io) = Forth::new(k, forth_settings)
         .await
         .expect("Forth spawning must succeed");
-    k.spawn(task.run()).await;
-    k.spawn(async move {
+    k.spawn(Priority::User, task.run()).await;
+    k.spawn(Priority::User, async move {
         loop {
             futures::select_biased! {
                 rgr = port.consumer().read_grant().fuse() => {
@@ -106,7 +109,10 @@ pub async fn sermux_shell(k: &'static Kernel, settings: SermuxShellSettings) {
 /// };
 /// # drop(shell);
 /// ```
-#[derive(Debug)]
+///
+/// When (de)serialized, e.g. as part of a [BootConfig](crate::config::BootConfig),
+/// the `font` is left out, and always has its default value.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GraphicalShellSettings {
     /// Sermux port to use as a PseudoKeyboard.
     ///
@@ -127,8 +133,10 @@ pub struct GraphicalShellSettings {
     /// Font used for the shell
     ///
     /// Defaults to [PROFONT_12_POINT]
+    #[serde(skip, default = "default_font")]
     pub font: MonoFont<'static>,
     /// Hidden for forwards compat
+    #[serde(skip)]
     _priv: (),
 }
 
@@ -146,6 +154,10 @@ impl GraphicalShellSettings {
     }
 }
 
+fn default_font() -> MonoFont<'static> {
+    PROFONT_12_POINT
+}
+
 /// Spawns a graphical shell using the [EmbDisplayService](crate::services::emb_display::EmbDisplayService) service
 #[tracing::instrument(skip(k))]
 pub async fn graphical_shell_mono(k: &'static Kernel, settings: GraphicalShellSettings) {
@@ -219,7 +231,7 @@ pub async fn graphical_shell_mono(k: &'static Kernel, settings: GraphicalShellSe
         .expect("Forth spawning must succeed");
 
     // Spawn the forth task
-    k.spawn(task.run()).await;
+    k.spawn(Priority::User, task.run()).await;
 
     loop {
         // Wait until there is a frame buffer ready. There wouldn't be if we've spammed frames
@@ -0,0 +1,140 @@
+//! Task statistics daemon
+//!
+//! Periodically logs a summary of the [task table][crate::tasks], including
+//! how much of the last interval each task spent being polled. The summary is
+//! emitted as `tracing` events, so it shows up wherever the kernel's traces go,
+//! e.g. over the [tracing port][crate::services::serial_mux::WellKnown::BinaryTracing].
+
+use core::time::Duration;
+
+use mnemos_alloc::containers::FixedVec;
+use serde::{Deserialize, Serialize};
+
+use crate::{tasks::TaskInfo, tracing, Kernel};
+
+/// Task Statistics Settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskStatsSettings {
+    /// Interval between summaries. Defaults to 10 seconds
+    pub interval: Duration,
+    #[serde(skip)]
+    _priv: (),
+}
+
+impl Default for TaskStatsSettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            _priv: (),
+        }
+    }
+}
+
+/// Spawns the task statistics daemon
+///
+/// Every `interval`, logs one event with the totals for the interval, and one
+/// event per running task with its poll count, busy time, and the number of
+/// times it exceeded the [poll budget][crate::KernelSettings::poll_budget].
+#[tracing::instrument(skip(kernel))]
+pub async fn task_stats(kernel: &'static Kernel, settings: TaskStatsSettings) {
+    let TaskStatsSettings { interval, _priv } = settings;
+    tracing::info!("Task statistics running!");
+
+    let mut last_uptime = kernel.uptime();
+    let mut last: FixedVec<TaskInfo> = kernel.tasks().await;
+
+    loop {
+        kernel.sleep(interval).await;
+
+        let uptime = kernel.uptime();
+        let elapsed = uptime.saturating_sub(last_uptime);
+        let tasks = kernel.tasks().await;
+
+        let mut busy_total = Duration::ZERO;
+        for task in tasks.as_slice() {
+            let (busy, polls) = since(last.as_slice(), task);
+            busy_total += busy;
+            tracing::info!(
+                task.id = task.id.id(),
+                task.name = task.name.unwrap_or("<unnamed>"),
+                task.priority = ?task.priority,
+                polls,
+                busy_us = busy.as_micros() as u64,
+                load_pct = percent(busy, elapsed),
+                total_polls = task.polls,
+                total_busy_ms = task.busy.as_millis() as u64,
+                overruns = task.overruns,
+            );
+        }
+        tracing::info!(
+            tasks = tasks.as_slice().len(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            busy_us = busy_total.as_micros() as u64,
+            load_pct = percent(busy_total, elapsed),
+            "Task summary"
+        );
+
+        last_uptime = uptime;
+        last = tasks;
+    }
+}
+
+/// Returns the busy time and polls of `task` since the `last` snapshot.
+fn since(last: &[TaskInfo], task: &TaskInfo) -> (Duration, u64) {
+    match last.iter().find(|t| t.id == task.id) {
+        Some(prev) => (
+            task.busy.saturating_sub(prev.busy),
+            task.polls.saturating_sub(prev.polls),
+        ),
+        // The task was spawned during this interval
+        None => (task.busy, task.polls),
+    }
+}
+
+fn percent(busy: Duration, elapsed: Duration) -> u64 {
+    match elapsed.as_nanos() {
+        0 => 0,
+        elapsed => (busy.as_nanos() * 100 / elapsed) as u64,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tasks::{Priority, TaskId};
+
+    fn info(id: u32, polls: u64, busy_ms: u64) -> TaskInfo {
+        TaskInfo {
+            id: TaskId(id),
+            name: None,
+            priority: Priority::User,
+            spawned_at: Duration::ZERO,
+            polls,
+            busy: Duration::from_millis(busy_ms),
+            overruns: 0,
+            heap: None,
+        }
+    }
+
+    #[test]
+    fn since_last_snapshot() {
+        let last = [info(0, 10, 100), info(1, 5, 50)];
+        assert_eq!(
+            since(&last, &info(1, 8, 80)),
+            (Duration::from_millis(30), 3)
+        );
+        // New tasks count from when they were spawned.
+        assert_eq!(
+            since(&last, &info(2, 4, 40)),
+            (Duration::from_millis(40), 4)
+        );
+    }
+
+    #[test]
+    fn load() {
+        let second = Duration::from_secs(1);
+        assert_eq!(percent(Duration::from_millis(250), second), 25);
+        assert_eq!(percent(second, second), 100);
+        assert_eq!(percent(second, Duration::ZERO), 0);
+    }
+}
@@ -1,11 +1,14 @@
-use crate::services::forth_spawnulator::SpawnulatorClient;
+use crate::services::{
+    clock::{ClockClient, ClockError},
+    forth_spawnulator::SpawnulatorClient,
+};
 use crate::tracing;
 use crate::{
     comms::bbq,
     services::serial_mux::{PortHandle, SerialMuxClient},
     Kernel,
 };
-use core::{any::TypeId, future::Future, ptr::NonNull, time::Duration};
+use core::{any::TypeId, fmt::Write, future::Future, ptr::NonNull, time::Duration};
 use forth3::{
     async_builtin,
     dictionary::{self, AsyncBuiltinEntry, AsyncBuiltins, Dictionary, OwnedDict},
@@ -18,10 +21,13 @@ use forth3::{
 use mnemos_alloc::{
     containers::{ArrayBuf, Box, FixedVec},
     heap::{alloc, dealloc},
+    leaks::{self, Tag},
+    quota::{self, Account},
 };
 use portable_atomic::{AtomicUsize, Ordering};
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 #[non_exhaustive]
 pub struct Params {
     pub stack_size: usize,
@@ -32,6 +38,13 @@ pub struct Params {
     pub stdout_capacity: usize,
     pub bag_of_holding_capacity: usize,
     pub spawnulator_timeout: Duration,
+    /// Limit on the heap usage of the VM's task, in bytes, see
+    /// [`Kernel::set_heap_quota()`]. Defaults to `None`.
+    ///
+    /// Memory allocated while creating the VM is charged to the task that
+    /// created it, not to the VM.
+    pub heap_quota: Option<usize>,
+    #[serde(skip)]
     _priv: (),
 }
 
@@ -108,6 +121,13 @@ impl Forth {
     )]
     pub async fn run(mut self) {
         tracing::info!("VM running");
+        if let Some(limit) = self.forth.host_ctxt().params.heap_quota {
+            // We are being polled by our own task, so this is its account.
+            match Account::current() {
+                Some(account) => account.set_limit(Some(limit)),
+                None => tracing::warn!(limit, "VM task has no heap quota account"),
+            }
+        }
         loop {
             self.forth.output_mut().clear();
 
@@ -168,6 +188,8 @@ pub(crate) struct MnemosContext {
     id: usize,
     /// Handle for spawning child tasks.
     spawnulator: SpawnulatorClient,
+    /// Handle for the clock service, obtained the first time it's needed.
+    clock: Option<ClockClient>,
 }
 
 impl MnemosContext {
@@ -179,6 +201,11 @@ impl MnemosContext {
 #[derive(Copy, Clone)]
 pub(crate) struct Dispatcher;
 
+/// Tags the allocations of spawned child VMs, see [`leaks`].
+static SPAWN_TAG: Tag = Tag("forth::spawn");
+/// Tags the items in a [`BagOfHolding`], see [`leaks`].
+static BOH_TAG: Tag = Tag("forth::boh");
+
 struct DropDict;
 
 impl<'forth> AsyncBuiltins<'forth, MnemosContext> for Dispatcher {
@@ -194,6 +221,28 @@ impl<'forth> AsyncBuiltins<'forth, MnemosContext> for Dispatcher {
         async_builtin!("sleep::ms"),
         // sleep for a number of seconds
         async_builtin!("sleep::s"),
+        // number of registered driver services
+        async_builtin!("svc::count"),
+        // print a driver service's request metrics
+        async_builtin!("svc::metrics"),
+        // list running tasks
+        async_builtin!("ps"),
+        // kill a task by ID
+        async_builtin!("kill"),
+        // print the time since boot
+        async_builtin!("uptime"),
+        // print the wall-clock time
+        async_builtin!("time"),
+        // set the wall-clock time, in seconds since the UNIX epoch
+        async_builtin!("time::set"),
+        // print heap statistics
+        async_builtin!("heap"),
+        // limit a task's heap usage
+        async_builtin!("heap::quota"),
+        // push the current leak tracing sequence number
+        async_builtin!("heap::mark"),
+        // trace live allocations made since a sequence number
+        async_builtin!("heap::leaks"),
     ];
 
     fn dispatch_async(
@@ -205,10 +254,21 @@ impl<'forth> AsyncBuiltins<'forth, MnemosContext> for Dispatcher {
             match id.as_str() {
                 "sermux::open_port" => sermux_open_port(forth).await,
                 "sermux::write_outbuf" => sermux_write_outbuf(forth).await,
-                "spawn" => spawn_forth_task(forth).await,
                 "sleep::us" => sleep(forth, Duration::from_micros).await,
                 "sleep::ms" => sleep(forth, Duration::from_millis).await,
                 "sleep::s" => sleep(forth, Duration::from_secs).await,
+                "svc::count" => service_count(forth).await,
+                "svc::metrics" => service_metrics(forth).await,
+                "ps" => list_tasks(forth).await,
+                "kill" => kill_task(forth).await,
+                "uptime" => uptime(forth).await,
+                "time" => time(forth).await,
+                "time::set" => set_time(forth).await,
+                "heap" => heap_stats(forth).await,
+                "heap::quota" => heap_quota(forth).await,
+                "heap::mark" => heap_mark(forth).await,
+                "heap::leaks" => heap_leaks(forth).await,
+                "spawn" => leaks::tagged(&SPAWN_TAG, spawn_forth_task(forth)).await,
                 _ => {
                     tracing::warn!("unimplemented async builtin: {}", id.as_str());
                     Err(forth3::Error::WordNotInDict)
@@ -230,6 +290,7 @@ impl Params {
             stdout_capacity: 1024,
             bag_of_holding_capacity: 16,
             spawnulator_timeout: Duration::from_secs(5),
+            heap_quota: None,
             _priv: (),
         }
     }
@@ -288,6 +349,7 @@ impl MnemosContext {
                 )
                 .await
                 .expect("Spawnulator client timed out - is the spawnulator running?"),
+            clock: None,
         }
     }
 }
@@ -495,6 +557,314 @@ async fn sleep(
     Ok(())
 }
 
+/// Binding for [`Registry::len()`][crate::registry::Registry::len]
+///
+/// Call: `svc::count`
+/// Return: the number of registered driver services on the stack
+async fn service_count(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let count = forth
+        .host_ctxt
+        .kernel
+        .with_registry(|reg| reg.services().count())
+        .await;
+    forth.data_stack.push(Word::data(count as i32))?;
+    Ok(())
+}
+
+/// Binding for [`Registry::services()`][crate::registry::Registry::services]
+///
+/// Prints the request metrics of the `IDX`th registered driver service, in
+/// UUID order, to the output buffer.
+///
+/// Call: `IDX svc::metrics`
+/// Return: No change
+///
+/// Errors if there is no driver service at `IDX`. Use `svc::count` to find
+/// out how many services are registered.
+async fn service_metrics(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let idx = forth.data_stack.try_pop()?.as_usize()?;
+    let info = forth
+        .host_ctxt
+        .kernel
+        .with_registry(|reg| reg.services().nth(idx))
+        .await
+        .ok_or(forth3::Error::InternalError)?;
+    let m = info.metrics;
+    writeln!(
+        &mut forth.output,
+        "{} req={} rep={} err={} full={} depth={}",
+        info.uuid, m.requests, m.replies, m.reply_errors, m.queue_full, m.max_queue_depth,
+    )?;
+    Ok(())
+}
+
+/// Binding for [`Kernel::tasks()`]
+///
+/// Prints the ID, poll count, busy time, heap usage, and name of every running
+/// task to the output buffer, one task per line. Heap usage is shown as
+/// `allocated/limit` bytes if the task has a quota, and as `?` if its
+/// allocations are not accounted for. If the output buffer fills up, the remaining
+/// tasks are left out and the list ends with `...`.
+///
+/// Call: `ps`
+/// Return: No change
+async fn list_tasks(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let tasks = forth.host_ctxt.kernel.tasks().await;
+    for task in tasks.as_slice() {
+        let res = write!(
+            &mut forth.output,
+            "{} polls={} busy={}ms heap=",
+            task.id.id(),
+            task.polls,
+            task.busy.as_millis(),
+        )
+        .and_then(|_| match task.heap {
+            Some(quota::Usage {
+                allocated,
+                limit: Some(limit),
+                ..
+            }) => write!(&mut forth.output, "{allocated}/{limit}"),
+            Some(usage) => write!(&mut forth.output, "{}", usage.allocated),
+            None => write!(&mut forth.output, "?"),
+        })
+        .and_then(|_| writeln!(&mut forth.output, " {}", task.name.unwrap_or("<unnamed>")));
+        if res.is_err() {
+            let _ = forth.output.push_str("...");
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Binding for [`Kernel::kill()`]
+///
+/// Kills the task with the given ID, as listed by `ps`.
+///
+/// Call: `ID kill`
+/// Return: No change
+///
+/// Errors if `ID` is negative, or there is no running task with that ID.
+async fn kill_task(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let id = forth.data_stack.try_pop()?.as_i32();
+    let id = u32::try_from(id).map_err(|_| forth3::Error::WordToUsizeInvalid(id))?;
+    let id = crate::tasks::TaskId(id);
+    if !forth.host_ctxt.kernel.kill(id).await {
+        tracing::warn!(task.id = id.id(), "No such task to kill");
+        return Err(forth3::Error::InternalError);
+    }
+    Ok(())
+}
+
+/// Binding for [`Kernel::set_heap_quota()`]
+///
+/// Limits the heap usage of the task with the given ID, as listed by `ps`,
+/// to `BYTES` bytes. A negative `BYTES` removes the task's limit.
+///
+/// Call: `ID BYTES heap::quota`
+/// Return: No change
+///
+/// Errors if `ID` is negative, there is no running task with that ID, or its
+/// allocations are not accounted for.
+async fn heap_quota(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let bytes = forth.data_stack.try_pop()?.as_i32();
+    let id = forth.data_stack.try_pop()?.as_i32();
+    let id = u32::try_from(id).map_err(|_| forth3::Error::WordToUsizeInvalid(id))?;
+    let id = crate::tasks::TaskId(id);
+    let limit = usize::try_from(bytes).ok();
+    if !forth.host_ctxt.kernel.set_heap_quota(id, limit).await {
+        tracing::warn!(task.id = id.id(), "No such task to limit");
+        return Err(forth3::Error::InternalError);
+    }
+    Ok(())
+}
+
+/// Binding for [`ClockClient::uptime()`]
+///
+/// Prints the time since the kernel started to the output buffer.
+///
+/// Call: `uptime`
+/// Return: No change
+///
+/// Errors if there is no clock service.
+async fn uptime(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let uptime = clock(&mut forth.host_ctxt).await?.uptime().await;
+    let uptime = uptime.map_err(|error| clock_error(&mut forth.host_ctxt, error))?;
+    let secs = uptime.as_secs();
+    writeln!(
+        &mut forth.output,
+        "up {}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60,
+        uptime.subsec_millis(),
+    )?;
+    Ok(())
+}
+
+/// Binding for [`ClockClient::wall_time()`]
+///
+/// Prints the wall-clock time, in seconds since the UNIX epoch, to the output
+/// buffer, or `unset` if the wall clock has not been set.
+///
+/// Call: `time`
+/// Return: No change
+///
+/// Errors if there is no clock service.
+async fn time(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let wall = clock(&mut forth.host_ctxt).await?.wall_time().await;
+    let wall = wall.map_err(|error| clock_error(&mut forth.host_ctxt, error))?;
+    match wall {
+        Some(wall) => writeln!(
+            &mut forth.output,
+            "{}.{:03}",
+            wall.as_secs(),
+            wall.subsec_millis()
+        )?,
+        None => writeln!(&mut forth.output, "unset")?,
+    }
+    Ok(())
+}
+
+/// Binding for [`ClockClient::set_wall_clock()`]
+///
+/// Call: `SECS time::set`
+/// Return: No change
+///
+/// Errors if there is no clock service, or if `SECS` is negative.
+async fn set_time(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let secs = forth.data_stack.try_pop()?.as_usize()?;
+    let set = clock(&mut forth.host_ctxt)
+        .await?
+        .set_wall_clock(Duration::from_secs(secs as u64))
+        .await;
+    set.map_err(|error| {
+        tracing::warn!(?error, "Failed to set the wall clock");
+        clock_error(&mut forth.host_ctxt, error)
+    })
+}
+
+/// Binding for [`Kernel::heap_stats()`]
+///
+/// Prints the heap usage, allocation counters, and the largest free block
+/// to the output buffer.
+///
+/// Call: `heap`
+/// Return: No change
+///
+/// Errors if the platform did not provide heap statistics.
+async fn heap_stats(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let Some(stats) = forth.host_ctxt.kernel.heap_stats() else {
+        tracing::warn!("No heap statistics available");
+        return Err(forth3::Error::InternalError);
+    };
+    writeln!(
+        &mut forth.output,
+        "used={}B peak={}B size={}B",
+        stats.allocated, stats.peak_allocated, stats.heap_size,
+    )?;
+    writeln!(
+        &mut forth.output,
+        "allocs={} failed={} inhibited={}",
+        stats.allocs, stats.failed_allocs, stats.inhibited,
+    )?;
+    match (stats.largest_free_block, stats.fragmentation_pct()) {
+        (Some(largest), Some(frag)) => {
+            writeln!(&mut forth.output, "largest_free={largest}B frag={frag}%")?
+        }
+        (Some(largest), None) => writeln!(&mut forth.output, "largest_free={largest}B")?,
+        (None, _) => {}
+    }
+    Ok(())
+}
+
+/// Binding for [`leaks::mark()`]
+///
+/// Call: `heap::mark`
+/// Return: the sequence number of the next allocation on the stack, for use
+/// with `heap::leaks`
+///
+/// Errors if the kernel was built without the `leak-trace` feature, or if
+/// the sequence number doesn't fit in a [`Word`].
+async fn heap_mark(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    if !leaks::ENABLED {
+        tracing::warn!("Leak tracing is disabled, enable the `leak-trace` feature");
+        return Err(forth3::Error::InternalError);
+    }
+    let mark = leaks::mark();
+    let mark = i32::try_from(mark).map_err(|_| forth3::Error::UsizeToWordInvalid(mark))?;
+    forth.data_stack.push(Word::data(mark))?;
+    Ok(())
+}
+
+/// Binding for [`leaks::for_each_since()`]
+///
+/// Emits a trace event for every live allocation made since sequence number
+/// `SEQ`, which can be used to find leaks offline (e.g. in crowtty's
+/// output), and prints the number of allocations and bytes to the output
+/// buffer. Use `0` for every live allocation.
+///
+/// Call: `SEQ heap::leaks`
+/// Return: No change
+///
+/// Errors if the kernel was built without the `leak-trace` feature.
+async fn heap_leaks(forth: &mut forth3::Forth<MnemosContext>) -> Result<(), forth3::Error> {
+    let seq = forth.data_stack.try_pop()?.as_usize()?;
+    if !leaks::ENABLED {
+        tracing::warn!("Leak tracing is disabled, enable the `leak-trace` feature");
+        return Err(forth3::Error::InternalError);
+    }
+    let (mut count, mut bytes) = (0, 0);
+    leaks::for_each_since(seq, |alloc| {
+        count += 1;
+        bytes += alloc.size;
+        tracing::info!(
+            seq = alloc.seq,
+            addr = alloc.addr,
+            size = alloc.size,
+            align = alloc.align,
+            task.id = ?alloc.owner,
+            tag = alloc.tag.unwrap_or("<none>"),
+            "Live allocation"
+        );
+    });
+    write!(&mut forth.output, "{count} allocs, {bytes}B since {seq}")?;
+    if let Some(stats) = leaks::stats() {
+        if stats.untracked > 0 {
+            write!(&mut forth.output, " ({} untracked)", stats.untracked)?;
+        }
+        if stats.lost_frees > 0 {
+            write!(&mut forth.output, " ({} lost frees)", stats.lost_frees)?;
+        }
+    }
+    writeln!(&mut forth.output)?;
+    Ok(())
+}
+
+/// Returns the VM's [`ClockClient`], getting one from the registry the first
+/// time it's needed.
+async fn clock(ctxt: &mut MnemosContext) -> Result<&mut ClockClient, forth3::Error> {
+    let client = match ctxt.clock.take() {
+        Some(client) => client,
+        None => ClockClient::from_registry_no_retry(ctxt.kernel)
+            .await
+            .ok_or_else(|| {
+                tracing::warn!("No clock service registered");
+                forth3::Error::InternalError
+            })?,
+    };
+    Ok(ctxt.clock.insert(client))
+}
+
+/// Converts a [`ClockError`]. If the request itself failed (e.g. because
+/// the clock service went away), the VM's client is dropped, so that the
+/// next clock word gets a new one.
+fn clock_error(ctxt: &mut MnemosContext, error: ClockError) -> forth3::Error {
+    if error == ClockError::InternalError {
+        ctxt.clock = None;
+    }
+    forth3::Error::InternalError
+}
+
 impl dictionary::DropDict for DropDict {
     unsafe fn drop_dict(ptr: NonNull<u8>, layout: core::alloc::Layout) {
         dealloc(ptr.as_ptr().cast(), layout);
@@ -572,7 +942,8 @@ impl BagOfHolding {
         if self.inner.is_full() {
             return None;
         }
-        let value_ptr = NonNull::new(Box::into_raw(Box::new(item).await))?.cast();
+        let boxed = leaks::tagged(&BOH_TAG, Box::new(item)).await;
+        let value_ptr = NonNull::new(Box::into_raw(boxed))?.cast();
         let idx = self.next_idx();
         let tid = TypeId::of::<T>();
 
@@ -37,9 +37,21 @@
 //! [`Kernel::initialize()`], which takes a future and spawns it on the executor. Futures added with
 //! `initialize` still do not run until the later "running" phase.
 //!
+//! Every task is spawned with a [`Priority`]. Drivers and other kernel services should use
+//! [`Priority::System`], so that they keep running promptly even while [`Priority::User`] tasks,
+//! such as Forth shells, are busy.
+//!
 //! Right now, it is generally suggested you use one or more `initialize`
 //! calls to register all (initial) kernel services.
 //!
+//! The order of `initialize` calls does not matter for services that depend on each
+//! other: a service (or its clients) can use [`Kernel::wait_for_service()`] to sleep
+//! until another service has been registered.
+//!
+//! The platform-independent services and daemons can also be described by a
+//! [`BootConfig`](config::BootConfig), which the platform may load as bytes at
+//! boot, and which spawns them with `initialize` calls of its own.
+//!
 //! ## Running mode
 //!
 //! Once everything is prepared and initialized, the startup code is expected to call
@@ -49,9 +61,19 @@
 //!   asynchronous allocation
 //! * The async executor is polled
 //!
-//! AT THE MOMENT, there is no indication of whether all tasks are blocked, which could be use to
-//! inform whether we should put the CPU into some kind of sleep mode until a hardware event (like
-//! a timer or DMA transaction) is triggered, and an async task has potentially been awoken.
+//! The kernel's timer must also be advanced, by however much time passed. Rather than doing
+//! both by hand, the startup code will usually call [`Kernel::run_until_idle()`], which ticks
+//! the scheduler and advances the timer until all tasks are blocked, and then returns an
+//! [`Idle`] describing when the next timer deadline is. At that point, the CPU can be put into
+//! some kind of sleep mode until either that deadline, or a hardware event (like a DMA
+//! transaction) that may have woken an async task, whichever comes first. After waking up,
+//! the time spent asleep is accounted for with [`Kernel::advance_timer()`].
+//!
+//! ## Shutting down
+//!
+//! Any task may request a [shutdown] with [`Kernel::shutdown()`]. The startup code should
+//! check [`Kernel::shutdown_complete()`] as it runs the kernel, and once that returns a
+//! [`ShutdownReason`], stop running the kernel and halt or reboot the system.
 //!
 //! ## Not covered: "userspace"
 //!
@@ -70,24 +92,29 @@
 #![no_std]
 #![allow(clippy::missing_safety_doc)]
 #![feature(impl_trait_in_assoc_type)]
+#![feature(panic_info_message)]
 
 extern crate alloc;
 
 pub mod comms;
+pub mod config;
 pub mod daemons;
 pub(crate) mod fmt;
 pub mod forth;
 pub mod isr;
 pub mod registry;
 pub mod services;
+pub mod shutdown;
+pub mod tasks;
 #[cfg(feature = "tracing-02")]
 pub mod trace;
+pub mod watchdog;
 
 use abi::{
     bbqueue_ipc::BBBuffer,
     syscall::{KernelResponse, UserRequest},
 };
-use comms::kchannel::KChannel;
+use comms::{bbq, kchannel::KChannel};
 use core::{future::Future, ptr::NonNull};
 pub use maitake;
 use maitake::{
@@ -97,8 +124,16 @@ use maitake::{
     time::{Duration, Sleep, Timeout, Timer},
 };
 pub use mnemos_alloc;
-use mnemos_alloc::containers::Box;
-use registry::Registry;
+use mnemos_alloc::{
+    containers::{Box, FixedVec},
+    heap::HeapStats,
+};
+use mycelium_util::sync::InitOnce;
+use portable_atomic::{AtomicU64, Ordering};
+use registry::{RegisteredDriver, Registry, ServiceInfo, UserHandles};
+use shutdown::{ShutdownGuard, ShutdownReason, ShutdownState};
+use tasks::{Killed, PollClock, Priority, TaskId, TaskInfo, TaskTable, Tracked};
+use watchdog::Watchdog;
 
 /// Shim to handle tracing v0.1 vs v0.2
 ///
@@ -142,7 +177,39 @@ pub struct Rings {
 
 pub struct KernelSettings {
     pub max_drivers: usize,
+    /// The maximum number of tasks recorded in the task table, see [tasks]
+    pub max_tasks: usize,
     pub timer_granularity: Duration,
+    /// A free-running counter, in ticks of [`timer_granularity`], which is
+    /// used to measure how long each task spends being polled. The counter
+    /// may wrap.
+    ///
+    /// Busy time is only ever measured with this clock, not with the kernel's
+    /// timer, which doesn't advance while a task is being polled. If `None`,
+    /// the [task table][tasks] does not record busy time: every busy time
+    /// statistic (such as [`TaskInfo::busy`], and the busy time and load
+    /// reported by the [`task_stats`][daemons::task_stats] daemon) reads
+    /// zero, and no poll is ever reported as exceeding the
+    /// [`poll_budget`](KernelSettings::poll_budget). Poll counts are
+    /// recorded either way.
+    ///
+    /// [`timer_granularity`]: KernelSettings::timer_granularity
+    pub busy_clock: Option<fn() -> u32>,
+    /// The longest a single poll of a task may take before it is reported by
+    /// the [watchdog]. Requires a [`busy_clock`](KernelSettings::busy_clock).
+    pub poll_budget: Option<Duration>,
+}
+
+/// Returned by [`Kernel::run_until_idle()`] once all tasks are blocked.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Idle {
+    /// The number of timer ticks until the next timer deadline, or `None` if
+    /// no timers are pending.
+    ///
+    /// The platform may sleep until this deadline, or until an interrupt
+    /// occurs, whichever comes first. Timer ticks have the duration set by
+    /// [`KernelSettings::timer_granularity`].
+    pub next_deadline: Option<u64>,
 }
 
 pub struct Message {
@@ -156,17 +223,40 @@ pub struct Kernel {
     inner: KernelInner,
     /// The run-time driver registry, accessed via an async Mutex
     registry: Mutex<Registry>,
+    /// The table of running tasks, accessed via an async Mutex
+    tasks: Mutex<TaskTable>,
 }
 
 unsafe impl Sync for Kernel {}
 
 pub struct KernelInner {
-    /// MnemOS currently only targets single-threaded platforms, so we can use a
-    /// `maitake` scheduler capable of running `!Send` futures.
-    scheduler: LocalScheduler,
+    /// MnemOS currently only targets single-threaded platforms, so we can use
+    /// `maitake` schedulers capable of running `!Send` futures.
+    ///
+    /// This one runs [`Priority::System`] tasks.
+    system: LocalScheduler,
+
+    /// The scheduler for [`Priority::User`] tasks, which only run once no
+    /// system tasks are ready.
+    user: LocalScheduler,
 
     /// Maitake timer wheel.
     timer: Timer,
+
+    /// The duration of a single timer tick.
+    timer_granularity: Duration,
+
+    /// The total number of ticks the timer has been advanced by.
+    uptime_ticks: AtomicU64,
+
+    /// The hardware watchdog, if the platform has set one.
+    watchdog: InitOnce<&'static dyn Watchdog>,
+
+    /// Whether, and why, a [shutdown] was requested.
+    shutdown: ShutdownState,
+
+    /// Reads the global allocator's statistics, if the platform has set it.
+    heap_stats: InitOnce<fn() -> HeapStats>,
 }
 
 impl Kernel {
@@ -177,16 +267,31 @@ impl Kernel {
     pub unsafe fn new(settings: KernelSettings) -> Result<Box<Self>, &'static str> {
         let registry = registry::Registry::new(settings.max_drivers);
 
-        let scheduler = LocalScheduler::new();
+        let clock = settings.busy_clock.map(|now| {
+            let mut clock = PollClock {
+                now,
+                granularity: settings.timer_granularity,
+                budget: None,
+            };
+            clock.budget = settings.poll_budget.map(|budget| clock.ticks(budget));
+            clock
+        });
 
         let inner = KernelInner {
-            scheduler,
+            system: LocalScheduler::new(),
+            user: LocalScheduler::new(),
             timer: Timer::new(settings.timer_granularity),
+            timer_granularity: settings.timer_granularity,
+            uptime_ticks: AtomicU64::new(0),
+            watchdog: InitOnce::uninitialized(),
+            shutdown: ShutdownState::new(),
+            heap_stats: InitOnce::uninitialized(),
         };
 
         let new_kernel = Box::try_new(Kernel {
             inner,
             registry: Mutex::new(registry),
+            tasks: Mutex::new(TaskTable::new(settings.max_tasks, clock)),
         })
         .map_err(|_| "Kernel allocation failed.")?;
 
@@ -203,12 +308,202 @@ impl Kernel {
         &self.inner.timer
     }
 
+    /// Tick the schedulers.
+    ///
+    /// [`Priority::System`] tasks are always run first. [`Priority::User`]
+    /// tasks are only run once no system tasks are ready, after which any
+    /// system tasks they woke up are run too.
+    ///
+    /// If a hardware [watchdog] has been set, it is petted first.
+    ///
+    /// The returned [`Tick`][maitake::scheduler::Tick] adds up the counters of
+    /// every scheduler tick that was run.
     pub fn tick(&'static self) -> maitake::scheduler::Tick {
         let inner = self.inner();
-        inner.scheduler.tick()
+        if let Some(watchdog) = inner.watchdog.try_get() {
+            watchdog.pet();
+        }
+        let mut tick = inner.system.tick();
+        if tick.has_remaining {
+            // Come back to the user tasks once the system tasks are done.
+            return tick;
+        }
+
+        let user = inner.user.tick();
+        let system = inner.system.tick();
+        tick.has_remaining = user.has_remaining || system.has_remaining;
+        for other in [user, system] {
+            tick.polled += other.polled;
+            tick.completed += other.completed;
+            tick.spawned += other.spawned;
+            tick.woken_external += other.woken_external;
+            tick.woken_internal += other.woken_internal;
+        }
+        tick
         // TODO: Send time to userspace?
     }
 
+    fn scheduler(&'static self, priority: Priority) -> &'static LocalScheduler {
+        match priority {
+            Priority::System => &self.inner.system,
+            Priority::User => &self.inner.user,
+        }
+    }
+
+    /// Tick the scheduler once, and then advance the timer by the number of
+    /// timer ticks returned by `elapsed`, which is called right after the
+    /// scheduler tick.
+    ///
+    /// Returns `Some` if all tasks are now blocked, e.g. no task was left
+    /// ready to run, and no timer expired and woke up a task.
+    pub fn run_once(&'static self, elapsed: impl FnOnce() -> u64) -> Option<Idle> {
+        let tick = self.tick();
+        let ticks = elapsed();
+        self.inner.uptime_ticks.fetch_add(ticks, Ordering::Relaxed);
+        let turn = self.inner.timer.force_advance_ticks(ticks);
+        if turn.expired == 0 && !tick.has_remaining {
+            Some(Idle {
+                next_deadline: turn.ticks_to_next_deadline(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Run the kernel until all tasks are blocked.
+    ///
+    /// `elapsed` is called after every scheduler tick, and must return the
+    /// number of timer ticks that passed since it was last called. The
+    /// scheduler is ticked and the timer advanced, as in [`Kernel::run_once()`],
+    /// until no tasks are ready to run.
+    ///
+    /// The platform should then sleep until the returned [`Idle::next_deadline`]
+    /// or an interrupt, and account for the time spent sleeping with
+    /// [`Kernel::advance_timer()`].
+    pub fn run_until_idle(&'static self, mut elapsed: impl FnMut() -> u64) -> Idle {
+        loop {
+            if let Some(idle) = self.run_once(&mut elapsed) {
+                return idle;
+            }
+        }
+    }
+
+    /// Returns the number of timer ticks until the next timer deadline, or
+    /// `None` if no timers are pending.
+    pub fn next_deadline(&'static self) -> Option<u64> {
+        // Advancing by zero ticks doesn't move time forward, it just gives us
+        // a look at the timer wheel.
+        self.inner
+            .timer
+            .force_advance_ticks(0)
+            .ticks_to_next_deadline()
+    }
+
+    /// Advance the kernel's timer by `ticks`, e.g. after the platform slept
+    /// until an interrupt or the [next deadline][Self::next_deadline].
+    ///
+    /// Any timers that expire wake their tasks, which will run on the next
+    /// call to [`Kernel::tick()`].
+    pub fn advance_timer(&'static self, ticks: u64) {
+        self.inner.uptime_ticks.fetch_add(ticks, Ordering::Relaxed);
+        let _turn = self.inner.timer.force_advance_ticks(ticks);
+    }
+
+    /// Returns how long the kernel has been running, e.g. the total time the
+    /// timer has been advanced by.
+    ///
+    /// Only time accounted for through [`Kernel::run_once()`],
+    /// [`Kernel::run_until_idle()`] and [`Kernel::advance_timer()`] is counted.
+    pub fn uptime(&'static self) -> Duration {
+        let ticks = self.inner.uptime_ticks.load(Ordering::Relaxed);
+        let nanos = self.inner.timer_granularity.as_nanos() * u128::from(ticks);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// Request a [shutdown] of the kernel.
+    ///
+    /// Wakes every task waiting in [`Kernel::wait_for_shutdown()`], and gives
+    /// tasks holding a [`ShutdownGuard`] up to `timeout` to finish. Returns
+    /// `false` if a shutdown was already requested, in which case the original
+    /// reason and deadline are kept.
+    pub fn shutdown(&'static self, reason: ShutdownReason, timeout: Duration) -> bool {
+        let now = self.inner.uptime_ticks.load(Ordering::Relaxed);
+        let timeout_ticks = timeout.as_nanos() / self.inner.timer_granularity.as_nanos().max(1);
+        let deadline = now.saturating_add(u64::try_from(timeout_ticks).unwrap_or(u64::MAX));
+        if !self.inner.shutdown.request(reason, deadline) {
+            return false;
+        }
+        tracing::info!(?reason, ?timeout, "Shutdown requested");
+
+        // Make sure the kernel wakes up at the deadline, even if every
+        // guard-holding task is asleep.
+        let _ = self.scheduler(Priority::System).spawn(self.sleep(timeout));
+        true
+    }
+
+    /// Wait until a [shutdown] is requested, returning its reason.
+    ///
+    /// Resolves immediately if a shutdown was already requested.
+    pub async fn wait_for_shutdown(&'static self) -> ShutdownReason {
+        self.inner.shutdown.wait().await
+    }
+
+    /// Returns the reason for the [shutdown], if one was requested.
+    pub fn shutdown_requested(&'static self) -> Option<ShutdownReason> {
+        self.inner.shutdown.requested()
+    }
+
+    /// Take a [`ShutdownGuard`], which delays the completion of a [shutdown]
+    /// until it is dropped, or the shutdown deadline passes.
+    pub fn shutdown_guard(&'static self) -> ShutdownGuard {
+        self.inner.shutdown.guard()
+    }
+
+    /// Returns the reason for the [shutdown], once it has completed.
+    ///
+    /// The platform should check this after running the kernel, e.g. after
+    /// every [`Kernel::run_until_idle()`], and stop running the kernel once it
+    /// returns `Some`.
+    pub fn shutdown_complete(&'static self) -> Option<ShutdownReason> {
+        let now = self.inner.uptime_ticks.load(Ordering::Relaxed);
+        self.inner.shutdown.complete(now)
+    }
+
+    /// Set the hardware [watchdog], which is petted on every [`Kernel::tick()`].
+    ///
+    /// The watchdog can only be set once.
+    pub fn set_watchdog(
+        &'static self,
+        watchdog: &'static dyn Watchdog,
+    ) -> Result<(), &'static str> {
+        self.inner
+            .watchdog
+            .try_init(watchdog)
+            .map_err(|_| "watchdog already set")
+    }
+
+    /// Set the function used to read the global allocator's [`HeapStats`],
+    /// usually [`MnemosAlloc::stats()`][mnemos_alloc::heap::MnemosAlloc::stats]
+    /// of the platform's global allocator, with the
+    /// [largest free block][mnemos_alloc::heap::MnemosAlloc::largest_free_block]
+    /// filled in. It is only called from tasks, so it may use the heap.
+    ///
+    /// The kernel doesn't know the type of the global allocator, so it can
+    /// only report heap statistics (e.g. with the Forth `heap` word) once this
+    /// is set. It can only be set once.
+    pub fn set_heap_stats(&'static self, stats: fn() -> HeapStats) -> Result<(), &'static str> {
+        self.inner
+            .heap_stats
+            .try_init(stats)
+            .map_err(|_| "heap stats already set")
+    }
+
+    /// Returns the global allocator's [`HeapStats`], if
+    /// [`Kernel::set_heap_stats()`] was called.
+    pub fn heap_stats(&'static self) -> Option<HeapStats> {
+        self.inner.heap_stats.try_get().map(|stats| stats())
+    }
+
     /// Initialize the kernel's `maitake` timer as the global default timer.
     ///
     /// This allows the use of `sleep` and `timeout` free functions.
@@ -218,21 +513,114 @@ impl Kernel {
         maitake::time::set_global_timer(self.timer())
     }
 
-    pub fn initialize<F>(&'static self, fut: F) -> Result<JoinHandle<F::Output>, &'static str>
+    pub fn initialize<F>(
+        &'static self,
+        priority: Priority,
+        fut: F,
+    ) -> Result<JoinHandle<Result<F::Output, Killed>>, &'static str>
+    where
+        F: Future + 'static,
+    {
+        self.initialize_named(priority, None, fut)
+    }
+
+    /// Like [`Kernel::initialize()`], but records the task in the [task
+    /// table][tasks] under the given `name`.
+    pub fn initialize_named<F>(
+        &'static self,
+        priority: Priority,
+        name: Option<&'static str>,
+        fut: F,
+    ) -> Result<JoinHandle<Result<F::Output, Killed>>, &'static str>
     where
         F: Future + 'static,
     {
-        Ok(self.inner.scheduler.spawn(fut))
+        // Nothing else is running before the kernel starts, so the task table
+        // lock can't be contended.
+        let mut tasks = self
+            .tasks
+            .try_lock()
+            .ok_or("task table locked during initialization")?;
+        let fut = tasks.track(priority, name, self.uptime(), fut);
+        Ok(self.scheduler(priority).spawn(fut))
     }
 
-    pub async fn spawn<F>(&'static self, fut: F) -> JoinHandle<F::Output>
+    pub async fn spawn<F>(
+        &'static self,
+        priority: Priority,
+        fut: F,
+    ) -> JoinHandle<Result<F::Output, Killed>>
     where
         F: Future + 'static,
     {
+        self.spawn_named(priority, None, fut).await
+    }
+
+    /// Like [`Kernel::spawn()`], but records the task in the [task
+    /// table][tasks] under the given `name`.
+    pub async fn spawn_named<F>(
+        &'static self,
+        priority: Priority,
+        name: Option<&'static str>,
+        fut: F,
+    ) -> JoinHandle<Result<F::Output, Killed>>
+    where
+        F: Future + 'static,
+    {
+        let fut = self.track(priority, name, fut).await;
         let bx = Box::new(maitake::task::Task::new(fut))
             .await
             .into_alloc_box();
-        self.spawn_allocated(bx)
+        self.spawn_allocated(priority, bx)
+    }
+
+    async fn track<F: Future>(
+        &'static self,
+        priority: Priority,
+        name: Option<&'static str>,
+        fut: F,
+    ) -> Tracked<F> {
+        let now = self.uptime();
+        self.tasks.lock().await.track(priority, name, now, fut)
+    }
+
+    /// Returns a snapshot of every running task in the [task table][tasks].
+    ///
+    /// As with [`Kernel::registered_services()`], storage for the snapshot is
+    /// allocated before the task table lock is taken.
+    pub async fn tasks(&'static self) -> FixedVec<TaskInfo> {
+        let capacity = self.tasks.lock().await.capacity();
+        let mut tasks = FixedVec::new(capacity).await;
+        let table = self.tasks.lock().await;
+        for info in table.tasks() {
+            // The table can never hold more than `capacity` tasks
+            let _ = tasks.try_push(info);
+        }
+        tasks
+    }
+
+    /// Kill the task with the given ID.
+    ///
+    /// The task's future is dropped the next time the scheduler would poll
+    /// it, and its `JoinHandle` completes with [`Killed`]. Returns `false` if
+    /// there is no running task with that ID.
+    pub async fn kill(&'static self, id: TaskId) -> bool {
+        self.tasks.lock().await.kill(id)
+    }
+
+    /// Limit the heap usage of the task with the given ID to `limit` bytes,
+    /// or remove its limit with `None`.
+    ///
+    /// Once the task has `limit` bytes allocated, its further async
+    /// allocations wait for it to free memory, and its `try_` allocations
+    /// fail, without affecting other tasks. See the
+    /// [quota][mnemos_alloc::quota] module.
+    ///
+    /// Returns `false` if there is no running task with that ID, or its
+    /// allocations are not accounted for, e.g. because the kernel was built
+    /// without the `heap-quota` feature.
+    pub async fn set_heap_quota(&'static self, id: TaskId, limit: Option<usize>) -> bool {
+        self.tasks.lock().await.set_heap_quota(id, limit)
     }
 
     pub async fn with_registry<F, R>(&'static self, f: F) -> R
@@ -243,14 +631,78 @@ impl Kernel {
         f(&mut guard)
     }
 
+    /// Wait until a driver service matching `RD` has been registered.
+    ///
+    /// Resolves immediately if the service is already registered. Otherwise,
+    /// the calling task sleeps until the registry reports that a new service
+    /// was added, rather than polling the registry on a timer.
+    pub async fn wait_for_service<RD: RegisteredDriver>(&'static self) {
+        loop {
+            let service_added = self
+                .with_registry(|reg| (!reg.contains::<RD>()).then(|| reg.service_added()))
+                .await;
+
+            // NOTE: there must be no `.await` between releasing the registry
+            // lock above and starting to wait below, otherwise we could miss
+            // a registration that happens in the meantime.
+            match service_added {
+                None => return,
+                Some(service_added) => {
+                    // The queue is never closed, so the result can be ignored.
+                    let _ = service_added.wait().await;
+                }
+            }
+        }
+    }
+
+    /// Serve a userspace connection, which sends [`UserMessage`]s on
+    /// `requests`, and receives [`KernelMessage`]s on `responses`.
+    ///
+    /// The connection can discover up to `max_handles` driver services, and
+    /// start up to `max_subscriptions` subscriptions at once. This never
+    /// returns, so it should be spawned as its own task. See
+    /// [`registry::serve_userspace()`].
+    ///
+    /// [`UserMessage`]: registry::UserMessage
+    /// [`KernelMessage`]: registry::KernelMessage
+    pub async fn serve_userspace(
+        &'static self,
+        requests: bbq::Consumer,
+        responses: bbq::MpscProducer,
+        max_handles: usize,
+        max_subscriptions: usize,
+    ) {
+        let handles = UserHandles::new(max_handles, max_subscriptions).await;
+        registry::serve_userspace(&self.registry, handles, requests, responses).await
+    }
+
+    /// Returns a snapshot of every driver service currently in the registry.
+    ///
+    /// Storage for the snapshot is allocated *before* the registry lock is
+    /// taken, so the lock is never held across an `.await`, even if the
+    /// allocation has to wait for memory to become available.
+    pub async fn registered_services(&'static self) -> FixedVec<ServiceInfo> {
+        let capacity = self.with_registry(|reg| reg.capacity()).await;
+        let mut services = FixedVec::new(capacity).await;
+        self.with_registry(|reg| {
+            for info in reg.services() {
+                // The registry can never hold more than `capacity` items
+                let _ = services.try_push(info);
+            }
+        })
+        .await;
+        services
+    }
+
     pub fn spawn_allocated<F>(
         &'static self,
+        priority: Priority,
         task: <BoxStorage as Storage<LocalScheduler, F>>::StoredTask,
     ) -> JoinHandle<F::Output>
     where
         F: Future + 'static,
     {
-        self.inner.scheduler.spawn_allocated(task)
+        self.scheduler(priority).spawn_allocated(task)
     }
 
     /// Returns a [`Sleep`] future that sleeps for the specified [`Duration`].
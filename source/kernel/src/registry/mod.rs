@@ -1,10 +1,16 @@
 use core::any::TypeId;
 
 use crate::{
-    comms::oneshot::Reusable,
-    tracing::{self, debug, info},
+    comms::oneshot::{CancelOnDrop, Reusable},
+    tracing::{self, debug, info, warn},
+    Kernel,
 };
-use mnemos_alloc::containers::FixedVec;
+use maitake::{
+    sync::{Mutex, WaitQueue},
+    time::Duration,
+};
+use mnemos_alloc::containers::{Arc, FixedVec, SortedMap};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 use postcard::experimental::max_size::MaxSize;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use spitebuf::EnqueueError;
@@ -12,7 +18,7 @@ pub use uuid::{uuid, Uuid};
 
 use crate::comms::{
     bbq,
-    kchannel::{ErasedKProducer, KProducer},
+    kchannel::{ErasedKProducer, KChannel, KConsumer, KProducer},
     oneshot::{ReusableError, Sender},
 };
 
@@ -28,6 +34,7 @@ pub mod known_uuids {
         pub const SIMPLE_SERIAL_PORT: Uuid = uuid!("f06aac01-2773-4266-8681-583ffe756554");
         pub const EMB_DISPLAY: Uuid = uuid!("b54db574-3eb7-4c89-8bfb-1a20890be68e");
         pub const FORTH_SPAWNULATOR: Uuid = uuid!("4ae4a406-005a-4bde-be91-afc1900f76fa");
+        pub const CLOCK: Uuid = uuid!("ba74c396-b5a9-4501-9224-064509b3d9c8");
     }
 
     // In case you need to iterate over every UUID
@@ -36,6 +43,7 @@ pub mod known_uuids {
         kernel::SIMPLE_SERIAL_PORT,
         kernel::EMB_DISPLAY,
         kernel::FORTH_SPAWNULATOR,
+        kernel::CLOCK,
     ];
 }
 
@@ -57,6 +65,17 @@ pub trait RegisteredDriver {
     /// This is the UUID of the driver service
     const UUID: Uuid;
 
+    /// This is the version of the driver service's contract, e.g. the
+    /// `Request`, `Response`, and `Error` types.
+    ///
+    /// The major version MUST be increased when a change to these types
+    /// would break existing clients (such as removing or reordering enum
+    /// variants), and the minor version SHOULD be increased when they are
+    /// extended in a backwards compatible way. See [Version::is_compatible_with].
+    ///
+    /// Defaults to [Version::INITIAL].
+    const VERSION: Version = Version::INITIAL;
+
     /// Get the type_id used to make sure that driver instances are correctly typed.
     /// Corresponds to the same type ID as `(Self::Request, Self::Response, Self::Error)`
     fn type_id() -> RegistryType {
@@ -70,27 +89,105 @@ pub struct RegistryType {
     tuple_type_id: TypeId,
 }
 
+/// The version of a driver service's contract.
+///
+/// See [RegisteredDriver::VERSION].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, MaxSize)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
 /// The driver registry used by the kernel.
 pub struct Registry {
-    items: FixedVec<RegistryItem>,
+    items: SortedMap<Uuid, RegistryValue>,
+    max_items: usize,
     counter: u32,
+    /// Woken every time a new driver service is registered.
+    ///
+    /// This lives in an `Arc` so that waiters don't need to hold the lock
+    /// around the registry while they wait.
+    service_added: Arc<WaitQueue>,
+}
+
+/// A compact identifier for a driver service, valid only for a single
+/// userspace connection.
+///
+/// Userspace clients obtain a `HandleId` by sending a [DiscoverRequest] for the
+/// UUID of the service they would like to use, and then use the `HandleId` in
+/// every following [UserRequest] instead of the (much larger) UUID. See
+/// [UserHandles] for the kernel side of this mapping.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HandleId(u16);
+
+// TODO: This probably goes into the ABI crate, here is fine for now
+#[derive(Serialize, Deserialize)]
+pub struct DiscoverRequest {
+    uuid: Uuid,
+    /// The contract version the client was built against
+    version: Version,
+    nonce: u32,
+}
+
+// TODO: This probably goes into the ABI crate, here is fine for now
+#[derive(Serialize, Deserialize, MaxSize)]
+pub struct DiscoverResponse {
+    nonce: u32,
+    reply: Result<HandleId, UserspaceHandleError>,
 }
 
 // TODO: This probably goes into the ABI crate, here is fine for now
 #[derive(Serialize, Deserialize)]
 pub struct UserRequest<'a> {
-    // TODO: Maybe not the UUID, maybe pre-discover a shorter UID?
-    uid: Uuid,
+    handle: HandleId,
     nonce: u32,
+    /// If `Some`, the request starts a subscription: the service may send
+    /// up to this many responses before waiting for more credit to be
+    /// granted with a [UserSubscriptionControl].
+    subscribe: Option<u32>,
     #[serde(borrow)]
     req_bytes: &'a [u8],
 }
 
+// TODO: This probably goes into the ABI crate, here is fine for now
+#[derive(Serialize, Deserialize)]
+pub struct UserSubscriptionControl {
+    handle: HandleId,
+    /// The nonce of the [UserRequest] that started the subscription
+    nonce: u32,
+    control: SubscriptionControl,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SubscriptionControl {
+    /// Allow the service to send this many more responses.
+    Grant(u32),
+    /// End the subscription. The service will not send any further responses.
+    Cancel,
+}
+
+// TODO: This probably goes into the ABI crate, here is fine for now
+/// A message sent by userspace on a connection, see [serve_userspace].
+#[derive(Serialize, Deserialize)]
+pub enum UserMessage<'a> {
+    Discover(DiscoverRequest),
+    #[serde(borrow)]
+    Request(UserRequest<'a>),
+    Control(UserSubscriptionControl),
+}
+
+// TODO: This probably goes into the ABI crate, here is fine for now
+/// A message sent to userspace on a connection, see [serve_userspace].
+#[derive(Serialize, Deserialize, MaxSize)]
+pub enum KernelMessage<U, E> {
+    Discovered(DiscoverResponse),
+    Response(UserResponse<U, E>),
+}
+
 // TODO: This probably goes into the ABI crate, here is fine for now
 #[derive(Serialize, Deserialize)]
 pub struct UserResponse<U, E> {
-    // TODO: Maybe not the UUID, maybe pre-discover a shorter UID?
-    uuid: Uuid,
+    handle: HandleId,
     nonce: u32,
     reply: Result<U, E>,
     //
@@ -105,7 +202,7 @@ impl<U: MaxSize, E: MaxSize> MaxSize for UserResponse<U, E> {
     // KEEP IN SYNC WITH STRUCT DEFINITION ABOVE!
     //
     const POSTCARD_MAX_SIZE: usize = {
-        <[u8; 16] as MaxSize>::POSTCARD_MAX_SIZE
+        <HandleId as MaxSize>::POSTCARD_MAX_SIZE
             + <u32 as MaxSize>::POSTCARD_MAX_SIZE
             + <Result<U, E> as MaxSize>::POSTCARD_MAX_SIZE
     };
@@ -114,6 +211,62 @@ impl<U: MaxSize, E: MaxSize> MaxSize for UserResponse<U, E> {
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct ServiceId(pub(crate) u32);
 
+/// A snapshot of a single driver service in the [Registry].
+///
+/// Obtained through [Registry::services] or
+/// [Kernel::registered_services][crate::Kernel::registered_services].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ServiceInfo {
+    /// The UUID the service was registered with, e.g. [RegisteredDriver::UUID]
+    pub uuid: Uuid,
+    /// The [ServiceId] assigned to the service when it was registered
+    pub service_id: ServiceId,
+    /// The contract version provided by the service, e.g. [RegisteredDriver::VERSION]
+    pub version: Version,
+    /// Was the service registered with [Registry::register], and can therefore
+    /// be reached from userspace?
+    pub userspace: bool,
+    /// The number of client handles that have been obtained for this service,
+    /// through either [Registry::get] or [Registry::get_userspace]
+    pub clients: u32,
+    /// Request and reply counters for the service
+    pub metrics: ServiceMetrics,
+}
+
+/// A snapshot of the request counters of a single driver service.
+///
+/// Requests are counted for every client handle. Replies are only visible to
+/// the registry for requests made with [KernelHandle::request_oneshot] or
+/// [KernelHandle::subscribe], and requests from userspace; replies sent to a
+/// [ReplyTo::KChannel], or to a [ReplyTo::OneShot] or [Subscription] created
+/// by hand, are not counted.
+///
+/// All counters wrap around on overflow.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ServiceMetrics {
+    /// Requests successfully sent to the service
+    pub requests: u32,
+    /// Replies received from the service
+    pub replies: u32,
+    /// Replies carrying an `Err` from the service, or which were never
+    /// received because the service dropped the reply channel
+    pub reply_errors: u32,
+    /// Requests which found the service's request queue full
+    pub queue_full: u32,
+    /// The largest number of requests seen waiting in the service's queue
+    pub max_queue_depth: u32,
+}
+
+/// The live counters behind [ServiceMetrics], shared between the [Registry]
+/// and every handle to a driver service.
+pub struct ServiceCounters {
+    requests: AtomicU32,
+    replies: AtomicU32,
+    reply_errors: AtomicU32,
+    queue_full: AtomicU32,
+    max_queue_depth: AtomicU32,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct ClientId(pub(crate) u32);
 
@@ -189,9 +342,58 @@ pub enum ReplyTo<RD: RegisteredDriver> {
     // This can be used to reply to userspace. Responses are serialized
     // and sent over the bbq::MpscProducer
     Userspace {
+        handle: HandleId,
         nonce: u32,
         outgoing: bbq::MpscProducer,
+        metrics: Arc<ServiceCounters>,
     },
+
+    // This can be used to reply any number of times, until the client
+    // cancels the subscription. See [Subscription].
+    Subscription(Subscription<RD>),
+}
+
+/// The sending half of a subscription, allowing a driver service to send
+/// any number of responses to a single request.
+///
+/// A driver service receives a `Subscription` as [ReplyTo::Subscription],
+/// and may keep it for as long as it likes, sending responses with
+/// [Subscription::send] or [Subscription::send_konly]. Sending waits if the
+/// client has not caught up with the previous responses yet, and fails with
+/// [ReplyError::ReplyChannelClosed] once the client has cancelled the
+/// subscription.
+///
+/// The client is not notified when a `Subscription` is dropped. Services
+/// that end a stream on their own should say so in their response type.
+pub struct Subscription<RD: RegisteredDriver> {
+    inner: SubscriptionInner<RD>,
+    metrics: Option<Arc<ServiceCounters>>,
+}
+
+enum SubscriptionInner<RD: RegisteredDriver> {
+    /// Responses are sent to a kernel client's bounded [KChannel], which
+    /// provides backpressure. Dropping the consumer cancels the subscription.
+    Kernel(KProducer<Envelope<Result<RD::Response, RD::Error>>>),
+    /// Responses are serialized and sent to userspace, as long as userspace
+    /// has granted enough credit.
+    Userspace {
+        handle: HandleId,
+        nonce: u32,
+        outgoing: bbq::MpscProducer,
+        credits: Arc<SubscriptionCredits>,
+    },
+}
+
+/// Flow control state of a userspace subscription, shared between its
+/// [Subscription] and the [UserHandles] of the connection.
+struct SubscriptionCredits {
+    /// The number of responses the service may send before it must wait for
+    /// userspace to grant more.
+    credits: AtomicU32,
+    /// Set when userspace cancels the subscription, or the service drops it.
+    closed: AtomicBool,
+    /// Woken when credit is granted, and closed along with the subscription.
+    wait: WaitQueue,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -205,13 +407,61 @@ pub enum ReplyError {
 #[derive(Debug, Eq, PartialEq)]
 pub enum UserHandlerError {
     DeserializationFailed,
+    /// A reply to userspace could not be serialized.
+    SerializationFailed,
     QueueFull,
+    /// The request's [HandleId] was not obtained through discovery on this
+    /// connection.
+    NoSuchHandle,
+    /// The driver service's request channel has been closed. See
+    /// [SendError::ServiceClosed].
+    ServiceClosed,
+    /// The connection already has the maximum number of active subscriptions.
+    TooManySubscriptions,
+    /// The [UserSubscriptionControl] refers to a subscription that was never
+    /// started on this connection, or has already ended.
+    NoSuchSubscription,
+    /// Memory for a subscription could not be allocated.
+    OutOfMemory,
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum RegistrationError {
     UuidAlreadyRegistered,
     RegistryFull,
+    /// The service's metrics could not be allocated.
+    OutOfMemory,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, MaxSize)]
+pub enum UserspaceHandleError {
+    /// No driver service with the requested UUID is registered
+    NotRegistered,
+    /// The driver service was registered with [Registry::register_konly],
+    /// and cannot be used from userspace
+    KernelOnly,
+    /// The registered driver service provides a contract version that is
+    /// not compatible with the requested one
+    VersionMismatch { client: Version, service: Version },
+    /// The connection's [UserHandles] table is full
+    TooManyHandles,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum DeregistrationError {
+    /// No driver service matching the given type is registered
+    NotRegistered,
+}
+
+/// Errors returned by [KernelHandle::send].
+#[derive(Debug, Eq, PartialEq)]
+pub enum SendError {
+    /// The driver service's request channel has been closed, either because
+    /// the service was deregistered, or because its server stopped running.
+    ///
+    /// This handle will never work again. A new handle must be obtained from
+    /// the [Registry] once the service has been registered again.
+    ServiceClosed,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -219,9 +469,11 @@ pub enum OneshotRequestError {
     /// An error occurred while acquiring a sender.
     Sender(ReusableError),
     /// Sending the request failed.
-    Send,
+    Send(SendError),
     /// An error occurred while receiving the response.
     Receive(ReusableError),
+    /// No response was received before the timeout elapsed.
+    Timeout,
 }
 
 impl From<ReusableError> for ReplyError {
@@ -252,6 +504,27 @@ pub struct UserspaceHandle {
     req_deser: ErasedDeserHandler,
     service_id: ServiceId,
     client_id: ClientId,
+    metrics: Arc<ServiceCounters>,
+}
+
+/// A per-connection table of the driver services a userspace client has
+/// discovered.
+///
+/// Userspace clients refer to driver services with a compact [HandleId] rather
+/// than a full UUID. A `HandleId` is handed out by [UserHandles::discover], and
+/// is simply the index of the [UserspaceHandle] in this table.
+///
+/// It also tracks the subscriptions started on the connection, so userspace
+/// can grant them credit or cancel them with a [UserSubscriptionControl].
+pub struct UserHandles {
+    handles: FixedVec<(Uuid, UserspaceHandle)>,
+    subscriptions: FixedVec<ActiveSubscription>,
+}
+
+struct ActiveSubscription {
+    handle: HandleId,
+    nonce: u32,
+    credits: Arc<SubscriptionCredits>,
 }
 
 /// A KernelHandle is used to send typed messages to a kernelspace Driver
@@ -261,17 +534,18 @@ pub struct KernelHandle<RD: RegisteredDriver> {
     service_id: ServiceId,
     client_id: ClientId,
     request_ctr: u32,
+    metrics: Arc<ServiceCounters>,
 }
 
 type ErasedDeserHandler = unsafe fn(
     UserRequest<'_>,
-    &ErasedKProducer,
+    HandleId,
+    &UserspaceHandle,
     &bbq::MpscProducer,
-    ServiceId,
-    ClientId,
+    Option<Arc<SubscriptionCredits>>,
 ) -> Result<(), UserHandlerError>;
 
-/// The payload of a registry item.
+/// A registered driver service, keyed by its UUID in the [Registry].
 ///
 /// The typeid is stored here to allow the userspace handle to look up the UUID key
 /// without knowing the proper typeid. Kernel space drivers should always check that the
@@ -281,13 +555,9 @@ struct RegistryValue {
     req_prod: ErasedKProducer,
     req_deser: Option<ErasedDeserHandler>,
     service_id: ServiceId,
-}
-
-/// Right now we don't use a real HashMap, but rather a hand-rolled index map.
-/// Therefore our registry is basically a `Vec<RegistryItem>`.
-struct RegistryItem {
-    key: Uuid,
-    value: RegistryValue,
+    version: Version,
+    client_count: u32,
+    metrics: Arc<ServiceCounters>,
 }
 
 // RegistryType
@@ -304,8 +574,10 @@ impl Registry {
     /// Create a new registry with room for up to `max_items` registered drivers.
     pub fn new(max_items: usize) -> Self {
         Self {
-            items: FixedVec::try_new(max_items).unwrap(),
+            items: SortedMap::try_with_capacity(max_items).unwrap(),
+            max_items,
             counter: 0,
+            service_added: Arc::try_new(WaitQueue::new()).map_err(drop).unwrap(),
         }
     }
 
@@ -325,22 +597,31 @@ impl Registry {
         &mut self,
         kch: &KProducer<Message<RD>>,
     ) -> Result<(), RegistrationError> {
-        if self.items.as_slice().iter().any(|i| i.key == RD::UUID) {
-            return Err(RegistrationError::UuidAlreadyRegistered);
+        self.check_available(RD::UUID)?;
+        if self.items.len() >= self.max_items {
+            return Err(RegistrationError::RegistryFull);
         }
+        let metrics =
+            Arc::try_new(ServiceCounters::new()).map_err(|_| RegistrationError::OutOfMemory)?;
+        // Room for `max_items` entries was allocated up front, so this
+        // doesn't need to grow the map.
         self.items
-            .try_push(RegistryItem {
-                key: RD::UUID,
-                value: RegistryValue {
+            .try_insert(
+                RD::UUID,
+                RegistryValue {
                     req_resp_tuple_id: RD::type_id().type_of(),
                     req_prod: kch.clone().type_erase(),
                     req_deser: None,
                     service_id: ServiceId(self.counter),
+                    version: RD::VERSION,
+                    client_count: 0,
+                    metrics,
                 },
-            })
+            )
             .map_err(|_| RegistrationError::RegistryFull)?;
         info!(uuid = ?RD::UUID, service_id = self.counter, "Registered KOnly");
         self.counter = self.counter.wrapping_add(1);
+        self.service_added.wake_all();
         Ok(())
     }
 
@@ -361,22 +642,31 @@ impl Registry {
         RD::Request: Serialize + DeserializeOwned,
         RD::Response: Serialize + DeserializeOwned,
     {
-        if self.items.as_slice().iter().any(|i| i.key == RD::UUID) {
-            return Err(RegistrationError::UuidAlreadyRegistered);
+        self.check_available(RD::UUID)?;
+        if self.items.len() >= self.max_items {
+            return Err(RegistrationError::RegistryFull);
         }
+        let metrics =
+            Arc::try_new(ServiceCounters::new()).map_err(|_| RegistrationError::OutOfMemory)?;
+        // Room for `max_items` entries was allocated up front, so this
+        // doesn't need to grow the map.
         self.items
-            .try_push(RegistryItem {
-                key: RD::UUID,
-                value: RegistryValue {
+            .try_insert(
+                RD::UUID,
+                RegistryValue {
                     req_resp_tuple_id: RD::type_id().type_of(),
                     req_prod: kch.clone().type_erase(),
                     req_deser: Some(map_deser::<RD>),
                     service_id: ServiceId(self.counter),
+                    version: RD::VERSION,
+                    client_count: 0,
+                    metrics,
                 },
-            })
+            )
             .map_err(|_| RegistrationError::RegistryFull)?;
         info!(uuid = ?RD::UUID, service_id = self.counter, "Registered");
         self.counter = self.counter.wrapping_add(1);
+        self.service_added.wake_all();
         Ok(())
     }
 
@@ -395,23 +685,138 @@ impl Registry {
         fields(uuid = ?RD::UUID),
     )]
     pub fn get<RD: RegisteredDriver>(&mut self) -> Option<KernelHandle<RD>> {
-        let item = self.items.as_slice().iter().find(|i| i.key == RD::UUID)?;
-        if item.value.req_resp_tuple_id != RD::type_id().type_of() {
+        self.remove_if_closed(RD::UUID);
+        let value = self.items.get_mut(&RD::UUID)?;
+        if value.req_resp_tuple_id != RD::type_id().type_of() {
             return None;
         }
+        value.client_count = value.client_count.wrapping_add(1);
         unsafe {
             let res = Some(KernelHandle {
-                prod: item.value.req_prod.clone_typed(),
-                service_id: item.value.service_id,
+                prod: value.req_prod.clone_typed(),
+                service_id: value.service_id,
                 client_id: ClientId(self.counter),
                 request_ctr: 0,
+                metrics: value.metrics.clone(),
             });
-            info!(uuid = ?RD::UUID, service_id = item.value.service_id.0, client_id = self.counter, "Got KernelHandle from Registry");
+            info!(uuid = ?RD::UUID, service_id = value.service_id.0, client_id = self.counter, "Got KernelHandle from Registry");
             self.counter = self.counter.wrapping_add(1);
             res
         }
     }
 
+    /// Remove a driver service from the registry.
+    ///
+    /// The service's request channel is closed, so any clients still holding a
+    /// handle to the service will receive [SendError::ServiceClosed] on their
+    /// next request, and can obtain a new handle once the service has been
+    /// registered again.
+    ///
+    /// Services are also removed automatically once their server drops the
+    /// [KConsumer][crate::comms::kchannel::KConsumer] of its request channel,
+    /// so calling this is only necessary when a running server wants to stop
+    /// accepting requests.
+    #[tracing::instrument(
+        name = "Registry::deregister",
+        level = "debug",
+        skip(self),
+        fields(uuid = ?RD::UUID),
+    )]
+    pub fn deregister<RD: RegisteredDriver>(&mut self) -> Result<(), DeregistrationError> {
+        match self.items.get(&RD::UUID) {
+            Some(value) if value.req_resp_tuple_id == RD::type_id().type_of() => {}
+            _ => return Err(DeregistrationError::NotRegistered),
+        }
+        let (_, value) = self
+            .items
+            .remove(&RD::UUID)
+            .ok_or(DeregistrationError::NotRegistered)?;
+        value.req_prod.close();
+        info!(uuid = ?RD::UUID, service_id = value.service_id.0, "Deregistered");
+        Ok(())
+    }
+
+    /// Returns `true` if a driver service matching `RD` is currently registered.
+    ///
+    /// Both the UUID and the request/response types must match.
+    pub fn contains<RD: RegisteredDriver>(&self) -> bool {
+        self.items.get(&RD::UUID).map_or(false, |value| {
+            value.req_resp_tuple_id == RD::type_id().type_of() && !value.req_prod.is_closed()
+        })
+    }
+
+    /// Returns the queue that is woken every time a driver service is registered.
+    ///
+    /// Because registration only happens while the registry is locked, a waiter
+    /// should check the registry contents and obtain this queue while holding
+    /// the lock, then start waiting immediately after releasing it, without
+    /// awaiting anything else in between. See [Kernel::wait_for_service] for
+    /// how this is used.
+    ///
+    /// [Kernel::wait_for_service]: crate::Kernel::wait_for_service
+    pub fn service_added(&self) -> Arc<WaitQueue> {
+        self.service_added.clone()
+    }
+
+    /// Returns the number of driver services currently registered.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns `true` if no driver services are registered.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Returns the maximum number of driver services that can be registered.
+    pub fn capacity(&self) -> usize {
+        self.max_items
+    }
+
+    /// Iterate over every registered driver service, in UUID order.
+    ///
+    /// Services whose server has stopped running are skipped.
+    pub fn services(&self) -> impl Iterator<Item = ServiceInfo> + '_ {
+        self.items
+            .iter()
+            .filter(|(_, value)| !value.req_prod.is_closed())
+            .map(|(&uuid, value)| value.info(uuid))
+    }
+
+    /// Look up a registered driver service by UUID, without obtaining a handle
+    /// to it.
+    pub fn service_info(&self, uuid: Uuid) -> Option<ServiceInfo> {
+        self.items
+            .get(&uuid)
+            .filter(|value| !value.req_prod.is_closed())
+            .map(|value| value.info(uuid))
+    }
+
+    /// Make sure `uuid` can be registered, removing a previous registration
+    /// if its server has stopped running.
+    fn check_available(&mut self, uuid: Uuid) -> Result<(), RegistrationError> {
+        self.remove_if_closed(uuid);
+        if self.items.contains_key(&uuid) {
+            return Err(RegistrationError::UuidAlreadyRegistered);
+        }
+        Ok(())
+    }
+
+    /// Remove the registration for `uuid` if its request channel was closed,
+    /// e.g. because the server dropped its `KConsumer`.
+    fn remove_if_closed(&mut self, uuid: Uuid) {
+        let closed = self
+            .items
+            .get(&uuid)
+            .map_or(false, |value| value.req_prod.is_closed());
+        if !closed {
+            return;
+        }
+        if let Some((_, value)) = self.items.remove(&uuid) {
+            info!(uuid = ?uuid, service_id = value.service_id.0, "Removed closed service");
+        }
+    }
+
     /// Get a handle capable of processing serialized userspace messages to a
     /// registered driver service.
     ///
@@ -420,31 +825,170 @@ impl Registry {
     ///
     /// Driver services registered with [Registry::register_konly] cannot be retrieved via
     /// a call to [Registry::get_userspace].
+    ///
+    /// `version` is the contract version the userspace client was built
+    /// against. The registered service's contract version must be compatible
+    /// with it, otherwise [UserspaceHandleError::VersionMismatch] is returned.
     #[tracing::instrument(
         name = "Registry::get_userspace",
         level = "debug",
         skip(self),
         fields(uuid = ?RD::UUID),
     )]
-    pub fn get_userspace<RD>(&mut self) -> Option<UserspaceHandle>
+    pub fn get_userspace<RD>(
+        &mut self,
+        version: Version,
+    ) -> Result<UserspaceHandle, UserspaceHandleError>
     where
         RD: RegisteredDriver,
         RD::Request: Serialize + DeserializeOwned,
         RD::Response: Serialize + DeserializeOwned,
     {
-        let item = self.items.as_slice().iter().find(|i| i.key == RD::UUID)?;
+        self.get_userspace_erased(RD::UUID, version)
+    }
+
+    /// Get a handle capable of processing serialized userspace messages to the
+    /// driver service registered with `uuid`.
+    ///
+    /// Unlike [Registry::get_userspace], this does not require knowing the
+    /// type of the driver service, which makes it suitable for handling
+    /// requests from userspace, which only knows the UUID and contract version
+    /// it was built against.
+    #[tracing::instrument(name = "Registry::get_userspace_erased", level = "debug", skip(self))]
+    pub fn get_userspace_erased(
+        &mut self,
+        uuid: Uuid,
+        version: Version,
+    ) -> Result<UserspaceHandle, UserspaceHandleError> {
+        self.remove_if_closed(uuid);
+        let value = self
+            .items
+            .get_mut(&uuid)
+            .ok_or(UserspaceHandleError::NotRegistered)?;
+        let req_deser = value.req_deser.ok_or(UserspaceHandleError::KernelOnly)?;
+        if !version.is_compatible_with(value.version) {
+            warn!(
+                ?uuid,
+                client = ?version,
+                service = ?value.version,
+                "Incompatible userspace contract version"
+            );
+            return Err(UserspaceHandleError::VersionMismatch {
+                client: version,
+                service: value.version,
+            });
+        }
+        value.client_count = value.client_count.wrapping_add(1);
         let client_id = self.counter;
-        info!(uuid = ?RD::UUID, service_id = item.value.service_id.0, client_id = self.counter, "Got KernelHandle from Registry");
+        info!(
+            ?uuid,
+            service_id = value.service_id.0,
+            client_id = self.counter,
+            "Got UserspaceHandle from Registry"
+        );
         self.counter = self.counter.wrapping_add(1);
-        Some(UserspaceHandle {
-            req_producer_leaked: item.value.req_prod.clone(),
-            req_deser: item.value.req_deser?,
-            service_id: item.value.service_id,
+        Ok(UserspaceHandle {
+            req_producer_leaked: value.req_prod.clone(),
+            req_deser,
+            service_id: value.service_id,
             client_id: ClientId(client_id),
+            metrics: value.metrics.clone(),
         })
     }
 }
 
+// RegistryValue
+
+impl RegistryValue {
+    fn info(&self, uuid: Uuid) -> ServiceInfo {
+        ServiceInfo {
+            uuid,
+            service_id: self.service_id,
+            version: self.version,
+            userspace: self.req_deser.is_some(),
+            clients: self.client_count,
+            metrics: self.metrics.snapshot(),
+        }
+    }
+}
+
+// Version
+
+impl Version {
+    /// The default version of a driver service contract.
+    pub const INITIAL: Self = Self::new(1, 0);
+
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+
+    /// Can a client built against `self` talk to a service providing `service`?
+    ///
+    /// This is the case if the major versions are the same, and the service's
+    /// minor version is at least as new as the client's.
+    pub const fn is_compatible_with(&self, service: Version) -> bool {
+        self.major == service.major && self.minor <= service.minor
+    }
+}
+
+// ServiceCounters
+
+impl ServiceCounters {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU32::new(0),
+            replies: AtomicU32::new(0),
+            reply_errors: AtomicU32::new(0),
+            queue_full: AtomicU32::new(0),
+            max_queue_depth: AtomicU32::new(0),
+        }
+    }
+
+    /// Take a snapshot of the current counter values.
+    pub fn snapshot(&self) -> ServiceMetrics {
+        ServiceMetrics {
+            requests: self.requests.load(Ordering::Relaxed),
+            replies: self.replies.load(Ordering::Relaxed),
+            reply_errors: self.reply_errors.load(Ordering::Relaxed),
+            queue_full: self.queue_full.load(Ordering::Relaxed),
+            max_queue_depth: self.max_queue_depth.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record a request that was enqueued, leaving `depth` requests waiting
+    /// in the service's queue.
+    fn record_request(&self, depth: usize) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let depth = u32::try_from(depth).unwrap_or(u32::MAX);
+        self.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn record_queue_full(&self) {
+        self.queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a reply from the service, which may carry an error.
+    fn record_reply(&self, is_err: bool) {
+        self.replies.fetch_add(1, Ordering::Relaxed);
+        if is_err {
+            self.reply_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a request which will never receive a reply.
+    fn record_reply_error(&self) {
+        self.reply_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// ServiceId
+
+impl ServiceId {
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
 // UserRequest
 
 // Envelope
@@ -488,6 +1032,13 @@ impl<P> Envelope<P> {
 // ReplyTo
 
 impl<RD: RegisteredDriver> ReplyTo<RD> {
+    /// Does the client expect any number of responses to this request?
+    ///
+    /// See [Subscription].
+    pub fn is_subscription(&self) -> bool {
+        matches!(self, ReplyTo::Subscription(_))
+    }
+
     pub async fn reply_konly(
         self,
         envelope: Envelope<Result<RD::Response, RD::Error>>,
@@ -506,6 +1057,7 @@ impl<RD: RegisteredDriver> ReplyTo<RD> {
                 sender.send(envelope)?;
             }
             ReplyTo::Userspace { .. } => return Err(ReplyError::KOnlyUserspaceResponse),
+            ReplyTo::Subscription(mut sub) => sub.send_konly(envelope).await?,
         }
         Ok(())
     }
@@ -518,7 +1070,6 @@ where
 {
     pub async fn reply(
         self,
-        uuid_source: Uuid,
         envelope: Envelope<Result<RD::Response, RD::Error>>,
     ) -> Result<(), ReplyError> {
         debug!(
@@ -536,25 +1087,232 @@ where
                 sender.send(envelope)?;
                 Ok(())
             }
-            ReplyTo::Userspace { nonce, outgoing } => {
-                let mut wgr = outgoing
-                    .send_grant_exact(
-                        <UserResponse<RD::Response, RD::Error> as MaxSize>::POSTCARD_MAX_SIZE,
-                    )
-                    .await;
-                let used = postcard::to_slice(
-                    &UserResponse {
-                        uuid: uuid_source,
-                        nonce,
-                        reply: envelope.body,
-                    },
-                    &mut wgr,
-                )
-                .map_err(|_| ReplyError::UserspaceSerializationError)?;
-                let len = used.len();
-                wgr.commit(len);
+            ReplyTo::Userspace {
+                handle,
+                nonce,
+                outgoing,
+                metrics,
+            } => reply_userspace::<RD>(handle, nonce, &outgoing, Some(&metrics), envelope).await,
+            ReplyTo::Subscription(mut sub) => sub.send(envelope).await,
+        }
+    }
+}
+
+/// Serialize a response to a userspace request, and send it over `outgoing`.
+async fn reply_userspace<RD>(
+    handle: HandleId,
+    nonce: u32,
+    outgoing: &bbq::MpscProducer,
+    metrics: Option<&Arc<ServiceCounters>>,
+    envelope: Envelope<Result<RD::Response, RD::Error>>,
+) -> Result<(), ReplyError>
+where
+    RD: RegisteredDriver,
+    RD::Response: Serialize + MaxSize,
+    RD::Error: Serialize + MaxSize,
+{
+    if let Some(metrics) = metrics {
+        metrics.record_reply(envelope.body.is_err());
+    }
+    let msg = KernelMessage::Response(UserResponse {
+        handle,
+        nonce,
+        reply: envelope.body,
+    });
+    send_userspace(outgoing, &msg).await.map_err(|_| {
+        if let Some(metrics) = metrics {
+            metrics.record_reply_error();
+        }
+        ReplyError::UserspaceSerializationError
+    })
+}
+
+/// Serialize a message to userspace, and send it over `outgoing`.
+async fn send_userspace<U, E>(
+    outgoing: &bbq::MpscProducer,
+    msg: &KernelMessage<U, E>,
+) -> Result<(), postcard::Error>
+where
+    U: Serialize + MaxSize,
+    E: Serialize + MaxSize,
+{
+    let mut wgr = outgoing
+        .send_grant_exact(<KernelMessage<U, E> as MaxSize>::POSTCARD_MAX_SIZE)
+        .await;
+    let len = postcard::to_slice(msg, &mut wgr)?.len();
+    wgr.commit(len);
+    Ok(())
+}
+
+/// Serve a userspace connection: process the [UserMessage]s userspace sends
+/// on `requests`, and send every [KernelMessage] for it on `responses`.
+///
+/// Userspace must first discover each driver service it would like to use
+/// with a [UserMessage::Discover], and then refers to it by the [HandleId]
+/// in the reply. Messages which can't be processed are logged and dropped.
+///
+/// This runs for as long as the connection exists. See
+/// [Kernel::serve_userspace][crate::Kernel::serve_userspace].
+pub async fn serve_userspace(
+    registry: &Mutex<Registry>,
+    mut handles: UserHandles,
+    requests: bbq::Consumer,
+    responses: bbq::MpscProducer,
+) {
+    loop {
+        let rgr = requests.read_grant().await;
+        // Every message is committed to the ring on its own, so a grant
+        // never ends part way through one.
+        let mut rest: &[u8] = &rgr;
+        while !rest.is_empty() {
+            let msg = match postcard::take_from_bytes::<UserMessage<'_>>(rest) {
+                Ok((msg, tail)) => {
+                    rest = tail;
+                    msg
+                }
+                Err(error) => {
+                    warn!(?error, len = rest.len(), "Undecodable userspace message");
+                    break;
+                }
+            };
+            if let Err(error) = handles.process_message(registry, msg, &responses).await {
+                warn!(?error, "Failed to process userspace message");
+            }
+        }
+        let len = rgr.len();
+        rgr.release(len);
+    }
+}
+
+// Subscription
+
+impl<RD: RegisteredDriver> Subscription<RD> {
+    /// Create a subscription which sends responses to a kernel [KChannel].
+    ///
+    /// The channel's capacity limits how far the service can get ahead of
+    /// the client. Dropping the channel's [KConsumer] cancels the
+    /// subscription.
+    pub fn new(replies: KProducer<Envelope<Result<RD::Response, RD::Error>>>) -> Self {
+        Self {
+            inner: SubscriptionInner::Kernel(replies),
+            metrics: None,
+        }
+    }
+
+    /// Has the client cancelled the subscription?
+    ///
+    /// Once cancelled, all further sends will fail, so the service may as
+    /// well stop producing responses.
+    pub fn is_cancelled(&self) -> bool {
+        match &self.inner {
+            SubscriptionInner::Kernel(prod) => prod.is_closed(),
+            SubscriptionInner::Userspace { credits, .. } => credits.is_closed(),
+        }
+    }
+
+    /// Send a response to a kernel client, waiting until the client has room
+    /// for it.
+    ///
+    /// Returns [ReplyError::KOnlyUserspaceResponse] if the subscription was
+    /// started from userspace.
+    pub async fn send_konly(
+        &mut self,
+        envelope: Envelope<Result<RD::Response, RD::Error>>,
+    ) -> Result<(), ReplyError> {
+        match &self.inner {
+            SubscriptionInner::Kernel(prod) => {
+                let is_err = envelope.body.is_err();
+                prod.enqueue_async(envelope).await?;
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.record_reply(is_err);
+                }
                 Ok(())
             }
+            SubscriptionInner::Userspace { .. } => Err(ReplyError::KOnlyUserspaceResponse),
+        }
+    }
+}
+
+impl<RD: RegisteredDriver> Subscription<RD>
+where
+    RD::Response: Serialize + MaxSize,
+    RD::Error: Serialize + MaxSize,
+{
+    /// Send a response to the client, waiting until the client has room for
+    /// it.
+    pub async fn send(
+        &mut self,
+        envelope: Envelope<Result<RD::Response, RD::Error>>,
+    ) -> Result<(), ReplyError> {
+        match &self.inner {
+            SubscriptionInner::Kernel(_) => self.send_konly(envelope).await,
+            SubscriptionInner::Userspace {
+                handle,
+                nonce,
+                outgoing,
+                credits,
+            } => {
+                credits.acquire().await?;
+                reply_userspace::<RD>(*handle, *nonce, outgoing, self.metrics.as_ref(), envelope)
+                    .await
+            }
+        }
+    }
+}
+
+impl<RD: RegisteredDriver> Drop for Subscription<RD> {
+    fn drop(&mut self) {
+        // Let the connection forget about the subscription.
+        if let SubscriptionInner::Userspace { credits, .. } = &self.inner {
+            credits.close();
+        }
+    }
+}
+
+// SubscriptionCredits
+
+impl SubscriptionCredits {
+    fn new(credits: u32) -> Self {
+        Self {
+            credits: AtomicU32::new(credits),
+            closed: AtomicBool::new(false),
+            wait: WaitQueue::new(),
+        }
+    }
+
+    fn grant(&self, credits: u32) {
+        let _ = self
+            .credits
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| {
+                Some(c.saturating_add(credits))
+            });
+        self.wait.wake_all();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.wait.close();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Take one credit, waiting for userspace to grant more if none are left.
+    async fn acquire(&self) -> Result<(), ReplyError> {
+        loop {
+            if self.is_closed() {
+                return Err(ReplyError::ReplyChannelClosed);
+            }
+            let took = self
+                .credits
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| c.checked_sub(1));
+            if took.is_ok() {
+                return Ok(());
+            }
+            if self.wait.wait().await.is_err() {
+                return Err(ReplyError::ReplyChannelClosed);
+            }
         }
     }
 }
@@ -562,41 +1320,234 @@ where
 // UserspaceHandle
 
 impl UserspaceHandle {
-    pub fn process_msg(
+    /// Process a message from userspace, which was sent using `handle`.
+    ///
+    /// If the message starts a subscription, its flow control state must be
+    /// passed as `credits`.
+    fn process_msg(
         &self,
         user_msg: UserRequest<'_>,
+        handle: HandleId,
         user_ring: &bbq::MpscProducer,
+        credits: Option<Arc<SubscriptionCredits>>,
     ) -> Result<(), UserHandlerError> {
-        unsafe {
-            (self.req_deser)(
-                user_msg,
-                &self.req_producer_leaked,
-                user_ring,
-                self.service_id,
-                self.client_id,
-            )
+        unsafe { (self.req_deser)(user_msg, handle, self, user_ring, credits) }
+    }
+
+    /// Returns a snapshot of the driver service's request counters.
+    pub fn metrics(&self) -> ServiceMetrics {
+        self.metrics.snapshot()
+    }
+}
+
+// UserHandles
+
+impl UserHandles {
+    /// Create a new table with room for up to `max_handles` discovered
+    /// services, and up to `max_subscriptions` active subscriptions.
+    ///
+    /// Panics if `max_handles` is zero, or larger than the number of
+    /// [HandleId]s that exist.
+    pub async fn new(max_handles: usize, max_subscriptions: usize) -> Self {
+        assert_ne!(max_handles, 0, "a connection needs room for a handle");
+        assert!(
+            max_handles <= (u16::MAX as usize) + 1,
+            "too many handles for a HandleId"
+        );
+        Self {
+            handles: FixedVec::new(max_handles).await,
+            subscriptions: FixedVec::new(max_subscriptions).await,
+        }
+    }
+
+    /// Handle a [DiscoverRequest] from userspace, returning the reply to send
+    /// back.
+    ///
+    /// If the requested service was already discovered on this connection, the
+    /// existing [HandleId] is reused.
+    pub fn discover(&mut self, registry: &mut Registry, req: &DiscoverRequest) -> DiscoverResponse {
+        DiscoverResponse {
+            nonce: req.nonce,
+            reply: self.discover_inner(registry, req.uuid, req.version),
+        }
+    }
+
+    fn discover_inner(
+        &mut self,
+        registry: &mut Registry,
+        uuid: Uuid,
+        version: Version,
+    ) -> Result<HandleId, UserspaceHandleError> {
+        let existing = self.handles.as_slice().iter().position(|(u, _)| *u == uuid);
+        if existing.is_none() && self.handles.is_full() {
+            return Err(UserspaceHandleError::TooManyHandles);
+        }
+
+        // Always go back to the registry, even if the service was discovered
+        // before: it may have been restarted, and the version must be checked.
+        let handle = registry.get_userspace_erased(uuid, version)?;
+        let idx = match existing {
+            Some(idx) => {
+                self.handles.as_slice_mut()[idx].1 = handle;
+                idx
+            }
+            None => {
+                let idx = self.handles.as_slice().len();
+                self.handles
+                    .try_push((uuid, handle))
+                    .map_err(|_| UserspaceHandleError::TooManyHandles)?;
+                idx
+            }
+        };
+        Ok(HandleId(idx as u16))
+    }
+
+    /// Process any message from userspace, see [serve_userspace].
+    ///
+    /// The registry is only locked to discover driver services, and never
+    /// while waiting for room in `user_ring`.
+    pub async fn process_message(
+        &mut self,
+        registry: &Mutex<Registry>,
+        msg: UserMessage<'_>,
+        user_ring: &bbq::MpscProducer,
+    ) -> Result<(), UserHandlerError> {
+        match msg {
+            UserMessage::Discover(req) => {
+                let resp = self.discover(&mut *registry.lock().await, &req);
+                let msg = KernelMessage::<(), ()>::Discovered(resp);
+                // There is always room for the response in the grant.
+                send_userspace(user_ring, &msg)
+                    .await
+                    .map_err(|_| UserHandlerError::SerializationFailed)
+            }
+            UserMessage::Request(req) => self.process_msg(req, user_ring),
+            UserMessage::Control(ctl) => self.control_subscription(&ctl),
+        }
+    }
+
+    /// Process a message from userspace, using the driver service its
+    /// [HandleId] was discovered for.
+    pub fn process_msg(
+        &mut self,
+        user_msg: UserRequest<'_>,
+        user_ring: &bbq::MpscProducer,
+    ) -> Result<(), UserHandlerError> {
+        let handle = user_msg.handle;
+        let nonce = user_msg.nonce;
+        let (_, hdl) = self
+            .handles
+            .as_slice()
+            .get(handle.0 as usize)
+            .ok_or(UserHandlerError::NoSuchHandle)?;
+
+        let Some(initial) = user_msg.subscribe else {
+            return hdl.process_msg(user_msg, handle, user_ring, None);
+        };
+
+        self.prune_subscriptions();
+        if self.subscriptions.is_full() {
+            return Err(UserHandlerError::TooManySubscriptions);
+        }
+        let credits = Arc::try_new(SubscriptionCredits::new(initial))
+            .map_err(|_| UserHandlerError::OutOfMemory)?;
+        hdl.process_msg(user_msg, handle, user_ring, Some(credits.clone()))?;
+        self.subscriptions
+            .try_push(ActiveSubscription {
+                handle,
+                nonce,
+                credits,
+            })
+            .map_err(|_| UserHandlerError::TooManySubscriptions)
+    }
+
+    /// Grant credit to, or cancel, a subscription started on this connection.
+    pub fn control_subscription(
+        &mut self,
+        ctl: &UserSubscriptionControl,
+    ) -> Result<(), UserHandlerError> {
+        self.prune_subscriptions();
+        let idx = self
+            .subscriptions
+            .as_slice()
+            .iter()
+            .position(|s| s.handle == ctl.handle && s.nonce == ctl.nonce)
+            .ok_or(UserHandlerError::NoSuchSubscription)?;
+        match ctl.control {
+            SubscriptionControl::Grant(credits) => {
+                self.subscriptions.as_slice()[idx].credits.grant(credits);
+            }
+            SubscriptionControl::Cancel => {
+                let sub = self.subscriptions.remove(idx);
+                sub.credits.close();
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget about subscriptions which the driver service has dropped.
+    fn prune_subscriptions(&mut self) {
+        while let Some(idx) = self
+            .subscriptions
+            .as_slice()
+            .iter()
+            .position(|s| s.credits.is_closed())
+        {
+            self.subscriptions.remove(idx);
         }
     }
 }
 
+// HandleId
+
+impl MaxSize for HandleId {
+    const POSTCARD_MAX_SIZE: usize = <u16 as MaxSize>::POSTCARD_MAX_SIZE;
+}
+
 // KernelHandle
 
 impl<RD: RegisteredDriver> KernelHandle<RD> {
-    pub async fn send(&mut self, msg: RD::Request, reply: ReplyTo<RD>) -> Result<(), ()> {
+    /// Has the driver service stopped accepting requests?
+    ///
+    /// If this returns `true`, all further requests will fail with
+    /// [SendError::ServiceClosed], and a new handle should be obtained
+    /// from the [Registry].
+    pub fn is_closed(&self) -> bool {
+        self.prod.is_closed()
+    }
+
+    /// Returns a snapshot of the driver service's request counters.
+    pub fn metrics(&self) -> ServiceMetrics {
+        self.metrics.snapshot()
+    }
+
+    pub async fn send(&mut self, msg: RD::Request, reply: ReplyTo<RD>) -> Result<(), SendError> {
         let request_id = RequestResponseId::new(self.request_ctr, MessageKind::Request);
         self.request_ctr = self.request_ctr.wrapping_add(1);
-        self.prod
-            .enqueue_async(Message {
-                msg: Envelope {
-                    body: msg,
-                    service_id: self.service_id,
-                    client_id: self.client_id,
-                    request_id,
-                },
-                reply,
-            })
-            .await
-            .map_err(drop)?;
+        let msg = Message {
+            msg: Envelope {
+                body: msg,
+                service_id: self.service_id,
+                client_id: self.client_id,
+                request_id,
+            },
+            reply,
+        };
+        // Try a synchronous send first, so we notice when the service is
+        // falling behind.
+        match self.prod.enqueue_sync(msg) {
+            Ok(()) => {}
+            Err(EnqueueError::Full(msg)) => {
+                self.metrics.record_queue_full();
+                self.prod
+                    .enqueue_async(msg)
+                    .await
+                    // Enqueueing asynchronously only fails if the channel is closed
+                    .map_err(|_| SendError::ServiceClosed)?;
+            }
+            Err(EnqueueError::Closed(_)) => return Err(SendError::ServiceClosed),
+        }
+        self.metrics.record_request(self.prod.len());
         debug!(
             service_id = self.service_id.0,
             client_id = self.client_id.0,
@@ -608,16 +1559,76 @@ impl<RD: RegisteredDriver> KernelHandle<RD> {
 
     /// Send a [`ReplyTo::OneShot`] request using the provided [`Reusable`]
     /// oneshot channel, and await the response from that channel.
+    ///
+    /// If the returned future is dropped before the response arrives, the
+    /// request is cancelled (see [`Reusable::cancel`]), so `reply` can be
+    /// used for another request right away.
     pub async fn request_oneshot(
         &mut self,
         msg: RD::Request,
         reply: &Reusable<Envelope<Result<RD::Response, RD::Error>>>,
     ) -> Result<Envelope<Result<RD::Response, RD::Error>>, OneshotRequestError> {
         let tx = reply.sender().await.map_err(OneshotRequestError::Sender)?;
+        // Once a sender exists, make sure we don't leave it dangling if we
+        // stop waiting for the response.
+        let _cancel = CancelOnDrop::new(reply);
         self.send(msg, ReplyTo::OneShot(tx))
             .await
-            .map_err(|_| OneshotRequestError::Send)?;
-        reply.receive().await.map_err(OneshotRequestError::Receive)
+            .map_err(OneshotRequestError::Send)?;
+        match reply.receive().await {
+            Ok(resp) => {
+                self.metrics.record_reply(resp.body.is_err());
+                Ok(resp)
+            }
+            Err(e) => {
+                self.metrics.record_reply_error();
+                Err(OneshotRequestError::Receive(e))
+            }
+        }
+    }
+
+    /// Send a request which may be answered with any number of responses,
+    /// returning the [`KConsumer`] they will be delivered to.
+    ///
+    /// The returned channel has room for `capacity` responses, which must be
+    /// a power of two. Once it is full, the service waits for the client to
+    /// catch up. Dropping the `KConsumer` cancels the subscription. See
+    /// [`Subscription`] for the service's side.
+    pub async fn subscribe(
+        &mut self,
+        msg: RD::Request,
+        capacity: usize,
+    ) -> Result<KConsumer<Envelope<Result<RD::Response, RD::Error>>>, SendError> {
+        let (prod, cons) = KChannel::new_async(capacity).await.split();
+        let sub = Subscription {
+            inner: SubscriptionInner::Kernel(prod),
+            metrics: Some(self.metrics.clone()),
+        };
+        self.send(msg, ReplyTo::Subscription(sub)).await?;
+        Ok(cons)
+    }
+
+    /// Like [`KernelHandle::request_oneshot`], but gives up with
+    /// [`OneshotRequestError::Timeout`] if no response has been received
+    /// within `timeout`, as measured by the `kernel`'s timer.
+    ///
+    /// The timeout covers waiting for room in the service's queue as well
+    /// as waiting for the response. When it elapses, the request is
+    /// cancelled, and any late response from the service is discarded.
+    pub async fn request_oneshot_timeout(
+        &mut self,
+        msg: RD::Request,
+        reply: &Reusable<Envelope<Result<RD::Response, RD::Error>>>,
+        kernel: &'static Kernel,
+        timeout: Duration,
+    ) -> Result<Envelope<Result<RD::Response, RD::Error>>, OneshotRequestError> {
+        match kernel
+            .timeout(timeout, self.request_oneshot(msg, reply))
+            .await
+        {
+            Ok(res) => res,
+            Err(_) => Err(OneshotRequestError::Timeout),
+        }
     }
 }
 
@@ -633,10 +1644,10 @@ impl<RD: RegisteredDriver> KernelHandle<RD> {
 /// used to create the `ErasedKProducer`.
 unsafe fn map_deser<RD>(
     umsg: UserRequest<'_>,
-    req_tx: &ErasedKProducer,
+    handle: HandleId,
+    uhdl: &UserspaceHandle,
     user_resp: &bbq::MpscProducer,
-    service_id: ServiceId,
-    client_id: ClientId,
+    credits: Option<Arc<SubscriptionCredits>>,
 ) -> Result<(), UserHandlerError>
 where
     RD: RegisteredDriver,
@@ -651,7 +1662,8 @@ where
     //
     // This PROBABLY would require a "with"/closure method to make sure the producer ref
     // doesn't outlive the LeakedKProducer reference.
-    let req_prod = req_tx.clone_typed::<Message<RD>>();
+    let req_prod = uhdl.req_producer_leaked.clone_typed::<Message<RD>>();
+    let metrics = &uhdl.metrics;
 
     // Deserialize the request, if it doesn't have the right contents, deserialization will fail.
     let u_payload: RD::Request = postcard::from_bytes(umsg.req_bytes)
@@ -661,18 +1673,334 @@ where
     let msg: Message<RD> = Message {
         msg: Envelope {
             body: u_payload,
-            service_id,
-            client_id,
+            service_id: uhdl.service_id,
+            client_id: uhdl.client_id,
             request_id: RequestResponseId::new(umsg.nonce, MessageKind::Request),
         },
-        reply: ReplyTo::Userspace {
-            nonce: umsg.nonce,
-            outgoing: user_resp.clone(),
+        reply: match credits {
+            None => ReplyTo::Userspace {
+                handle,
+                nonce: umsg.nonce,
+                outgoing: user_resp.clone(),
+                metrics: metrics.clone(),
+            },
+            Some(credits) => ReplyTo::Subscription(Subscription {
+                inner: SubscriptionInner::Userspace {
+                    handle,
+                    nonce: umsg.nonce,
+                    outgoing: user_resp.clone(),
+                    credits,
+                },
+                metrics: Some(metrics.clone()),
+            }),
         },
     };
 
     // Send the message, and report any failures
-    req_prod
-        .enqueue_sync(msg)
-        .map_err(|_| UserHandlerError::QueueFull)
+    req_prod.enqueue_sync(msg).map_err(|e| match e {
+        EnqueueError::Full(_) => {
+            metrics.record_queue_full();
+            UserHandlerError::QueueFull
+        }
+        EnqueueError::Closed(_) => UserHandlerError::ServiceClosed,
+    })?;
+    metrics.record_request(req_prod.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+    use spitebuf::DequeueError;
+
+    struct TestService;
+
+    impl RegisteredDriver for TestService {
+        type Request = u32;
+        type Response = u32;
+        type Error = ();
+        const UUID: Uuid = uuid!("6ab4e1d4-6a7c-4c32-9a3b-5a1f1e0e2c01");
+    }
+
+    /// A different service, registered with the same UUID.
+    struct Impostor;
+
+    impl RegisteredDriver for Impostor {
+        type Request = u8;
+        type Response = u8;
+        type Error = ();
+        const UUID: Uuid = TestService::UUID;
+    }
+
+    /// A newer, backwards compatible version of [TestService].
+    struct TestServiceV1_2;
+
+    impl RegisteredDriver for TestServiceV1_2 {
+        type Request = u32;
+        type Response = u32;
+        type Error = ();
+        const UUID: Uuid = TestService::UUID;
+        const VERSION: Version = Version::new(1, 2);
+    }
+
+    type Server = KConsumer<Message<TestService>>;
+
+    fn register(registry: &mut Registry) -> Server {
+        let (prod, cons) = KChannel::new(4).split();
+        registry.register(&prod).unwrap();
+        cons
+    }
+
+    /// Send `req` with `handle`, returning what the server received.
+    fn request(
+        handle: &mut KernelHandle<TestService>,
+        server: &Server,
+        req: u32,
+    ) -> Result<u32, SendError> {
+        let (reply, _replies) = KChannel::new(2).split();
+        handle
+            .send(req, ReplyTo::KChannel(reply))
+            .now_or_never()
+            .unwrap()?;
+        let msg = server.dequeue_sync().unwrap();
+        Ok(msg.msg.body)
+    }
+
+    #[test]
+    fn deregister() {
+        let mut registry = Registry::new(4);
+        let server = register(&mut registry);
+        let mut handle = registry.get::<TestService>().unwrap();
+        assert!(registry.contains::<TestService>());
+
+        // The types must match, not just the UUID.
+        assert!(!registry.contains::<Impostor>());
+        assert_eq!(
+            registry.deregister::<Impostor>(),
+            Err(DeregistrationError::NotRegistered)
+        );
+
+        assert_eq!(registry.deregister::<TestService>(), Ok(()));
+        assert!(!registry.contains::<TestService>());
+        assert!(registry.is_empty());
+        assert!(registry.get::<TestService>().is_none());
+        assert_eq!(
+            registry.deregister::<TestService>(),
+            Err(DeregistrationError::NotRegistered)
+        );
+
+        // The server's queue is closed, so its loop can end...
+        assert!(matches!(
+            server.dequeue_async().now_or_never(),
+            Some(Err(DequeueError::Closed))
+        ));
+        // ...and clients are told the service is gone.
+        assert!(handle.is_closed());
+        assert_eq!(
+            request(&mut handle, &server, 1),
+            Err(SendError::ServiceClosed)
+        );
+
+        // The UUID can be registered again.
+        let server = register(&mut registry);
+        let mut handle = registry.get::<TestService>().unwrap();
+        assert_eq!(request(&mut handle, &server, 2), Ok(2));
+    }
+
+    #[test]
+    fn closed_service_removed() {
+        let mut registry = Registry::new(4);
+        let server = register(&mut registry);
+        assert_eq!(registry.services().count(), 1);
+        assert!(registry.service_info(TestService::UUID).is_some());
+
+        // The server stops running.
+        drop(server);
+        assert!(!registry.contains::<TestService>());
+        assert_eq!(registry.services().count(), 0);
+        assert!(registry.service_info(TestService::UUID).is_none());
+        // It is removed the next time its UUID is looked up.
+        assert!(registry.get::<TestService>().is_none());
+        assert!(registry.is_empty());
+
+        // Or registered again.
+        let server = register(&mut registry);
+        drop(server);
+        let _server = register(&mut registry);
+        assert_eq!(registry.len(), 1);
+        assert!(registry.contains::<TestService>());
+    }
+
+    #[test]
+    fn client_reconnects_after_restart() {
+        let mut registry = Registry::new(4);
+        let server = register(&mut registry);
+        let mut handle = registry.get::<TestService>().unwrap();
+        assert_eq!(request(&mut handle, &server, 1), Ok(1));
+        let first_id = registry.service_info(TestService::UUID).unwrap().service_id;
+
+        // The driver restarts, registering a new request queue.
+        drop(server);
+        let server = register(&mut registry);
+        assert_ne!(
+            registry.service_info(TestService::UUID).unwrap().service_id,
+            first_id
+        );
+
+        // The old handle fails, but a new one can be obtained.
+        assert!(handle.is_closed());
+        assert_eq!(
+            request(&mut handle, &server, 2),
+            Err(SendError::ServiceClosed)
+        );
+        let mut handle = registry.get::<TestService>().unwrap();
+        assert_eq!(request(&mut handle, &server, 3), Ok(3));
+    }
+
+    #[test]
+    fn reply_to_dropped_client() {
+        let (reply, replies) = KChannel::new(2).split();
+        let reply = ReplyTo::<TestService>::KChannel(reply);
+        drop(replies);
+        let envelope = Envelope {
+            body: Ok(1),
+            service_id: ServiceId(0),
+            client_id: ClientId(0),
+            request_id: RequestResponseId::new(0, MessageKind::Response),
+        };
+        assert_eq!(
+            reply.reply_konly(envelope).now_or_never(),
+            Some(Err(ReplyError::ReplyChannelClosed))
+        );
+    }
+
+    #[test]
+    fn userspace_version_mismatch() {
+        let mut registry = Registry::new(4);
+        let (prod, _server) = KChannel::<Message<TestServiceV1_2>>::new(4).split();
+        registry.register(&prod).unwrap();
+
+        // Clients built against the same or an older minor version work...
+        for client in [Version::new(1, 0), Version::new(1, 2)] {
+            assert!(registry.get_userspace::<TestService>(client).is_ok());
+        }
+        // ...but not a newer one, or another major version.
+        for client in [Version::new(1, 3), Version::new(2, 0), Version::new(0, 2)] {
+            assert_eq!(
+                registry.get_userspace::<TestService>(client).err(),
+                Some(UserspaceHandleError::VersionMismatch {
+                    client,
+                    service: Version::new(1, 2),
+                })
+            );
+        }
+        assert_eq!(registry.services().next().unwrap().clients, 2);
+    }
+
+    /// Serialize `msg` onto a userspace connection's request ring.
+    fn send_user(ring: &bbq::SpscProducer, msg: &UserMessage<'_>) {
+        let mut wgr = ring.send_grant_max_sync(64).unwrap();
+        let len = postcard::to_slice(msg, &mut wgr).unwrap().len();
+        wgr.commit(len);
+    }
+
+    /// Take the next message off a userspace connection's response ring.
+    fn recv_user(ring: &bbq::Consumer) -> KernelMessage<u32, ()> {
+        let rgr = ring.read_grant_sync().unwrap();
+        let (msg, rest) = postcard::take_from_bytes(&rgr).unwrap();
+        let used = rgr.len() - rest.len();
+        rgr.release(used);
+        msg
+    }
+
+    #[test]
+    fn userspace_round_trip() {
+        let registry = Mutex::new(Registry::new(4));
+        let server = register(&mut registry.try_lock().unwrap());
+        let (requests, from_user) = bbq::new_spsc_channel(256).now_or_never().unwrap();
+        let (responses, to_user) = bbq::new_spsc_channel(256).now_or_never().unwrap();
+        let responses = responses.into_mpmc_producer().now_or_never().unwrap();
+        let handles = UserHandles::new(2, 2).now_or_never().unwrap();
+        let mut conn = core::pin::pin!(serve_userspace(&registry, handles, from_user, responses));
+
+        // Discover the service...
+        send_user(
+            &requests,
+            &UserMessage::Discover(DiscoverRequest {
+                uuid: TestService::UUID,
+                version: Version::INITIAL,
+                nonce: 1,
+            }),
+        );
+        assert!(conn.as_mut().now_or_never().is_none());
+        let handle = match recv_user(&to_user) {
+            KernelMessage::Discovered(DiscoverResponse {
+                nonce: 1,
+                reply: Ok(handle),
+            }) => handle,
+            _ => panic!("service not discovered"),
+        };
+
+        // ...use its handle to send it a request...
+        let mut buf = [0; 8];
+        let req_bytes: &[u8] = postcard::to_slice(&42u32, &mut buf).unwrap();
+        send_user(
+            &requests,
+            &UserMessage::Request(UserRequest {
+                handle,
+                nonce: 2,
+                subscribe: None,
+                req_bytes,
+            }),
+        );
+        assert!(conn.as_mut().now_or_never().is_none());
+        let Message { msg, reply } = server.dequeue_sync().unwrap();
+        assert_eq!(msg.body, 42);
+
+        // ...and get its response.
+        reply
+            .reply(msg.reply_with(Ok(43)))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        match recv_user(&to_user) {
+            KernelMessage::Response(UserResponse {
+                handle: h,
+                nonce: 2,
+                reply: Ok(43),
+            }) => assert_eq!(h, handle),
+            _ => panic!("wrong response"),
+        }
+
+        // Unknown services and handles are rejected.
+        send_user(
+            &requests,
+            &UserMessage::Discover(DiscoverRequest {
+                uuid: uuid!("00000000-0000-0000-0000-000000000000"),
+                version: Version::INITIAL,
+                nonce: 3,
+            }),
+        );
+        assert!(conn.as_mut().now_or_never().is_none());
+        assert!(matches!(
+            recv_user(&to_user),
+            KernelMessage::Discovered(DiscoverResponse {
+                nonce: 3,
+                reply: Err(UserspaceHandleError::NotRegistered),
+            })
+        ));
+        send_user(
+            &requests,
+            &UserMessage::Request(UserRequest {
+                handle: HandleId(1),
+                nonce: 4,
+                subscribe: None,
+                req_bytes,
+            }),
+        );
+        assert!(conn.as_mut().now_or_never().is_none());
+        assert!(server.dequeue_sync().is_none());
+        assert!(to_user.read_grant_sync().is_none());
+    }
 }
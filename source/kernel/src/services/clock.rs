@@ -0,0 +1,364 @@
+//! # Clock
+//!
+//! A service which provides the current time, both as the monotonic time since
+//! the kernel started (uptime), and as wall-clock time.
+//!
+//! Uptime is always available, and is measured by the kernel's timer. Wall-clock
+//! time is tracked as an offset from a time source, which is either a real-time
+//! clock provided by the platform, or the kernel's uptime if the platform has
+//! none. Without a real-time clock, the wall clock is unknown until it is set
+//! with [`ClockClient::set_wall_clock()`].
+//!
+//! This module contains the service definition, client definition, and a
+//! [`ClockServer`] implementation.
+
+use core::time::Duration;
+
+use uuid::Uuid;
+
+use crate::{
+    comms::{
+        kchannel::{KChannel, KConsumer},
+        oneshot::Reusable,
+    },
+    registry::{self, known_uuids, Envelope, KernelHandle, Message, RegisteredDriver},
+    tasks::Priority,
+    tracing, Kernel,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+// Service Definition
+////////////////////////////////////////////////////////////////////////////////
+
+/// Registered driver type for the clock service.
+pub struct ClockService;
+
+impl RegisteredDriver for ClockService {
+    type Request = Request;
+    type Response = Response;
+    type Error = ClockError;
+
+    const UUID: Uuid = known_uuids::kernel::CLOCK;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Message and Error Types
+////////////////////////////////////////////////////////////////////////////////
+
+pub enum Request {
+    /// Get the current time
+    Now,
+    /// Set the wall clock, to the given time since the UNIX epoch
+    SetWallClock(Duration),
+}
+
+pub enum Response {
+    Now(Timestamp),
+    WallClockSet,
+}
+
+/// A reading of the clock.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Timestamp {
+    /// Time since the kernel started
+    pub uptime: Duration,
+    /// Time since the UNIX epoch, or `None` if the wall clock has not been set
+    pub wall: Option<Duration>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ClockError {
+    /// Without a real-time clock, the wall clock can't be set to a time
+    /// before the kernel started
+    InvalidTime,
+    /// Internal Error
+    InternalError,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Client Definition
+////////////////////////////////////////////////////////////////////////////////
+
+/// Client interface to [`ClockService`].
+pub struct ClockClient {
+    hdl: KernelHandle<ClockService>,
+    reply: Reusable<Envelope<Result<Response, ClockError>>>,
+}
+
+impl ClockClient {
+    /// Obtain a new client handle by querying the registry for a registered
+    /// [`ClockService`].
+    ///
+    /// Will wait for the service to be registered, and retry until success
+    pub async fn from_registry(kernel: &'static Kernel) -> Self {
+        loop {
+            kernel.wait_for_service::<ClockService>().await;
+            if let Some(client) = Self::from_registry_no_retry(kernel).await {
+                return client;
+            }
+        }
+    }
+
+    /// Obtain a new client handle by querying the registry for a registered
+    /// [`ClockService`].
+    ///
+    /// Will not retry if not immediately successful
+    pub async fn from_registry_no_retry(kernel: &'static Kernel) -> Option<Self> {
+        let hdl = kernel
+            .with_registry(|reg| reg.get::<ClockService>())
+            .await?;
+
+        Some(ClockClient {
+            hdl,
+            reply: Reusable::new_async().await,
+        })
+    }
+
+    /// Read the current time
+    pub async fn now(&mut self) -> Result<Timestamp, ClockError> {
+        match self.request(Request::Now).await? {
+            Response::Now(now) => Ok(now),
+            _ => Err(ClockError::InternalError),
+        }
+    }
+
+    /// Time since the kernel started
+    pub async fn uptime(&mut self) -> Result<Duration, ClockError> {
+        Ok(self.now().await?.uptime)
+    }
+
+    /// Time since the UNIX epoch, or `None` if the wall clock has not been set
+    pub async fn wall_time(&mut self) -> Result<Option<Duration>, ClockError> {
+        Ok(self.now().await?.wall)
+    }
+
+    /// Set the wall clock to the given time since the UNIX epoch
+    pub async fn set_wall_clock(&mut self, since_epoch: Duration) -> Result<(), ClockError> {
+        match self.request(Request::SetWallClock(since_epoch)).await? {
+            Response::WallClockSet => Ok(()),
+            _ => Err(ClockError::InternalError),
+        }
+    }
+
+    async fn request(&mut self, req: Request) -> Result<Response, ClockError> {
+        self.hdl
+            .request_oneshot(req, &self.reply)
+            .await
+            .map_err(|_| ClockError::InternalError)?
+            .body
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Server Definition
+////////////////////////////////////////////////////////////////////////////////
+
+/// Server implementation for the [`ClockService`].
+pub struct ClockServer;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum RegistrationError {
+    ClockAlreadyRegistered,
+    /// The registry has no room for another driver service
+    RegistryFull,
+    /// The service could not be allocated
+    OutOfMemory,
+}
+
+impl ClockServer {
+    /// Register the `ClockServer`.
+    ///
+    /// `rtc` is the platform's real-time clock, if it has one, which returns
+    /// the time since the UNIX epoch. The wall clock is then always known, and
+    /// setting it adjusts the offset from `rtc`. Otherwise, the wall clock is
+    /// unknown until it is set, and then advances with the kernel's uptime.
+    #[tracing::instrument(level = tracing::Level::DEBUG, skip(kernel, rtc))]
+    pub async fn register(
+        kernel: &'static Kernel,
+        capacity: usize,
+        rtc: Option<fn() -> Duration>,
+    ) -> Result<(), RegistrationError> {
+        let (cmd_prod, cmd_cons) = KChannel::new_async(capacity).await.split();
+        let clock = ClockTask {
+            kernel,
+            cmd: cmd_cons,
+            rtc,
+            // A real-time clock needs no adjustment until it is set.
+            offset_nanos: rtc.map(|_| 0),
+        };
+
+        // Only start the server once it is registered, so a failed
+        // registration doesn't leave it running.
+        kernel
+            .with_registry(|reg| reg.register_konly::<ClockService>(&cmd_prod))
+            .await
+            .map_err(|error| match error {
+                registry::RegistrationError::UuidAlreadyRegistered => {
+                    RegistrationError::ClockAlreadyRegistered
+                }
+                registry::RegistrationError::RegistryFull => RegistrationError::RegistryFull,
+                registry::RegistrationError::OutOfMemory => RegistrationError::OutOfMemory,
+            })?;
+
+        kernel
+            .spawn_named(Priority::System, Some("clock"), clock.run())
+            .await;
+
+        tracing::info!(rtc = rtc.is_some(), "ClockServer initialized!");
+        Ok(())
+    }
+}
+
+struct ClockTask {
+    kernel: &'static Kernel,
+    cmd: KConsumer<Message<ClockService>>,
+    rtc: Option<fn() -> Duration>,
+    /// Wall-clock time is the time source plus this offset, in nanoseconds.
+    /// `None` until the wall clock is set, if there is no real-time clock.
+    offset_nanos: Option<i128>,
+}
+
+// impl ClockTask
+
+impl ClockTask {
+    async fn run(mut self) {
+        // The queue is closed once the service is deregistered.
+        while let Ok(msg) = self.cmd.dequeue_async().await {
+            let Message { msg: req, reply } = msg;
+            let res = match req.body {
+                Request::Now => Ok(Response::Now(self.now())),
+                Request::SetWallClock(since_epoch) => self
+                    .set_wall_clock(since_epoch)
+                    .map(|_| Response::WallClockSet),
+            };
+            let resp = req.reply_with(res);
+            let _ = reply.reply_konly(resp).await;
+        }
+    }
+
+    /// The time that wall-clock time is measured from, in nanoseconds.
+    fn source_nanos(&self) -> i128 {
+        match self.rtc {
+            Some(rtc) => rtc().as_nanos() as i128,
+            None => self.kernel.uptime().as_nanos() as i128,
+        }
+    }
+
+    fn now(&self) -> Timestamp {
+        let wall = self.offset_nanos.and_then(|offset| {
+            let nanos = u64::try_from(self.source_nanos() + offset).ok()?;
+            Some(Duration::from_nanos(nanos))
+        });
+        Timestamp {
+            uptime: self.kernel.uptime(),
+            wall,
+        }
+    }
+
+    fn set_wall_clock(&mut self, since_epoch: Duration) -> Result<(), ClockError> {
+        let offset = since_epoch.as_nanos() as i128 - self.source_nanos();
+        if self.rtc.is_none() && offset < 0 {
+            return Err(ClockError::InvalidTime);
+        }
+        tracing::info!(?since_epoch, "Wall clock set");
+        self.offset_nanos = Some(offset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::KernelSettings;
+    use futures::FutureExt;
+
+    fn kernel() -> &'static Kernel {
+        let settings = KernelSettings {
+            max_drivers: 4,
+            max_tasks: 4,
+            timer_granularity: Duration::from_millis(1),
+            busy_clock: None,
+            poll_budget: None,
+        };
+        let kernel = unsafe { Kernel::new(settings).unwrap() };
+        unsafe { &*mnemos_alloc::containers::Box::into_raw(kernel) }
+    }
+
+    fn clock(kernel: &'static Kernel, rtc: Option<fn() -> Duration>) -> ClockTask {
+        let (_, cmd) = KChannel::new(2).split();
+        ClockTask {
+            kernel,
+            cmd,
+            rtc,
+            offset_nanos: rtc.map(|_| 0),
+        }
+    }
+
+    #[test]
+    fn wall_clock_follows_uptime() {
+        let kernel = kernel();
+        let mut clock = clock(kernel, None);
+        kernel.advance_timer(1000);
+        assert_eq!(
+            clock.now(),
+            Timestamp {
+                uptime: Duration::from_secs(1),
+                wall: None,
+            }
+        );
+
+        clock.set_wall_clock(Duration::from_secs(100)).unwrap();
+        assert_eq!(clock.now().wall, Some(Duration::from_secs(100)));
+        kernel.advance_timer(500);
+        assert_eq!(
+            clock.now(),
+            Timestamp {
+                uptime: Duration::from_millis(1500),
+                wall: Some(Duration::from_millis(100_500)),
+            }
+        );
+    }
+
+    #[test]
+    fn wall_clock_before_boot_is_invalid() {
+        let kernel = kernel();
+        let mut clock = clock(kernel, None);
+        kernel.advance_timer(1000);
+        // The offset from uptime would be negative.
+        assert_eq!(
+            clock.set_wall_clock(Duration::from_millis(500)),
+            Err(ClockError::InvalidTime)
+        );
+        assert_eq!(clock.now().wall, None);
+    }
+
+    #[test]
+    fn wall_clock_from_rtc() {
+        fn rtc() -> Duration {
+            Duration::from_secs(1000)
+        }
+
+        let kernel = kernel();
+        let mut clock = clock(kernel, Some(rtc));
+        assert_eq!(clock.now().wall, Some(Duration::from_secs(1000)));
+        // With a real-time clock, the offset may be negative.
+        clock.set_wall_clock(Duration::from_secs(10)).unwrap();
+        assert_eq!(clock.now().wall, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn register_once() {
+        let kernel = kernel();
+        let register = || {
+            ClockServer::register(kernel, 2, None)
+                .now_or_never()
+                .unwrap()
+        };
+        assert_eq!(register(), Ok(()));
+        // A failed registration doesn't start another server.
+        assert_eq!(register(), Err(RegistrationError::ClockAlreadyRegistered));
+        let tasks = kernel.tasks().now_or_never().unwrap();
+        assert_eq!(tasks.as_slice().len(), 1);
+        assert_eq!(tasks.as_slice()[0].name, Some("clock"));
+    }
+}
@@ -20,8 +20,6 @@
 //! The current server assumes 8 bits per pixel, which is implementation defined for
 //! color/greyscale format (sorry).
 
-use core::time::Duration;
-
 use crate::{
     comms::oneshot::{Reusable, ReusableError},
     registry::{Envelope, KernelHandle, RegisteredDriver, ReplyTo},
@@ -110,14 +108,12 @@ impl EmbDisplayClient {
     /// Obtain a new client handle by querying the registry for a registered
     /// [`EmbDisplayService`].
     ///
-    /// Will retry until success
+    /// Will wait for the service to be registered, and retry until success
     pub async fn from_registry(kernel: &'static Kernel) -> Self {
         loop {
-            match Self::from_registry_no_retry(kernel).await {
-                Some(me) => return me,
-                None => {
-                    kernel.sleep(Duration::from_millis(10)).await;
-                }
+            kernel.wait_for_service::<EmbDisplayService>().await;
+            if let Some(me) = Self::from_registry_no_retry(kernel).await {
+                return me;
             }
         }
     }
@@ -38,7 +38,7 @@
 //! different queue (the scheduler's run queue), but I couldn't easily come up
 //! with another solution...
 
-use core::{convert::Infallible, time::Duration};
+use core::convert::Infallible;
 
 use uuid::Uuid;
 
@@ -51,6 +51,7 @@ use crate::{
     registry::{
         known_uuids::kernel::FORTH_SPAWNULATOR, Envelope, KernelHandle, Message, RegisteredDriver,
     },
+    tasks::Priority,
     tracing, Kernel,
 };
 
@@ -85,12 +86,10 @@ pub struct SpawnulatorClient {
 impl SpawnulatorClient {
     pub async fn from_registry(kernel: &'static Kernel) -> Self {
         loop {
-            match Self::from_registry_no_retry(kernel).await {
-                Some(port) => return port,
-                None => {
-                    // SerialMux probably isn't registered yet. Try again in a bit
-                    kernel.sleep(Duration::from_millis(10)).await;
-                }
+            // The spawnulator probably isn't registered yet. Wait until it is
+            kernel.wait_for_service::<SpawnulatorService>().await;
+            if let Some(client) = Self::from_registry_no_retry(kernel).await {
+                return client;
             }
         }
     }
@@ -143,7 +142,11 @@ impl SpawnulatorServer {
         let (cmd_prod, cmd_cons) = KChannel::new_async(capacity).await.split();
         tracing::debug!("who spawns the spawnulator?");
         kernel
-            .spawn(SpawnulatorServer::spawnulate(kernel, cmd_cons))
+            .spawn_named(
+                Priority::System,
+                Some("spawnulator"),
+                SpawnulatorServer::spawnulate(kernel, cmd_cons),
+            )
             .await;
         tracing::debug!("spawnulator spawnulated!");
         kernel
@@ -167,7 +170,9 @@ impl SpawnulatorServer {
 
             let vm = vm.unwrap();
             let id = vm.forth.host_ctxt().id();
-            kernel.spawn(vm.run()).await;
+            kernel
+                .spawn_named(Priority::User, Some("forth"), vm.run())
+                .await;
             let _ = msg.reply.reply_konly(resp).await;
             tracing::trace!(task.id = id, "spawnulated!");
         }
@@ -18,6 +18,7 @@
 //!
 //! For examples of using these services, see the [daemons][crate::daemons] module.
 
+pub mod clock;
 pub mod emb_display;
 pub mod forth_spawnulator;
 pub mod serial_mux;
@@ -15,18 +15,23 @@ use crate::{
         kchannel::{KChannel, KConsumer},
         oneshot::Reusable,
     },
-    registry::{Envelope, KernelHandle, Message, RegisteredDriver},
-    services::simple_serial::SimpleSerialClient,
+    registry::{Envelope, KernelHandle, Message, RegisteredDriver, Version},
+    services::simple_serial::{SimpleSerialClient, SimpleSerialService},
+    tasks::Priority,
     Kernel,
 };
 use maitake::sync::Mutex;
-use mnemos_alloc::containers::{Arc, FixedVec};
+use mnemos_alloc::containers::{Arc, FixedVec, SortedMap};
 use sermux_proto::PortChunk;
 use uuid::Uuid;
 
 // Well known ports live in the sermux_proto crate
 pub use sermux_proto::WellKnown;
 
+/// How long the server waits for memory for a new port's buffer, before
+/// giving up with [`SerialMuxError::OutOfMemory`].
+const PORT_ALLOC_TIMEOUT: Duration = Duration::from_secs(1);
+
 ////////////////////////////////////////////////////////////////////////////////
 // Service Definition
 ////////////////////////////////////////////////////////////////////////////////
@@ -39,6 +44,8 @@ impl RegisteredDriver for SerialMuxService {
     type Response = Response;
     type Error = SerialMuxError;
     const UUID: Uuid = crate::registry::known_uuids::kernel::SERIAL_MUX;
+    /// 1.1 added [`SerialMuxError::OutOfMemory`].
+    const VERSION: Version = Version::new(1, 1);
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -57,6 +64,8 @@ pub enum Response {
 pub enum SerialMuxError {
     DuplicateItem,
     RegistryFull,
+    /// The port's buffer could not be allocated in time
+    OutOfMemory,
 }
 
 /// A `PortHandle` is the interface received after opening a virtual serial port
@@ -84,15 +93,13 @@ pub struct SerialMuxClient {
 impl SerialMuxClient {
     /// Obtain a `SerialMuxClient`
     ///
-    /// If the [`SerialMuxServer`] hasn't been registered yet, we will retry until it has been
+    /// If the [`SerialMuxServer`] hasn't been registered yet, we will wait until it has been
     pub async fn from_registry(kernel: &'static Kernel) -> Self {
         loop {
-            match SerialMuxClient::from_registry_no_retry(kernel).await {
-                Some(port) => return port,
-                None => {
-                    // SerialMux probably isn't registered yet. Try again in a bit
-                    kernel.sleep(Duration::from_millis(10)).await;
-                }
+            // SerialMux may not be registered yet, wait for it
+            kernel.wait_for_service::<SerialMuxService>().await;
+            if let Some(client) = SerialMuxClient::from_registry_no_retry(kernel).await {
+                return client;
             }
         }
     }
@@ -175,7 +182,8 @@ pub struct SerialMuxServer;
 impl SerialMuxServer {
     /// Register the `SerialMuxServer`.
     ///
-    /// Will retry to obtain a [`SimpleSerialClient`] until success.
+    /// Will wait for a [`SimpleSerialService`] to be registered if one isn't yet,
+    /// and retry to obtain a [`SimpleSerialClient`] until success.
     pub async fn register(
         kernel: &'static Kernel,
         max_ports: usize,
@@ -185,8 +193,8 @@ impl SerialMuxServer {
             match SerialMuxServer::register_no_retry(kernel, max_ports, max_frame).await {
                 Ok(_) => break,
                 Err(RegistrationError::SerialPortNotFound) => {
-                    // Uart probably isn't registered yet. Try again in a bit
-                    kernel.sleep(Duration::from_millis(10)).await;
+                    // Uart probably isn't registered yet. Wait until it is
+                    kernel.wait_for_service::<SimpleSerialService>().await;
                 }
                 Err(e) => {
                     panic!("uhhhh {e:?}");
@@ -218,11 +226,17 @@ impl SerialMuxServer {
         let (sprod, scons) = serial_port.split();
         let sprod = sprod.into_mpmc_producer().await;
 
-        let ports = FixedVec::new(max_ports).await;
-        let imutex = Arc::new(Mutex::new(MuxingInfo { ports, max_frame })).await;
+        let ports = SortedMap::with_capacity(max_ports).await;
+        let imutex = Arc::new(Mutex::new(MuxingInfo {
+            ports,
+            max_ports,
+            max_frame,
+        }))
+        .await;
         let (cmd_prod, cmd_cons) = KChannel::new_async(max_ports).await.split();
         let buf = FixedVec::new(max_frame).await;
         let commander = CommanderTask {
+            kernel,
             cmd: cmd_cons,
             out: sprod,
             mux: imutex.clone(),
@@ -233,10 +247,12 @@ impl SerialMuxServer {
             buf,
         };
 
-        kernel.spawn(commander.run()).await;
+        kernel
+            .spawn_named(Priority::System, Some("sermux::commander"), commander.run())
+            .await;
 
         kernel
-            .spawn(async move {
+            .spawn_named(Priority::System, Some("sermux::muxer"), async move {
                 muxer.run().await;
             })
             .await;
@@ -257,17 +273,15 @@ pub enum RegistrationError {
     MuxAlreadyRegistered,
 }
 
-struct PortInfo {
-    port: u16,
-    upstream: bbq::SpscProducer,
-}
-
 struct MuxingInfo {
-    ports: FixedVec<PortInfo>,
+    /// The upstream producer of each registered port, by port ID
+    ports: SortedMap<u16, bbq::SpscProducer>,
+    max_ports: usize,
     max_frame: usize,
 }
 
 struct CommanderTask {
+    kernel: &'static Kernel,
     cmd: KConsumer<Message<SerialMuxService>>,
     out: bbq::MpscProducer,
     mux: Arc<Mutex<MuxingInfo>>,
@@ -280,25 +294,23 @@ struct IncomingMuxerTask {
 }
 
 impl MuxingInfo {
-    async fn register_port(
+    /// Register a new port, with the channel `(prod, cons)` as its buffer.
+    fn register_port(
         &mut self,
         port_id: u16,
-        capacity: usize,
+        (prod, cons): (bbq::SpscProducer, bbq::Consumer),
         outgoing: &bbq::MpscProducer,
     ) -> Result<PortHandle, SerialMuxError> {
-        if self.ports.is_full() {
+        if self.ports.len() >= self.max_ports {
             return Err(SerialMuxError::RegistryFull);
         }
-        if self.ports.as_slice().iter().any(|p| p.port == port_id) {
+        if self.ports.contains_key(&port_id) {
             return Err(SerialMuxError::DuplicateItem);
         }
-        let (prod, cons) = bbq::new_spsc_channel(capacity).await;
-
+        // Room for `max_ports` entries was allocated up front, so this
+        // doesn't need to grow the map.
         self.ports
-            .try_push(PortInfo {
-                port: port_id,
-                upstream: prod,
-            })
+            .try_insert(port_id, prod)
             .map_err(|_| SerialMuxError::RegistryFull)?;
 
         let ph = PortHandle {
@@ -316,16 +328,25 @@ impl MuxingInfo {
 
 impl CommanderTask {
     async fn run(self) {
-        loop {
-            let msg = self.cmd.dequeue_async().await.map_err(drop).unwrap();
+        // The queue is closed once the service is deregistered.
+        while let Ok(msg) = self.cmd.dequeue_async().await {
             let Message { msg: req, reply } = msg;
             match req.body {
                 Request::RegisterPort { port_id, capacity } => {
-                    let res = {
-                        let mut mux = self.mux.lock().await;
-                        mux.register_port(port_id, capacity, &self.out).await
-                    }
-                    .map(Response::PortRegistered);
+                    // Allocate the port's buffer before taking the mux lock,
+                    // so that waiting for memory doesn't stall every other
+                    // port. If the port can't be registered, the buffer is
+                    // freed again.
+                    let cancel = self.kernel.sleep(PORT_ALLOC_TIMEOUT);
+                    let res = match bbq::new_spsc_channel_or_cancel(capacity, cancel).await {
+                        Ok(chan) => self
+                            .mux
+                            .lock()
+                            .await
+                            .register_port(port_id, chan, &self.out)
+                            .map(Response::PortRegistered),
+                        Err(_) => Err(SerialMuxError::OutOfMemory),
+                    };
 
                     let resp = req.reply_with(res);
 
@@ -393,8 +414,8 @@ impl IncomingMuxerTask {
 
                 // Great, now we have a message! Let's see if we have someone listening to this port
                 let mux = self.mux.lock().await;
-                if let Some(port) = mux.ports.as_slice().iter().find(|p| p.port == port_id) {
-                    if let Some(mut wgr) = port.upstream.send_grant_exact_sync(datab.len()) {
+                if let Some(upstream) = mux.ports.get(&port_id) {
+                    if let Some(mut wgr) = upstream.send_grant_exact_sync(datab.len()) {
                         wgr.copy_from_slice(datab);
                         wgr.commit(datab.len());
                         debug!(port_id, len = datab.len(), "Sent bytes to port");
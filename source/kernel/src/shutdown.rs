@@ -0,0 +1,125 @@
+//! Kernel shutdown
+//!
+//! A shutdown is requested with [`Kernel::shutdown()`][crate::Kernel::shutdown],
+//! which can be called from any task. It proceeds in three steps:
+//!
+//! 1. Every task waiting in [`Kernel::wait_for_shutdown()`][crate::Kernel::wait_for_shutdown]
+//!    is woken, so that services can flush any buffered data (e.g. traces that
+//!    haven't been sent yet), and other tasks can finish what they are doing.
+//! 2. The kernel keeps running until every [`ShutdownGuard`] has been dropped,
+//!    or the shutdown deadline has passed, whichever comes first. Tasks take a
+//!    guard with [`Kernel::shutdown_guard()`][crate::Kernel::shutdown_guard]
+//!    to delay the shutdown until they are done.
+//! 3. Once [`Kernel::shutdown_complete()`][crate::Kernel::shutdown_complete]
+//!    returns the [`ShutdownReason`], the platform stops running the kernel,
+//!    and halts or reboots the system. Tasks which are still running are never
+//!    polled again.
+
+use maitake::sync::WaitQueue;
+use mycelium_util::sync::InitOnce;
+use portable_atomic::{AtomicU64, AtomicUsize, Ordering};
+use serde::{Deserialize, Serialize};
+
+/// Why the kernel was shut down.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ShutdownReason {
+    /// The system should be halted, or the simulator should exit
+    Halt,
+    /// The system should be restarted
+    Reboot,
+    /// The system should be halted because of an error, with an error code
+    /// that the platform may report, see [`ShutdownReason::code()`]
+    Error(u32),
+}
+
+/// Delays the kernel shutdown until it is dropped, or the shutdown deadline
+/// passes.
+///
+/// Returned by [`Kernel::shutdown_guard()`][crate::Kernel::shutdown_guard].
+#[must_use = "the shutdown is only delayed until the guard is dropped"]
+pub struct ShutdownGuard {
+    state: &'static ShutdownState,
+}
+
+pub(crate) struct ShutdownState {
+    reason: InitOnce<ShutdownReason>,
+    /// The uptime, in timer ticks, after which the kernel shuts down even if
+    /// guards are still held.
+    deadline: AtomicU64,
+    guards: AtomicUsize,
+    requested: WaitQueue,
+}
+
+// ShutdownReason
+
+impl ShutdownReason {
+    /// A numeric code for the reason, e.g. to use as a process exit code.
+    ///
+    /// `Halt` is `0`, `Reboot` is `1`, and `Error(code)` is `code`, or `2`
+    /// for codes below `2`, so that an error can't be mistaken for a halt or
+    /// reboot.
+    pub fn code(&self) -> u32 {
+        match self {
+            ShutdownReason::Halt => 0,
+            ShutdownReason::Reboot => 1,
+            ShutdownReason::Error(code) => (*code).max(2),
+        }
+    }
+}
+
+// ShutdownGuard
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.state.guards.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// ShutdownState
+
+impl ShutdownState {
+    pub(crate) fn new() -> Self {
+        Self {
+            reason: InitOnce::uninitialized(),
+            deadline: AtomicU64::new(u64::MAX),
+            guards: AtomicUsize::new(0),
+            requested: WaitQueue::new(),
+        }
+    }
+
+    pub(crate) fn guard(&'static self) -> ShutdownGuard {
+        self.guards.fetch_add(1, Ordering::AcqRel);
+        ShutdownGuard { state: self }
+    }
+
+    /// Returns `false` if a shutdown was already requested.
+    pub(crate) fn request(&self, reason: ShutdownReason, deadline: u64) -> bool {
+        if self.reason.try_init(reason).is_err() {
+            return false;
+        }
+        self.deadline.store(deadline, Ordering::Release);
+        self.requested.close();
+        true
+    }
+
+    pub(crate) fn requested(&self) -> Option<ShutdownReason> {
+        self.reason.try_get().copied()
+    }
+
+    pub(crate) async fn wait(&self) -> ShutdownReason {
+        loop {
+            if let Some(reason) = self.requested() {
+                return reason;
+            }
+            // Closed once the shutdown is requested.
+            let _ = self.requested.wait().await;
+        }
+    }
+
+    pub(crate) fn complete(&self, now: u64) -> Option<ShutdownReason> {
+        let reason = self.requested()?;
+        let done = self.guards.load(Ordering::Acquire) == 0
+            || now >= self.deadline.load(Ordering::Acquire);
+        done.then_some(reason)
+    }
+}
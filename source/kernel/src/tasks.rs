@@ -0,0 +1,561 @@
+//! Kernel task table
+//!
+//! Every task spawned with [`Kernel::spawn()`][crate::Kernel::spawn] or
+//! [`Kernel::initialize()`][crate::Kernel::initialize] is recorded in the
+//! kernel's task table, along with an optional name, the time it was
+//! spawned, and how many times it has been polled. A snapshot of the table
+//! can be obtained with [`Kernel::tasks()`][crate::Kernel::tasks], and a task
+//! can be stopped with [`Kernel::kill()`][crate::Kernel::kill].
+//!
+//! If the platform provides a [busy clock][crate::KernelSettings::busy_clock],
+//! every poll of a task is also timed, and the total is reported as the
+//! task's [busy time][TaskInfo::busy]. The
+//! [`task_stats`][crate::daemons::task_stats] daemon periodically logs a
+//! summary of this. Polls which take longer than the
+//! [poll budget][crate::KernelSettings::poll_budget] are reported as
+//! warnings, see the [watchdog][crate::watchdog] module.
+//!
+//! With the `heap-quota` feature, each tracked task also gets its own heap
+//! [quota] account, so the table reports how much memory each task has
+//! allocated, and a task's allocations can be limited with
+//! [`Kernel::set_heap_quota()`][crate::Kernel::set_heap_quota]. If the quota
+//! account table is full, the task's allocations aren't accounted for.
+//!
+//! Tasks spawned with [`Kernel::spawn_allocated()`][crate::Kernel::spawn_allocated]
+//! are not recorded, as their storage has already been allocated by the caller.
+
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use maitake::time::Duration;
+use mnemos_alloc::{
+    containers::{Arc, FixedVec},
+    policy,
+    quota::{self, Account},
+};
+use portable_atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use crate::tracing::{self, debug, info, warn};
+
+/// A kernel-assigned identifier for a task in the task table.
+///
+/// Task IDs are never reused while the kernel is running, unless more than
+/// `u32::MAX` tasks have been spawned.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TaskId(pub(crate) u32);
+
+/// The scheduling priority of a task.
+///
+/// [`Priority::System`] tasks always run before [`Priority::User`] tasks, see
+/// [`Kernel::tick()`][crate::Kernel::tick].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Priority {
+    /// Drivers and other kernel services.
+    System,
+    /// Everything else, such as Forth shells.
+    User,
+}
+
+/// The output of a task's `JoinHandle` if the task was stopped by
+/// [`Kernel::kill()`][crate::Kernel::kill] before it completed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Killed;
+
+/// A snapshot of a single task in the task table.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    /// The name the task was spawned with, if any
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+    /// The kernel's [uptime][crate::Kernel::uptime] when the task was spawned
+    pub spawned_at: Duration,
+    /// The number of times the task has been polled
+    pub polls: u64,
+    /// The total time spent polling the task.
+    ///
+    /// This is always zero if the platform has no
+    /// [busy clock][crate::KernelSettings::busy_clock].
+    pub busy: Duration,
+    /// The number of polls which took longer than the
+    /// [poll budget][crate::KernelSettings::poll_budget]
+    pub overruns: u32,
+    /// The task's heap usage, if its allocations are accounted for
+    pub heap: Option<quota::Usage>,
+}
+
+/// The table of running tasks, see the [module level docs][self].
+pub(crate) struct TaskTable {
+    tasks: FixedVec<Arc<TaskEntry>>,
+    next_id: u32,
+    clock: Option<PollClock>,
+}
+
+/// Times task polls, see [`KernelSettings::busy_clock`][crate::KernelSettings::busy_clock].
+#[derive(Copy, Clone)]
+pub(crate) struct PollClock {
+    pub(crate) now: fn() -> u32,
+    /// The duration of one tick of `now`
+    pub(crate) granularity: Duration,
+    /// The poll budget, in ticks of `now`
+    pub(crate) budget: Option<u32>,
+}
+
+/// A future which records its polls in the task table, and can be killed.
+pub(crate) struct Tracked<F> {
+    inner: Option<F>,
+    entry: Option<Arc<TaskEntry>>,
+    clock: Option<PollClock>,
+}
+
+/// The part of a task table entry shared between the table and the task.
+struct TaskEntry {
+    id: TaskId,
+    name: Option<&'static str>,
+    priority: Priority,
+    spawned_at: Duration,
+    polls: AtomicU64,
+    /// Total busy clock ticks spent polling the task
+    busy_ticks: AtomicU64,
+    /// Polls which took longer than the poll budget
+    overruns: AtomicU32,
+    /// The heap quota account the task's allocations are charged to
+    account: Option<Account>,
+    state: AtomicU8,
+    /// The waker from the task's most recent poll, used to get the task to
+    /// notice that it was killed.
+    ///
+    /// The kernel is single threaded, and this is only ever accessed from
+    /// task context, so a `RefCell` is sufficient.
+    waker: RefCell<Option<Waker>>,
+}
+
+/// The task is running (or waiting to be polled).
+const TASK_RUNNING: u8 = 0;
+/// The task has been killed, but has not yet been polled since.
+const TASK_KILLED: u8 = 1;
+/// The task has completed, or was dropped.
+const TASK_DONE: u8 = 2;
+
+// TaskId
+
+impl TaskId {
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+// TaskTable
+
+impl TaskTable {
+    /// Create a new task table with room for up to `max_tasks` tasks.
+    ///
+    /// If `clock` is provided, polls are timed with it.
+    pub(crate) fn new(max_tasks: usize, clock: Option<PollClock>) -> Self {
+        Self {
+            tasks: FixedVec::try_new(max_tasks).unwrap(),
+            next_id: 0,
+            clock,
+        }
+    }
+
+    /// Wrap `fut` so it is recorded in the task table.
+    ///
+    /// If the table is full, or its entry cannot be allocated, the task still
+    /// runs, but will not show up in the table and cannot be killed.
+    pub(crate) fn track<F: Future>(
+        &mut self,
+        priority: Priority,
+        name: Option<&'static str>,
+        spawned_at: Duration,
+        fut: F,
+    ) -> Tracked<F> {
+        self.prune();
+        let id = TaskId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let entry = if self.tasks.is_full() {
+            warn!(task.id = id.0, task.name = ?name, "Task table full, not tracking task");
+            None
+        } else {
+            Arc::try_new(TaskEntry {
+                id,
+                name,
+                priority,
+                spawned_at,
+                polls: AtomicU64::new(0),
+                busy_ticks: AtomicU64::new(0),
+                overruns: AtomicU32::new(0),
+                account: quota::ENABLED
+                    .then(|| Account::open(id.0, None).ok())
+                    .flatten(),
+                state: AtomicU8::new(TASK_RUNNING),
+                waker: RefCell::new(None),
+            })
+            .ok()
+        };
+
+        match entry.as_ref() {
+            Some(entry) => {
+                // We checked for room above
+                let _ = self.tasks.try_push(entry.clone());
+                debug!(task.id = id.0, task.name = ?name, ?priority, "Spawned task");
+            }
+            None => warn!(task.id = id.0, task.name = ?name, "Failed to track task"),
+        }
+
+        Tracked {
+            inner: Some(fut),
+            entry,
+            clock: self.clock,
+        }
+    }
+
+    /// Returns the maximum number of tasks the table can hold.
+    pub(crate) fn capacity(&self) -> usize {
+        self.tasks.as_vec().capacity()
+    }
+
+    /// Iterate over every running task, in the order they were spawned.
+    pub(crate) fn tasks(&self) -> impl Iterator<Item = TaskInfo> + '_ {
+        self.tasks
+            .as_slice()
+            .iter()
+            .filter(|e| e.state.load(Ordering::Acquire) != TASK_DONE)
+            .map(|e| e.info(self.clock.as_ref()))
+    }
+
+    /// Kill the task with the given `id`.
+    ///
+    /// The task's future is dropped the next time the task is polled, which
+    /// happens on the next tick of the scheduler. Returns `false` if there is
+    /// no running task with that ID.
+    pub(crate) fn kill(&mut self, id: TaskId) -> bool {
+        self.prune();
+        let Some(entry) = self.tasks.as_slice().iter().find(|e| e.id == id) else {
+            return false;
+        };
+        let swap = entry.state.compare_exchange(
+            TASK_RUNNING,
+            TASK_KILLED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        if swap.is_err() {
+            return false;
+        }
+        info!(task.id = id.0, task.name = ?entry.name, "Killing task");
+        if let Some(waker) = entry.waker.borrow_mut().take() {
+            waker.wake();
+        }
+        true
+    }
+
+    /// Set the heap quota of the task with the given `id`, see
+    /// [`Kernel::set_heap_quota()`][crate::Kernel::set_heap_quota].
+    pub(crate) fn set_heap_quota(&mut self, id: TaskId, limit: Option<usize>) -> bool {
+        self.prune();
+        let account = self
+            .tasks
+            .as_slice()
+            .iter()
+            .find(|e| e.id == id && e.state.load(Ordering::Acquire) == TASK_RUNNING)
+            .and_then(|e| e.account);
+        match account {
+            Some(account) => {
+                info!(task.id = id.0, ?limit, "Setting heap quota");
+                account.set_limit(limit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the entries of tasks which have completed.
+    fn prune(&mut self) {
+        while let Some(idx) = self
+            .tasks
+            .as_slice()
+            .iter()
+            .position(|e| e.state.load(Ordering::Acquire) == TASK_DONE)
+        {
+            self.tasks.remove(idx);
+        }
+    }
+}
+
+// TaskEntry
+
+impl TaskEntry {
+    fn info(&self, clock: Option<&PollClock>) -> TaskInfo {
+        let busy_ticks = self.busy_ticks.load(Ordering::Relaxed);
+        TaskInfo {
+            id: self.id,
+            name: self.name,
+            priority: self.priority,
+            spawned_at: self.spawned_at,
+            polls: self.polls.load(Ordering::Relaxed),
+            busy: clock.map_or(Duration::ZERO, |c| c.duration(busy_ticks)),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            heap: self.account.and_then(|a| a.usage()),
+        }
+    }
+}
+
+// PollClock
+
+impl PollClock {
+    /// Convert a number of clock ticks to a [`Duration`].
+    pub(crate) fn duration(&self, ticks: u64) -> Duration {
+        let nanos = self.granularity.as_nanos() * u128::from(ticks);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// Convert a [`Duration`] to a number of clock ticks, saturating.
+    pub(crate) fn ticks(&self, duration: Duration) -> u32 {
+        let ticks = duration.as_nanos() / self.granularity.as_nanos().max(1);
+        u32::try_from(ticks).unwrap_or(u32::MAX)
+    }
+}
+
+// Tracked
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = Result<F::Output, Killed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `inner` is structurally pinned, it is never moved out of,
+        // only dropped in place. `entry` is not pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        let mut start = None;
+        // Charge the task's allocations to its account while it is polled,
+        // including any it frees by being killed.
+        let _account = this
+            .entry
+            .as_ref()
+            .and_then(|e| e.account.as_ref())
+            .map(Account::enter);
+        // Drivers and services get served first when memory is tight, if
+        // the allocator's OOM policy goes by priority.
+        let _priority = this.entry.as_ref().map(|e| {
+            policy::enter_priority(match e.priority {
+                Priority::System => 1,
+                Priority::User => 0,
+            })
+        });
+        if let Some(entry) = this.entry.as_ref() {
+            if entry.state.load(Ordering::Acquire) == TASK_KILLED {
+                // Drop the task's future right away, so it releases any
+                // resources it holds.
+                inner.set(None);
+                debug!(task.id = entry.id.0, task.name = ?entry.name, "Task killed");
+                return Poll::Ready(Err(Killed));
+            }
+            entry.polls.fetch_add(1, Ordering::Relaxed);
+            let mut waker = entry.waker.borrow_mut();
+            match waker.as_ref() {
+                Some(w) if w.will_wake(cx.waker()) => {}
+                _ => *waker = Some(cx.waker().clone()),
+            }
+            start = this.clock.map(|c| (c.now)());
+        }
+
+        let poll = inner
+            .as_pin_mut()
+            .expect("task polled after completion")
+            .poll(cx);
+
+        if let (Some(entry), Some(clock), Some(start)) = (this.entry.as_ref(), this.clock, start) {
+            // The clock is allowed to wrap, which is fine as long as no
+            // single poll takes a whole lap of it.
+            let busy = (clock.now)().wrapping_sub(start);
+            entry
+                .busy_ticks
+                .fetch_add(u64::from(busy), Ordering::Relaxed);
+
+            if clock.budget.map_or(false, |budget| busy > budget) {
+                entry.overruns.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    task.id = entry.id.0,
+                    task.name = ?entry.name,
+                    poll_us = clock.duration(u64::from(busy)).as_micros() as u64,
+                    "Task exceeded its poll budget"
+                );
+            }
+        }
+
+        poll.map(Ok)
+    }
+}
+
+impl<F> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.as_ref() {
+            entry.state.store(TASK_DONE, Ordering::Release);
+            entry.waker.borrow_mut().take();
+            // Memory the task still has allocated (including its future,
+            // which is dropped after this) is no longer accounted for.
+            if let Some(account) = entry.account {
+                account.close();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::{sync::Arc as StdArc, task::Wake};
+    use core::{future::pending, pin::pin, sync::atomic::AtomicBool};
+    use futures::FutureExt;
+
+    #[derive(Default)]
+    struct Woken(AtomicBool);
+
+    impl Wake for Woken {
+        fn wake(self: StdArc<Self>) {
+            self.0.store(true, core::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    fn ids(table: &TaskTable) -> alloc::vec::Vec<u32> {
+        table.tasks().map(|t| t.id.id()).collect()
+    }
+
+    #[test]
+    fn spawn_and_list() {
+        let mut table = TaskTable::new(4, None);
+        let first = table.track(
+            Priority::System,
+            Some("first"),
+            Duration::from_secs(1),
+            async { 1 },
+        );
+        let mut second = pin!(table.track(
+            Priority::User,
+            None,
+            Duration::from_secs(2),
+            pending::<()>()
+        ));
+
+        let tasks = table.tasks().collect::<alloc::vec::Vec<_>>();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].id, TaskId(0));
+        assert_eq!(tasks[0].name, Some("first"));
+        assert_eq!(tasks[0].priority, Priority::System);
+        assert_eq!(tasks[0].spawned_at, Duration::from_secs(1));
+        assert_eq!(tasks[1].id, TaskId(1));
+        assert_eq!(tasks[1].name, None);
+        assert_eq!(tasks[1].priority, Priority::User);
+        assert!(tasks.iter().all(|t| t.polls == 0));
+
+        assert!(second.as_mut().now_or_never().is_none());
+        assert!(second.as_mut().now_or_never().is_none());
+        assert_eq!(table.tasks().nth(1).unwrap().polls, 2);
+
+        // Completed tasks leave the table.
+        assert_eq!(first.now_or_never(), Some(Ok(1)));
+        assert_eq!(ids(&table), [1]);
+    }
+
+    #[test]
+    fn kill() {
+        let mut table = TaskTable::new(4, None);
+        let held = StdArc::new(());
+        let task = {
+            let held = held.clone();
+            async move {
+                let _held = held;
+                pending::<()>().await
+            }
+        };
+        let mut task = pin!(table.track(Priority::User, None, Duration::ZERO, task));
+        let woken = StdArc::new(Woken::default());
+        let waker = Waker::from(woken.clone());
+        let poll = task.as_mut().poll(&mut Context::from_waker(&waker));
+        assert!(poll.is_pending());
+
+        assert!(!table.kill(TaskId(1)));
+        assert!(table.kill(TaskId(0)));
+        // The task is woken to notice...
+        assert!(woken.0.load(core::sync::atomic::Ordering::SeqCst));
+        // ...and can't be killed twice.
+        assert!(!table.kill(TaskId(0)));
+
+        // The next poll drops the task's future, and the JoinHandle gets
+        // `Killed`.
+        assert_eq!(task.as_mut().now_or_never(), Some(Err(Killed)));
+        assert_eq!(StdArc::strong_count(&held), 1);
+        assert_eq!(ids(&table), [0]);
+        drop(task);
+        assert!(ids(&table).is_empty());
+        assert!(!table.kill(TaskId(0)));
+    }
+
+    #[test]
+    fn full_table_still_runs() {
+        let mut table = TaskTable::new(1, None);
+        let _first = table.track(Priority::User, None, Duration::ZERO, pending::<()>());
+        let second = table.track(Priority::User, None, Duration::ZERO, async { 2 });
+        assert_eq!(ids(&table), [0]);
+        // The untracked task still runs, but can't be killed.
+        assert!(!table.kill(TaskId(1)));
+        assert_eq!(second.now_or_never(), Some(Ok(2)));
+    }
+
+    /// A busy clock which advances by 5 ticks every time it is read, so
+    /// every poll takes 5 ticks.
+    fn five_ticks_per_read() -> u32 {
+        static NOW: AtomicU32 = AtomicU32::new(0);
+        NOW.fetch_add(5, Ordering::Relaxed)
+    }
+
+    #[test]
+    fn busy_time() {
+        let clock = PollClock {
+            now: five_ticks_per_read,
+            granularity: Duration::from_micros(2),
+            budget: Some(4),
+        };
+        for (clock, busy, overruns) in [
+            (Some(clock), Duration::from_micros(30), 3),
+            (
+                Some(PollClock {
+                    budget: None,
+                    ..clock
+                }),
+                Duration::from_micros(30),
+                0,
+            ),
+            // Without a busy clock, only polls are counted.
+            (None, Duration::ZERO, 0),
+        ] {
+            let mut table = TaskTable::new(1, clock);
+            let mut task = pin!(table.track(Priority::User, None, Duration::ZERO, pending::<()>()));
+            for _ in 0..3 {
+                assert!(task.as_mut().now_or_never().is_none());
+            }
+            let info = table.tasks().next().unwrap();
+            assert_eq!(info.polls, 3);
+            assert_eq!(info.busy, busy);
+            assert_eq!(info.overruns, overruns);
+        }
+    }
+
+    #[test]
+    fn clock_conversions() {
+        let clock = PollClock {
+            now: five_ticks_per_read,
+            granularity: Duration::from_micros(10),
+            budget: None,
+        };
+        assert_eq!(clock.duration(3), Duration::from_micros(30));
+        assert_eq!(clock.ticks(Duration::from_micros(35)), 3);
+        assert_eq!(clock.ticks(Duration::from_secs(u64::MAX)), u32::MAX);
+    }
+}
@@ -1,10 +1,16 @@
-use crate::{comms::bbq, services::serial_mux};
-use core::time::Duration;
+use crate::{
+    comms::bbq,
+    services::serial_mux::{self, WellKnown},
+    tasks::Priority,
+};
+use core::{cell::UnsafeCell, fmt::Write, panic::PanicInfo, time::Duration};
 use level_filters::LevelFilter;
-use mnemos_trace_proto::{HostRequest, TraceEvent};
+use mnemos_alloc::heap::HeapStats;
+use mnemos_trace_proto::{HostRequest, PanicLocation, PanicReport, TraceEvent};
 use mycelium_util::sync::InitOnce;
 use portable_atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
+use sermux_proto::PortChunk;
 pub use tracing_02::*;
 use tracing_core_02::span::Current;
 use tracing_serde_structured::{AsSerde, SerializeRecordFields, SerializeSpanFields};
@@ -40,8 +46,50 @@ pub struct SerialCollector {
     /// Tracks whether we are inside of the collector's `send_event` method, so
     /// that BBQueue tracing can be disabled.
     in_send: AtomicBool,
+
+    /// IDs of the currently entered spans, outermost first, for panic reports.
+    span_stack: [AtomicU64; MAX_SPAN_DEPTH],
+
+    /// The number of currently entered spans. This may be larger than
+    /// [`MAX_SPAN_DEPTH`], in which case the innermost spans aren't recorded.
+    span_depth: AtomicUsize,
+
+    /// The most recently sent events, for panic reports.
+    ///
+    /// Only accessed while `recent_lock` is held.
+    recent: UnsafeCell<RecentEvents>,
+    recent_lock: AtomicBool,
+}
+
+/// The number of entered spans recorded for panic reports.
+const MAX_SPAN_DEPTH: usize = 16;
+
+/// The number of recent events kept for panic reports.
+const RECENT_EVENTS: usize = 8;
+
+/// The largest encoded event kept for panic reports. Larger events are left
+/// out of the report.
+const RECENT_EVENT_SIZE: usize = 256;
+
+/// A ring of the most recently sent encoded events.
+struct RecentEvents {
+    /// The slot the next event is written to, which is also the oldest event.
+    next: usize,
+    lens: [usize; RECENT_EVENTS],
+    slots: [[u8; RECENT_EVENT_SIZE]; RECENT_EVENTS],
+}
+
+/// A fixed size buffer for formatting a panic message, which truncates
+/// anything that doesn't fit.
+struct MessageBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
 }
 
+// SAFETY: `recent` is only accessed while `recent_lock` is held, everything
+// else is atomic.
+unsafe impl Sync for SerialCollector {}
+
 // === impl SerialCollector ===
 
 impl SerialCollector {
@@ -55,6 +103,7 @@ impl SerialCollector {
     }
 
     pub const fn with_max_level(max_level: LevelFilter) -> Self {
+        const NO_SPAN: AtomicU64 = AtomicU64::new(0);
         Self {
             tx: InitOnce::uninitialized(),
             isr_tx: InitOnce::uninitialized(),
@@ -67,6 +116,14 @@ impl SerialCollector {
             dropped_span_activity: AtomicUsize::new(0),
             max_level: AtomicU8::new(level_to_u8(max_level)),
             in_send: AtomicBool::new(false),
+            span_stack: [NO_SPAN; MAX_SPAN_DEPTH],
+            span_depth: AtomicUsize::new(0),
+            recent: UnsafeCell::new(RecentEvents {
+                next: 0,
+                lens: [0; RECENT_EVENTS],
+                slots: [[0; RECENT_EVENT_SIZE]; RECENT_EVENTS],
+            }),
+            recent_lock: AtomicBool::new(false),
         }
     }
 
@@ -87,7 +144,12 @@ impl SerialCollector {
             .expect("cannot set global default tracing dispatcher");
 
         // spawn a worker to read from the channel and write to the serial port.
-        k.spawn(Self::worker(self, rx, isr_rx, port, k)).await;
+        k.spawn_named(
+            Priority::System,
+            Some("trace::worker"),
+            Self::worker(self, rx, isr_rx, port, k),
+        )
+        .await;
     }
 
     /// Serialize a `TraceEvent`, returning `true` if the event was correctly serialized.
@@ -104,12 +166,18 @@ impl SerialCollector {
 
         // got a write grant! generate the event payload.
         let ev = event();
+        let is_event = matches!(ev, TraceEvent::Event { .. });
 
         // encode the event to our write grant, and commit however many bytes
         // were written. if encoding fails, commit 0 bytes, so the region we got
         // a write grant for can be reused.
         let len = match postcard::to_slice_cobs(&ev, &mut wgr[..]) {
-            Ok(encoded) => encoded.len(),
+            Ok(encoded) => {
+                if is_event {
+                    self.record_recent(encoded);
+                }
+                encoded.len()
+            }
             Err(_) => 0,
         };
         wgr.commit(len);
@@ -206,6 +274,10 @@ impl SerialCollector {
                 }
             }
 
+            // Now that a host is listening, don't let the kernel shut down
+            // before any buffered traces are sent.
+            let _guard = k.shutdown_guard();
+
             loop {
                 futures::select_biased! {
                     // something to send to the serial port!
@@ -244,6 +316,19 @@ impl SerialCollector {
                             port.send(buf).await;
                         }
                     }
+                    // flush everything that's left before the kernel shuts down
+                    _ = k.wait_for_shutdown().fuse() => {
+                        // (the buffered data may wrap around the end of the
+                        // ring buffer, so it can take two grants)
+                        for rx in [&isr_rx, &rx, &isr_rx, &rx] {
+                            if let Some(rgr) = rx.read_grant_sync() {
+                                let len = rgr.len();
+                                port.send(&rgr[..]).await;
+                                rgr.release(len);
+                            }
+                        }
+                        return;
+                    }
                     // TODO(eliza): make the host also send a heartbeat, and
                     // if we don't get it, break back to the idle loop...
                 }
@@ -251,6 +336,111 @@ impl SerialCollector {
         }
     }
 
+    /// Encode a [`PanicReport`] for `info`, framed for the
+    /// [`WellKnown::PanicReport`] SerMux port, into `buf`.
+    ///
+    /// This is intended to be called from a platform's panic handler, which
+    /// writes the returned frame directly to the serial port. It does not
+    /// allocate, and doesn't wait for anything. The first half of `buf` holds
+    /// the frame, and the second half is used as scratch space, so the report
+    /// must fit in half of `buf`. If it does not, the oldest recent events are
+    /// left out until it does. Returns `None` if it doesn't fit even without
+    /// any events.
+    pub fn panic_report<'b>(
+        &self,
+        info: &PanicInfo<'_>,
+        heap: Option<HeapStats>,
+        buf: &'b mut [u8],
+    ) -> Option<&'b [u8]> {
+        let mut message = MessageBuf::<256>::new();
+        let _ = match info.message() {
+            Some(args) => write!(&mut message, "{args}"),
+            None => match info.payload().downcast_ref::<&str>() {
+                Some(msg) => message.write_str(msg),
+                None => message.write_str("<no message>"),
+            },
+        };
+
+        let mut spans = [0u64; MAX_SPAN_DEPTH];
+        let depth = self.span_depth.load(Ordering::Acquire).min(MAX_SPAN_DEPTH);
+        for (id, slot) in spans.iter_mut().zip(&self.span_stack[..depth]) {
+            *id = slot.load(Ordering::Acquire);
+        }
+
+        // If we panicked while recording an event, the ring may be
+        // inconsistent, so leave it out.
+        let mut events: [&[u8]; RECENT_EVENTS] = [&[]; RECENT_EVENTS];
+        let mut num_events = 0;
+        if !self.recent_lock.swap(true, Ordering::Acquire) {
+            // SAFETY: we hold the lock, and never release it, as we are
+            // panicking.
+            let recent = unsafe { &*self.recent.get() };
+            for i in 0..RECENT_EVENTS {
+                let idx = (recent.next + i) % RECENT_EVENTS;
+                let len = recent.lens[idx];
+                if len > 0 {
+                    events[num_events] = &recent.slots[idx][..len];
+                    num_events += 1;
+                }
+            }
+        }
+
+        let mut report = PanicReport {
+            message: message.as_str(),
+            location: info.location().map(|loc| PanicLocation {
+                file: loc.file(),
+                line: loc.line(),
+                column: loc.col(),
+            }),
+            spans: &spans[..depth],
+            recent_events: &events[..num_events],
+            heap: heap.map(|heap| mnemos_trace_proto::HeapStats {
+                heap_size: heap.heap_size,
+                allocated: heap.allocated,
+                peak_allocated: heap.peak_allocated,
+                failed_allocs: heap.failed_allocs,
+                largest_free_block: heap.largest_free_block,
+            }),
+        };
+
+        let (frame, scratch) = buf.split_at_mut(buf.len() / 2);
+        // The events are the bulk of the report, so rather than dropping the
+        // whole report, drop the oldest events until it fits.
+        let mut len = None;
+        for oldest in 0..=num_events {
+            report.recent_events = &events[oldest..num_events];
+            let Ok(encoded) = postcard::to_slice(&report, &mut *scratch) else {
+                continue;
+            };
+            if PortChunk::new(WellKnown::PanicReport, encoded).buffer_required() <= frame.len() {
+                len = Some(encoded.len());
+                break;
+            }
+        }
+        let frame = PortChunk::new(WellKnown::PanicReport, &scratch[..len?])
+            .encode_to(frame)
+            .ok()?;
+        Some(frame)
+    }
+
+    /// Keep a copy of an encoded event for panic reports.
+    fn record_recent(&self, encoded: &[u8]) {
+        if encoded.len() > RECENT_EVENT_SIZE {
+            return;
+        }
+        // If we interrupted another call, just drop this event.
+        if self.recent_lock.swap(true, Ordering::Acquire) {
+            return;
+        }
+        // SAFETY: we hold the lock.
+        let recent = unsafe { &mut *self.recent.get() };
+        let idx = recent.next;
+        recent.slots[idx][..encoded.len()].copy_from_slice(encoded);
+        recent.lens[idx] = encoded.len();
+        recent.next = (idx + 1) % RECENT_EVENTS;
+        self.recent_lock.store(false, Ordering::Release);
+    }
+
     #[inline]
     fn level_enabled(&self, metadata: &Metadata<'_>) -> bool {
         // TODO(eliza): more sophisticated filtering
@@ -325,12 +515,23 @@ impl Collect for SerialCollector {
     }
 
     fn enter(&self, span: &span::Id) {
+        let depth = self.span_depth.fetch_add(1, Ordering::AcqRel);
+        if let Some(slot) = self.span_stack.get(depth) {
+            slot.store(span.into_u64(), Ordering::Release);
+        }
+
         if !self.send_event(16, || TraceEvent::Enter(span.as_serde())) {
             self.dropped_span_activity.fetch_add(1, Ordering::Relaxed);
         }
     }
 
     fn exit(&self, span: &span::Id) {
+        let _ = self
+            .span_depth
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |depth| {
+                depth.checked_sub(1)
+            });
+
         if !self.send_event(16, || TraceEvent::Exit(span.as_serde())) {
             self.dropped_span_activity.fetch_add(1, Ordering::Relaxed);
         }
@@ -380,6 +581,35 @@ impl Collect for SerialCollector {
     }
 }
 
+// === impl MessageBuf ===
+
+impl<const N: usize> MessageBuf<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole `str`s, or prefixes of them ending on a char boundary,
+        // are ever written.
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("<invalid utf8>")
+    }
+}
+
+impl<const N: usize> Write for MessageBuf<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut len = s.len().min(N - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..][..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
 const fn level_to_u8(level: LevelFilter) -> u8 {
     match level {
         LevelFilter::TRACE => 0,
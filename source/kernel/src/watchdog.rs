@@ -0,0 +1,28 @@
+//! Kernel watchdog
+//!
+//! MnemOS runs every task on a single thread, so a task which never yields
+//! hangs the whole system. The kernel has two ways of dealing with this:
+//!
+//! * In software, every poll of a task is timed with the platform's
+//!   [busy clock][crate::KernelSettings::busy_clock]. Any poll which takes
+//!   longer than the [poll budget][crate::KernelSettings::poll_budget] is
+//!   reported with a `tracing` warning, and counted in the task's
+//!   [`overruns`][crate::tasks::TaskInfo::overruns]. This can only report a
+//!   slow poll once it has returned, so it finds tasks that are hogging the
+//!   CPU, not tasks that are stuck forever.
+//! * In hardware, a platform can implement [`Watchdog`] for its watchdog
+//!   timer, and register it with [`Kernel::set_watchdog()`][crate::Kernel::set_watchdog].
+//!   The kernel pets it on every [`Kernel::tick()`][crate::Kernel::tick], so
+//!   a task which never yields will cause the watchdog to reset the system.
+
+/// A hardware watchdog timer, which resets the system unless it is petted
+/// regularly.
+///
+/// The kernel pets the watchdog at the start of every scheduler tick. The
+/// platform is responsible for configuring and starting the watchdog, and
+/// its timeout must be longer than both the longest expected poll of any task,
+/// and the longest time the platform sleeps while the kernel is idle.
+pub trait Watchdog {
+    /// Reset the watchdog's countdown.
+    fn pet(&self);
+}
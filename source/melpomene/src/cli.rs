@@ -1,6 +1,6 @@
 use crate::{sim_drivers::tcp_serial, sim_tracing};
 use clap::Parser;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -17,4 +17,17 @@ pub struct MelpomeneOptions {
     /// Address to bind the TCP listener for the simulated serial port.
     #[clap(long, default_value_t = tcp_serial::default_addr())]
     pub serial_addr: SocketAddr,
+
+    /// Path to a postcard-encoded kernel `BootConfig`.
+    ///
+    /// If this is not set, the default configuration is used.
+    #[clap(long)]
+    pub boot_config: Option<PathBuf>,
+
+    /// Write the boot config to this path, postcard-encoded, and exit.
+    ///
+    /// This can be used to generate a boot config file to edit, or to load
+    /// onto a hardware platform.
+    #[clap(long)]
+    pub save_boot_config: Option<PathBuf>,
 }
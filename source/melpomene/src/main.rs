@@ -1,4 +1,7 @@
-use std::{alloc::System, sync::Arc};
+use std::{
+    alloc::System,
+    sync::{Arc, OnceLock},
+};
 
 use clap::Parser;
 use futures::FutureExt;
@@ -6,21 +9,16 @@ use melpomene::{
     cli::{self, MelpomeneOptions},
     sim_drivers::{emb_display::SimDisplay, tcp_serial::TcpSerial},
 };
-use mnemos_alloc::heap::MnemosAlloc;
+use mnemos_alloc::heap::{HeapStats, MnemosAlloc};
 use mnemos_kernel::{
-    daemons::{
-        sermux::{hello, loopback, HelloSettings, LoopbackSettings},
-        shells::{graphical_shell_mono, GraphicalShellSettings},
-    },
-    services::{forth_spawnulator::SpawnulatorServer, serial_mux::SerialMuxServer},
-    Kernel, KernelSettings,
+    config::BootConfig, daemons::shells::GraphicalShellSettings, services::clock::ClockServer,
+    shutdown::ShutdownReason, tasks::Priority, Kernel,
 };
 use tokio::{
     task,
     time::{self, Duration},
 };
 
-use tracing::Instrument;
 const DISPLAY_WIDTH_PX: u32 = 400;
 const DISPLAY_HEIGHT_PX: u32 = 240;
 
@@ -28,17 +26,26 @@ fn main() {
     let args = cli::Args::parse();
     args.tracing.setup_tracing();
     let _span = tracing::info_span!("Melpo").entered();
-    run_melpomene(args.melpomene);
+    let reason = run_melpomene(args.melpomene);
+    let code = match reason {
+        ShutdownReason::Reboot => {
+            // A reboot was requested, not a failure, so exit successfully.
+            tracing::warn!("Rebooting is not supported by the simulator, exiting instead.");
+            0
+        }
+        reason => reason.code(),
+    };
+    std::process::exit(code as i32);
 }
 
 #[global_allocator]
 static AHEAP: MnemosAlloc<System> = MnemosAlloc::new();
 
 #[tokio::main(flavor = "current_thread")]
-async fn run_melpomene(opts: cli::MelpomeneOptions) {
+async fn run_melpomene(opts: cli::MelpomeneOptions) -> ShutdownReason {
     let local = tokio::task::LocalSet::new();
     println!("========================================");
-    local
+    let reason = local
         .run_until(async move {
             let kernel = task::spawn_local(kernel_entry(opts));
             tracing::info!("Kernel started.");
@@ -50,21 +57,39 @@ async fn run_melpomene(opts: cli::MelpomeneOptions) {
             let kj = kernel.await;
             time::sleep(Duration::from_millis(50)).await;
             tracing::info!("Kernel ended:    {:?}", kj);
+            kj
         })
         .await;
 
     println!("========================================");
 
-    tracing::error!("You've met with a terrible fate, haven't you?");
+    match reason {
+        Ok(reason) => reason,
+        Err(_) => {
+            tracing::error!("You've met with a terrible fate, haven't you?");
+            ShutdownReason::Error(101)
+        }
+    }
 }
 
 #[tracing::instrument(name = "Kernel", level = "info", skip(opts))]
-async fn kernel_entry(opts: MelpomeneOptions) {
-    let settings = KernelSettings {
-        max_drivers: 16,
+async fn kernel_entry(opts: MelpomeneOptions) -> ShutdownReason {
+    let config = boot_config(&opts);
+    if let Some(path) = opts.save_boot_config.as_ref() {
+        let mut buf = [0u8; 4096];
+        let bytes = config
+            .to_slice(&mut buf)
+            .unwrap_or_else(|e| panic!("failed to serialize boot config: {e:?}"));
+        std::fs::write(path, bytes)
+            .unwrap_or_else(|e| panic!("failed to write boot config {}: {e}", path.display()));
+        tracing::info!(path = %path.display(), "saved boot config");
+        return ShutdownReason::Halt;
+    }
+    let settings = config.kernel_settings(
         // TODO(eliza): chosen totally arbitrarily
-        timer_granularity: maitake::time::Duration::from_micros(1),
-    };
+        maitake::time::Duration::from_micros(1),
+        Some(busy_clock),
+    );
 
     let k = unsafe {
         mnemos_alloc::containers::Box::into_raw(Kernel::new(settings).unwrap())
@@ -72,11 +97,17 @@ async fn kernel_entry(opts: MelpomeneOptions) {
             .unwrap()
     };
 
+    k.set_heap_stats(|| HeapStats {
+        largest_free_block: AHEAP.largest_free_block(),
+        ..AHEAP.stats()
+    })
+    .unwrap();
+
     // Simulates the kernel main loop being woken by an IRQ.
     let irq = Arc::new(tokio::sync::Notify::new());
 
     // Initialize the UART
-    k.initialize({
+    k.initialize(Priority::System, {
         let irq = irq.clone();
         async move {
             // Set up the bidirectional, async bbqueue channel between the TCP port
@@ -93,92 +124,108 @@ async fn kernel_entry(opts: MelpomeneOptions) {
     })
     .unwrap();
 
-    // Initialize the SerialMuxServer
-    k.initialize({
-        const PORTS: usize = 16;
-        const FRAME_SIZE: usize = 512;
-        async {
-            // * Up to 16 virtual ports max
-            // * Framed messages up to 512 bytes max each
-            tracing::debug!("initializing SerialMuxServer...");
-            SerialMuxServer::register(k, PORTS, FRAME_SIZE)
-                .await
-                .unwrap();
-            tracing::info!("SerialMuxServer initialized!");
-        }
-        .instrument(tracing::info_span!(
-            "SerialMuxServer",
-            ports = PORTS,
-            frame_size = FRAME_SIZE
-        ))
+    // Spawn the clock, backed by the host's clock
+    k.initialize(Priority::System, async move {
+        ClockServer::register(k, 4, Some(host_time)).await.unwrap();
     })
     .unwrap();
 
     // Spawn the graphics driver
-    k.initialize(async move {
+    k.initialize(Priority::System, async move {
         SimDisplay::register(k, 4, DISPLAY_WIDTH_PX, DISPLAY_HEIGHT_PX)
             .await
             .unwrap();
     })
     .unwrap();
 
-    // Spawn a loopback port
-    let loopback_settings = LoopbackSettings::default();
-    k.initialize(loopback(k, loopback_settings)).unwrap();
-
-    // Spawn a hello port
-    let hello_settings = HelloSettings::default();
-    k.initialize(hello(k, hello_settings)).unwrap();
-
-    // Spawn a graphical shell
-    let mut guish = GraphicalShellSettings::with_display_size(DISPLAY_WIDTH_PX, DISPLAY_HEIGHT_PX);
-    guish.capacity = 1024;
-    k.initialize(graphical_shell_mono(k, guish)).unwrap();
+    // Spawn the services and daemons from the boot config
+    config.initialize(k).unwrap();
 
-    // Spawn the spawnulator
-    k.initialize(SpawnulatorServer::register(k, 16)).unwrap();
+    // Returns the number of timer ticks (1 tick per us) since the last call.
+    let mut last = tokio::time::Instant::now();
+    let mut elapsed = move || {
+        let now = tokio::time::Instant::now();
+        // (don't take more than 500k years)
+        let ticks = now.duration_since(last).as_micros() as u64;
+        last = now;
+        tracing::trace!("advanced timer by {ticks:?}");
+        ticks
+    };
 
     loop {
-        // Tick the scheduler
-        let t0 = tokio::time::Instant::now();
-        let tick = k.tick();
+        if let Some(reason) = k.shutdown_complete() {
+            tracing::info!(?reason, "Kernel shut down");
+            return reason;
+        }
 
-        // advance the timer (don't take more than 500k years)
-        let ticks = t0.elapsed().as_micros() as u64;
-        let turn = k.timer().force_advance_ticks(ticks);
-        tracing::trace!("advanced timer by {ticks:?}");
+        // Tick the scheduler and advance the timer. Don't use
+        // `run_until_idle` here, so other tokio tasks (simulated hardware
+        // devices) get to run in between ticks.
+        let Some(idle) = k.run_once(&mut elapsed) else {
+            tokio::task::yield_now().await;
+            continue;
+        };
 
         // If there is nothing else scheduled, and we didn't just wake something up,
-        // sleep for some amount of time
-        if turn.expired == 0 && !tick.has_remaining {
-            let wfi_start = tokio::time::Instant::now();
-            // if no timers have expired on this tick, we should sleep until the
-            // next timer expires *or* something is woken by I/O, to simulate a
-            // hardware platform waiting for an interrupt.
-            tracing::trace!("waiting for an interrupt...");
-
-            // Cap out at 100ms, just in case sim services aren't using the IRQ
-
-            // 1 ticks per us, 1000 us per ms, 100ms sleep
-            const CAP: u64 = 100 * 1000;
-            let amount = turn.ticks_to_next_deadline().unwrap_or(CAP);
-            tracing::trace!("next timer expires in {amount:?}us");
-            // wait for an "interrupt"
-            futures::select! {
-                _ = irq.notified().fuse() => {
-                    tracing::trace!("...woken by I/O interrupt");
-               },
-               _ = tokio::time::sleep(Duration::from_micros(amount.into())).fuse() => {
-                    tracing::trace!("woken by timer");
-               }
-            }
-
-            // Account for time slept
-            let elapsed = wfi_start.elapsed().as_micros() as u64;
-            let _turn = k.timer().force_advance_ticks(elapsed.into());
-        } else {
-            // let other tokio tasks (simulated hardware devices) run.
-            tokio::task::yield_now().await;
+        // we should sleep until the next timer expires *or* something is woken by
+        // I/O, to simulate a hardware platform waiting for an interrupt.
+        tracing::trace!("waiting for an interrupt...");
+
+        // Cap out at 100ms, just in case sim services aren't using the IRQ
+
+        // 1 ticks per us, 1000 us per ms, 100ms sleep
+        const CAP: u64 = 100 * 1000;
+        let amount = idle.next_deadline.unwrap_or(CAP);
+        tracing::trace!("next timer expires in {amount:?}us");
+        // wait for an "interrupt"
+        futures::select! {
+            _ = irq.notified().fuse() => {
+                tracing::trace!("...woken by I/O interrupt");
+           },
+           _ = tokio::time::sleep(Duration::from_micros(amount)).fuse() => {
+                tracing::trace!("woken by timer");
+           }
         }
+
+        // Account for time slept
+        k.advance_timer(elapsed());
     }
 }
+
+/// Loads the boot config from `--boot-config`, or returns the default config.
+fn boot_config(opts: &MelpomeneOptions) -> BootConfig {
+    let Some(path) = opts.boot_config.as_ref() else {
+        let mut guish =
+            GraphicalShellSettings::with_display_size(DISPLAY_WIDTH_PX, DISPLAY_HEIGHT_PX);
+        guish.capacity = 1024;
+        let mut config = BootConfig::default();
+        config.poll_budget = Some(maitake::time::Duration::from_millis(50));
+        config.graphical_shell = Some(guish);
+        return config;
+    };
+
+    let bytes = std::fs::read(path)
+        .unwrap_or_else(|e| panic!("failed to read boot config {}: {e}", path.display()));
+    let config = BootConfig::from_bytes(&bytes)
+        .unwrap_or_else(|e| panic!("failed to parse boot config {}: {e:?}", path.display()));
+    tracing::info!(path = %path.display(), "loaded boot config");
+    config
+}
+
+/// Returns the host's time since the UNIX epoch.
+///
+/// This is the real-time clock used by the kernel's clock service.
+fn host_time() -> maitake::time::Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Returns the number of microseconds since the first call, wrapping.
+///
+/// This is the kernel's busy clock, used to time task polls.
+fn busy_clock() -> u32 {
+    static START: OnceLock<std::time::Instant> = OnceLock::new();
+    let start = START.get_or_init(std::time::Instant::now);
+    start.elapsed().as_micros() as u32
+}
@@ -34,9 +34,14 @@ use mnemos_kernel::{
     comms::kchannel::{KChannel, KConsumer},
     registry::Message,
     services::emb_display::{EmbDisplayService, FrameChunk, FrameError, Request, Response},
+    shutdown::ShutdownReason,
+    tasks::Priority,
     Kernel,
 };
 
+/// How long other tasks get to finish when the window is closed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Implements the [`EmbDisplayService`] driver using the `embedded-graphics`
 /// simulator.
 pub struct SimDisplay;
@@ -66,7 +71,9 @@ impl SimDisplay {
             },
         };
 
-        kernel.spawn(commander.run(width, height)).await;
+        kernel
+            .spawn(Priority::System, commander.run(width, height))
+            .await;
 
         kernel
             .with_registry(|reg| reg.register_konly::<EmbDisplayService>(&cmd_prod))
@@ -127,16 +134,19 @@ impl CommanderTask {
 
         // Spawn a task that draws the framebuffer at a regular rate of 15Hz.
         self.kernel
-            .spawn({
+            .spawn(Priority::System, {
                 let mutex = mutex.clone();
                 async move {
+                    // Draw any pending changes before the kernel shuts down.
+                    let _shutdown = self.kernel.shutdown_guard();
                     let mut idle_ticks = 0;
                     loop {
                         self.kernel
                             .sleep(Duration::from_micros(1_000_000 / 15))
                             .await;
+                        let shutting_down = self.kernel.shutdown_requested().is_some();
                         let mut guard = mutex.lock().await;
-                        let mut done = false;
+                        let mut done = shutting_down;
                         if let Some(Context {
                             sdisp,
                             window,
@@ -145,7 +155,7 @@ impl CommanderTask {
                         {
                             // If nothing has been drawn, only update the frame at 1Hz to save
                             // CPU usage
-                            if *dirty || idle_ticks >= 15 {
+                            if *dirty || idle_ticks >= 15 || shutting_down {
                                 idle_ticks = 0;
                                 *dirty = false;
                                 window.update(&sdisp);
@@ -153,7 +163,9 @@ impl CommanderTask {
                                 idle_ticks += 1;
                             }
 
+                            // Closing the window shuts down the simulator.
                             if window.events().any(|e| e == SimulatorEvent::Quit) {
+                                self.kernel.shutdown(ShutdownReason::Halt, SHUTDOWN_TIMEOUT);
                                 done = true;
                             }
                         } else {
@@ -172,8 +184,9 @@ impl CommanderTask {
         //
         // Generally, don't handle errors when replying to clients, this indicates that they
         // sent us a message and "hung up" without waiting for a response.
-        loop {
-            let msg = self.cmd.dequeue_async().await.map_err(drop).unwrap();
+        //
+        // The queue is closed once the service is deregistered.
+        while let Ok(msg) = self.cmd.dequeue_async().await {
             let Message {
                 msg: mut req,
                 reply,
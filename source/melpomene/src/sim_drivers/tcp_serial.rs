@@ -5,6 +5,7 @@ use mnemos_kernel::{
     },
     registry::Message,
     services::simple_serial::{Request, Response, SimpleSerialError, SimpleSerialService},
+    tasks::Priority,
     Kernel,
 };
 use std::{net::SocketAddr, sync::Arc};
@@ -36,19 +37,21 @@ impl TcpSerial {
         tracing::info!("TCP serial port driver listening on {ip}");
 
         kernel
-            .spawn(async move {
+            .spawn(Priority::System, async move {
                 let handle = b_ring;
 
-                // Reply to the first request, giving away the serial port
-                let req = cons.dequeue_async().await.map_err(drop).unwrap();
+                // Reply to the first request, giving away the serial port.
+                // The queue is closed once the service is deregistered.
+                let Ok(req) = cons.dequeue_async().await else {
+                    return;
+                };
                 let Request::GetPort = req.msg.body;
                 let resp = req.msg.reply_with(Ok(Response::PortHandle { handle }));
 
                 req.reply.reply_konly(resp).await.map_err(drop).unwrap();
 
                 // And deny all further requests after the first
-                loop {
-                    let req = cons.dequeue_async().await.map_err(drop).unwrap();
+                while let Ok(req) = cons.dequeue_async().await {
                     let Request::GetPort = req.msg.body;
                     let resp = req
                         .msg
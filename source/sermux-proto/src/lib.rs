@@ -29,6 +29,8 @@ pub enum WellKnown {
     PseudoKeyboard = 2,
     /// A bidirectional for binary encoded tracing messages
     BinaryTracing = 3,
+    /// An output-only channel for a postcard encoded report of a kernel panic
+    PanicReport = 4,
 
     /// A bidirectional interactive forth shell (1/4)
     ForthShell0 = 10,
@@ -80,6 +80,32 @@ impl<T, STO: Storage<T>> MpScQueue<T, STO> {
         self.prod_wait.close();
     }
 
+    /// Has the channel been closed?
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of items currently in the queue.
+    ///
+    /// As producers and the consumer may be operating on the queue
+    /// concurrently, this is only a snapshot.
+    pub fn len(&self) -> usize {
+        let (_ptr, len) = self.storage.buf();
+        let enq = self.enqueue_pos.load(Ordering::Relaxed);
+        let deq = self.dequeue_pos.load(Ordering::Relaxed);
+        enq.wrapping_sub(deq).min(len)
+    }
+
+    /// Returns `true` if the queue currently contains no items.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of items the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.storage.buf().1
+    }
+
     /// Returns the item in the front of the queue, or `None` if the queue is empty
     pub fn dequeue_sync(&self) -> Option<T> {
         // Note: DON'T check the closed flag on dequeue. We want to be able
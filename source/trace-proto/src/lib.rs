@@ -78,3 +78,132 @@ impl fmt::Display for MetaId {
         write!(f, "{:x}", self.0)
     }
 }
+
+/// A report of a kernel panic, sent as a single SerMux frame on the
+/// `WellKnown::PanicReport` port.
+///
+/// Unlike [`TraceEvent`]s, a panic report is not COBS encoded on its own, as
+/// the SerMux frame already is.
+#[derive(Debug, serde::Serialize)]
+pub struct PanicReport<'a> {
+    /// The panic message, possibly truncated
+    pub message: &'a str,
+    pub location: Option<PanicLocation<'a>>,
+    /// The IDs of the spans the collector was in when the kernel panicked,
+    /// outermost first.
+    pub spans: &'a [u64],
+    /// The most recent `tracing` events, oldest first, each as the COBS
+    /// encoded [`TraceEvent`] that was sent on the tracing port.
+    pub recent_events: &'a [&'a [u8]],
+    pub heap: Option<HeapStats>,
+}
+
+/// Like [`PanicReport`], but owns its storage, so it can be deserialized.
+///
+/// Only available with the `std` feature active
+#[cfg(feature = "std")]
+#[derive(Debug, serde::Deserialize)]
+pub struct OwnedPanicReport {
+    pub message: String,
+    pub location: Option<OwnedPanicLocation>,
+    pub spans: Vec<u64>,
+    pub recent_events: Vec<Vec<u8>>,
+    pub heap: Option<HeapStats>,
+}
+
+/// Where in the source the kernel panicked.
+#[derive(Debug, serde::Serialize)]
+pub struct PanicLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Like [`PanicLocation`], but owns its storage.
+///
+/// Only available with the `std` feature active
+#[cfg(feature = "std")]
+#[derive(Debug, serde::Deserialize)]
+pub struct OwnedPanicLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Heap usage at the time of a panic.
+#[derive(Debug, Copy, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HeapStats {
+    /// The size of the heap, in bytes, or zero if unknown.
+    pub heap_size: usize,
+    /// The number of bytes currently allocated.
+    pub allocated: usize,
+    /// The most bytes that have ever been allocated at once.
+    pub peak_allocated: usize,
+    /// The total number of failed allocations.
+    pub failed_allocs: usize,
+    /// The size of the largest free block, if known.
+    pub largest_free_block: Option<usize>,
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_report_round_trip() {
+        let events: [&[u8]; 2] = [&[1, 2, 3], &[]];
+        let report = PanicReport {
+            message: "oh no",
+            location: Some(PanicLocation {
+                file: "src/lib.rs",
+                line: 42,
+                column: 7,
+            }),
+            spans: &[1, u64::MAX],
+            recent_events: &events,
+            heap: Some(HeapStats {
+                heap_size: 4096,
+                allocated: 1024,
+                peak_allocated: 2048,
+                failed_allocs: 1,
+                largest_free_block: None,
+            }),
+        };
+
+        let bytes = postcard::to_allocvec(&report).unwrap();
+        let owned: OwnedPanicReport = postcard::from_bytes(&bytes).unwrap();
+
+        assert_eq!(owned.message, "oh no");
+        let location = owned.location.unwrap();
+        assert_eq!(location.file, "src/lib.rs");
+        assert_eq!((location.line, location.column), (42, 7));
+        assert_eq!(owned.spans, [1, u64::MAX]);
+        assert_eq!(owned.recent_events, [vec![1, 2, 3], vec![]]);
+        let heap = owned.heap.unwrap();
+        assert_eq!(heap.heap_size, 4096);
+        assert_eq!(heap.allocated, 1024);
+        assert_eq!(heap.peak_allocated, 2048);
+        assert_eq!(heap.failed_allocs, 1);
+        assert_eq!(heap.largest_free_block, None);
+    }
+
+    #[test]
+    fn panic_report_without_location_or_heap() {
+        let report = PanicReport {
+            message: "",
+            location: None,
+            spans: &[],
+            recent_events: &[],
+            heap: None,
+        };
+
+        let bytes = postcard::to_allocvec(&report).unwrap();
+        let owned: OwnedPanicReport = postcard::from_bytes(&bytes).unwrap();
+
+        assert_eq!(owned.message, "");
+        assert!(owned.location.is_none());
+        assert!(owned.spans.is_empty());
+        assert!(owned.recent_events.is_empty());
+        assert!(owned.heap.is_none());
+    }
+}
@@ -283,13 +283,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         manager.workers.insert(i, handle);
     }
 
-    // spawn tracing listener
+    // spawn tracing listener, which also decodes panic reports, as they refer
+    // to spans and events from the trace
     let trace_port = WellKnown::BinaryTracing as u16;
+    let panic_port = WellKnown::PanicReport as u16;
+    let (panic_send, panic_recv) = channel::<Vec<u8>>();
     let trace_handle = {
         let (inp_send, inp_recv) = channel();
         let (out_send, out_recv) = channel::<Vec<u8>>();
         let thread_hdl = spawn(move || {
-            trace::TraceWorker::new(trace_level, inp_send, out_recv, tag.port(trace_port)).run()
+            trace::TraceWorker::new(
+                trace_level,
+                inp_send,
+                out_recv,
+                panic_recv,
+                tag.port(trace_port),
+            )
+            .run()
         });
         WorkerHandle {
             out: out_send,
@@ -341,6 +351,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             // even if the actual decoding failed
             let mut success = false;
             match OwnedPortChunk::decode(&carry) {
+                Ok(OwnedPortChunk { port, chunk }) if port == panic_port => {
+                    success = true;
+                    tag.port(port)
+                        .if_verbose(format_args!("{dmux} {}B -> :{port}", chunk.len()));
+                    panic_send.send(chunk).ok();
+                }
                 Ok(OwnedPortChunk { port, chunk }) => {
                     success = true;
                     if let Some(hdl) = manager.workers.get_mut(&port) {
@@ -1,11 +1,11 @@
-use mnemos_trace_proto::{HostRequest, MetaId, TraceEvent};
+use mnemos_trace_proto::{HostRequest, MetaId, OwnedPanicReport, TraceEvent};
 use postcard::accumulator::{CobsAccumulator, FeedResult};
 use std::{
     collections::HashMap,
     fmt::{self, Write},
     num::NonZeroU64,
     sync::mpsc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tracing_02::level_filters::LevelFilter;
 use tracing_serde_structured::{
@@ -19,6 +19,7 @@ use owo_colors::{OwoColorize, Stream};
 pub(crate) struct TraceWorker {
     tx: mpsc::Sender<Vec<u8>>,
     rx: mpsc::Receiver<Vec<u8>>,
+    panics: mpsc::Receiver<Vec<u8>>,
     tag: LogTag,
     spans: HashMap<NonZeroU64, Span>,
     metas: HashMap<MetaId, SerializeMetadata<'static>>,
@@ -33,6 +34,7 @@ impl TraceWorker {
         max_level: LevelFilter,
         tx: mpsc::Sender<Vec<u8>>,
         rx: mpsc::Receiver<Vec<u8>>,
+        panics: mpsc::Receiver<Vec<u8>>,
         tag: LogTag,
     ) -> Self {
         let ser_max_level = match max_level {
@@ -46,6 +48,7 @@ impl TraceWorker {
         Self {
             tx,
             rx,
+            panics,
             tag,
             spans: HashMap::new(),
             metas: HashMap::new(),
@@ -70,7 +73,16 @@ impl TraceWorker {
     pub(crate) fn run(mut self) {
         let mut cobs_buf: CobsAccumulator<1024> = CobsAccumulator::new();
 
-        while let Ok(chunk) = self.rx.recv() {
+        loop {
+            while let Ok(report) = self.panics.try_recv() {
+                self.panic(&report);
+            }
+
+            let chunk = match self.rx.recv_timeout(Duration::from_millis(10)) {
+                Ok(chunk) => chunk,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
             let mut window = &chunk[..];
 
             'cobs: while !window.is_empty() {
@@ -89,6 +101,72 @@ impl TraceWorker {
         println!("trace channel over");
     }
 
+    fn panic(&mut self, report: &[u8]) {
+        let panc = "PANC".if_supports_color(Stream::Stdout, |x| x.bright_red());
+        let report: OwnedPanicReport = match postcard::from_bytes(report) {
+            Ok(report) => report,
+            Err(e) => {
+                println!("{} {panc} undecodable panic report: {e}", self.tag);
+                return;
+            }
+        };
+
+        match report.location {
+            Some(loc) => println!(
+                "{} {panc} kernel panicked at {}:{}:{}",
+                self.tag, loc.file, loc.line, loc.column
+            ),
+            None => println!("{} {panc} kernel panicked", self.tag),
+        }
+        println!(
+            "{} {panc}   {}",
+            self.tag,
+            report
+                .message
+                .if_supports_color(Stream::Stdout, |x| x.bold())
+        );
+
+        for id in report.spans.iter() {
+            let span = NonZeroU64::new(*id).and_then(|id| self.spans.get(&id));
+            match span {
+                Some(span) => println!(
+                    "{} {panc}   in {}{} ({id:04})",
+                    self.tag,
+                    format_args!("{}::", span.target)
+                        .if_supports_color(Stream::Stdout, |target| target.dimmed()),
+                    span.repr,
+                ),
+                None => println!("{} {panc}   in <unknown span> ({id:04})", self.tag),
+            }
+        }
+
+        if let Some(heap) = report.heap {
+            println!(
+                "{} {panc}   heap: {}B allocated of {}B (peak {}B), {} failed allocs",
+                self.tag, heap.allocated, heap.heap_size, heap.peak_allocated, heap.failed_allocs
+            );
+            if let Some(largest) = heap.largest_free_block {
+                println!("{} {panc}   largest free block: {largest}B", self.tag);
+            }
+        }
+
+        println!(
+            "{} {panc}   last {} events:",
+            self.tag,
+            report.recent_events.len()
+        );
+        // The span stack we're tracking has nothing to do with when these
+        // events were recorded, so print them without span context.
+        let stack = std::mem::take(&mut self.stack);
+        for mut ev in report.recent_events {
+            match postcard::from_bytes_cobs::<TraceEvent<'_>>(&mut ev) {
+                Ok(ev) => self.event(ev),
+                Err(e) => println!("{} {panc}   undecodable event: {e}", self.tag),
+            }
+        }
+        self.stack = stack;
+    }
+
     fn event(&mut self, ev: TraceEvent<'_>) {
         match ev {
             TraceEvent::Heartbeat(level) => {